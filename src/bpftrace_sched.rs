@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chrome;
+use crate::input;
+use crate::perf_sched::{cpu_track, TimestampOffset};
+use crate::process_info::ProcessInfoTable;
+
+// field name -> value of a JSONL or CSV record
+type Record = HashMap<String, String>;
+
+fn parse_jsonl_record(line: &str) -> Result<Record> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)?;
+    Ok(object
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (key, value)
+        })
+        .collect())
+}
+
+fn parse_csv_record(header: &[String], line: &str) -> Result<Record> {
+    let values: Vec<&str> = line.split(',').collect();
+    if values.len() != header.len() {
+        return Err(anyhow!(
+            "{} columns are required by header, but found {}",
+            header.len(),
+            values.len()
+        ));
+    }
+    Ok(header
+        .iter()
+        .zip(values)
+        .map(|(key, value)| (key.clone(), value.trim().trim_matches('"').to_string()))
+        .collect())
+}
+
+// NOTE: comm of bpftrace output is padded with spaces e.g. 'bpftrace       '
+fn field<'a>(record: &'a Record, key: &str) -> Option<&'a str> {
+    record
+        .get(key)
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+fn number_field<T: std::str::FromStr>(record: &Record, key: &str) -> Result<T> {
+    let value = field(record, key).ok_or_else(|| anyhow!("'{}' is not found", key))?;
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow!("'{}' is not a number '{}'", key, value))
+}
+
+struct Running {
+    tid: u32,
+    // NOTE: pid of next task is unknown until it is switched out
+    pid: Option<u32>,
+    comm: String,
+    timestamp: Duration,
+}
+
+// sched_switch records of bpftrace (unit of ts and dur is nsec)
+// switch records: ts,cpu,[pid,]prev_pid,prev_comm,next_pid,next_comm (comm builtin is used as prev_comm)
+// slice records: ts,dur,cpu,pid,[tid,]comm
+// NOTE: prev_pid and next_pid of sched_switch are tids, and pid builtin is pid of prev task
+pub struct BpftraceSchedParser<'a> {
+    process_info_table: &'a ProcessInfoTable,
+    timestamp_offset: TimestampOffset,
+    // cpu -> running task
+    running: BTreeMap<u32, Running>,
+    // comm of tid which is omitted in later records
+    comms: HashMap<u32, String>,
+    first_timestamp: Option<Duration>,
+    last_timestamp: Duration,
+    truncated_count: usize,
+}
+
+impl<'a> BpftraceSchedParser<'a> {
+    pub fn new(
+        process_info_table: &'a ProcessInfoTable,
+        timestamp_offset: TimestampOffset,
+    ) -> BpftraceSchedParser<'a> {
+        BpftraceSchedParser {
+            process_info_table,
+            timestamp_offset,
+            running: BTreeMap::new(),
+            comms: HashMap::new(),
+            first_timestamp: None,
+            last_timestamp: Duration::from_micros(0),
+            truncated_count: 0,
+        }
+    }
+
+    // JSONL or CSV (which has header) is detected by the first line
+    pub fn parse_file(
+        &mut self,
+        filepath: &Path,
+        mut write_event: impl FnMut(&chrome::Event) -> Result<()>,
+    ) -> Result<()> {
+        let reader = input::open(filepath)?;
+        let mut csv_header: Option<Vec<String>> = None;
+        let mut jsonl = None;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read file {:?}", filepath))?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let jsonl = *jsonl.get_or_insert_with(|| line.starts_with('{'));
+            let record = if jsonl {
+                parse_jsonl_record(line)
+            } else {
+                match &csv_header {
+                    Some(header) => parse_csv_record(header, line),
+                    None => {
+                        csv_header =
+                            Some(line.split(',').map(|key| key.trim().to_string()).collect());
+                        continue;
+                    }
+                }
+            };
+            let event = record.and_then(|record| self.parse_record(&record));
+            match event {
+                Ok(Some(event)) => write_event(&event)?,
+                Ok(None) => {}
+                Err(e) => log::warn!("{:?}:{}: {}", filepath, i + 1, e),
+            }
+        }
+        Ok(())
+    }
+
+    fn slice_event(
+        &self,
+        cpu: u32,
+        tid: u32,
+        pid: Option<u32>,
+        comm: &str,
+        timestamp: Duration,
+        duration: Duration,
+    ) -> chrome::Event {
+        let track = cpu_track(cpu);
+        let mut event = chrome::Event {
+            name: format!("{}[{}]", comm, tid),
+            category: String::from("sched"),
+            event_type: chrome::EventType::Complete,
+            timestamp,
+            duration,
+            process_id: track.0,
+            thread_id: track.1,
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        };
+        event.set_arg("tid", tid);
+        // NOTE: tid is used as pid if pid is found neither in records nor in process info
+        let pid = pid
+            .or_else(|| self.process_info_table.thread_pid(tid))
+            .unwrap_or(tid);
+        event.set_arg("pid", pid);
+        event.set_arg("comm", comm);
+        if let Some(cmdline) = self.process_info_table.process_cmdline(pid) {
+            event.set_arg("cmdline", cmdline);
+        }
+        event
+    }
+
+    // comm of the record, comm of previous records, comm of --pid-comm-cmdline or 'Unknown'
+    fn comm(&mut self, tid: u32, comm: Option<&str>) -> String {
+        match comm {
+            Some(comm) => {
+                self.comms.insert(tid, comm.to_string());
+                comm.to_string()
+            }
+            None => self
+                .comms
+                .get(&tid)
+                .map(|comm| comm.as_str())
+                .or_else(|| self.process_info_table.thread_name(tid))
+                .or_else(|| self.process_info_table.process_name(tid))
+                .unwrap_or("Unknown")
+                .to_string(),
+        }
+    }
+
+    pub fn parse_record(&mut self, record: &Record) -> Result<Option<chrome::Event>> {
+        let timestamp = Duration::from_nanos(number_field(record, "ts")?);
+        let timestamp = self.timestamp_offset.apply(timestamp)?;
+        let cpu: u32 = number_field(record, "cpu")?;
+        self.first_timestamp.get_or_insert(timestamp);
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+
+        let pid: Option<u32> = match field(record, "pid") {
+            Some(_) => Some(number_field(record, "pid")?),
+            None => None,
+        };
+        if record.contains_key("dur") {
+            let duration = Duration::from_nanos(number_field(record, "dur")?);
+            let tid: u32 = match field(record, "tid") {
+                Some(_) => number_field(record, "tid")?,
+                None => pid.ok_or_else(|| anyhow!("'pid' is not found"))?,
+            };
+            let comm = self.comm(tid, field(record, "comm"));
+            return Ok(Some(
+                self.slice_event(cpu, tid, pid, &comm, timestamp, duration),
+            ));
+        }
+
+        let prev_tid: u32 = number_field(record, "prev_pid")?;
+        let next_tid: u32 = number_field(record, "next_pid")?;
+        let prev_comm = self.comm(
+            prev_tid,
+            field(record, "prev_comm").or_else(|| field(record, "comm")),
+        );
+        let next_comm = self.comm(next_tid, field(record, "next_comm"));
+        let next = Running {
+            tid: next_tid,
+            pid: None,
+            comm: next_comm,
+            timestamp,
+        };
+        let event = match self.running.insert(cpu, next) {
+            Some(mut running) if running.tid == prev_tid => {
+                running.pid = pid;
+                self.running_event(cpu, &running, timestamp, None)
+            }
+            Some(running) => {
+                // NOTE: switch records may be lost, so the running task is switched out at this time
+                log::warn!(
+                    "CPU {}: switch out {}[{}] at {:?}, but {}[{}] is running",
+                    cpu,
+                    prev_comm,
+                    prev_tid,
+                    timestamp,
+                    running.comm,
+                    running.tid
+                );
+                self.running_event(cpu, &running, timestamp, Some("lost"))
+            }
+            // the beginning of the trace
+            None => {
+                let running = Running {
+                    tid: prev_tid,
+                    pid,
+                    comm: prev_comm,
+                    timestamp: self.first_timestamp.unwrap_or(timestamp),
+                };
+                self.running_event(cpu, &running, timestamp, Some("begin"))
+            }
+        };
+        Ok(event)
+    }
+
+    // NOTE: idle task (pid 0) is shown as a gap of CPU track
+    fn running_event(
+        &mut self,
+        cpu: u32,
+        running: &Running,
+        timestamp: Duration,
+        truncated: Option<&str>,
+    ) -> Option<chrome::Event> {
+        if running.tid == 0 {
+            return None;
+        }
+        let duration = timestamp.saturating_sub(running.timestamp);
+        // NOTE: e.g. the first record of the trace is truncated to zero length
+        if truncated.is_some() && duration.is_zero() {
+            return None;
+        }
+        let mut event = self.slice_event(
+            cpu,
+            running.tid,
+            running.pid,
+            &running.comm,
+            running.timestamp,
+            duration,
+        );
+        if let Some(truncated) = truncated {
+            self.truncated_count += 1;
+            event.set_arg("truncated", truncated);
+        }
+        Some(event)
+    }
+
+    // tasks which are running at the end of the trace
+    pub fn finish(mut self) -> Vec<chrome::Event> {
+        let running = std::mem::take(&mut self.running);
+        let last_timestamp = self.last_timestamp;
+        let events: Vec<chrome::Event> = running
+            .iter()
+            .filter_map(|(&cpu, running)| {
+                self.running_event(cpu, running, last_timestamp, Some("end"))
+            })
+            .collect();
+        log::info!(
+            "{} slices are truncated at the beginning or the end of the trace or by lost records",
+            self.truncated_count
+        );
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Vec<chrome::Event> {
+        let process_info_table = ProcessInfoTable::default();
+        let mut parser = BpftraceSchedParser::new(&process_info_table, TimestampOffset::default());
+        let mut events = Vec::new();
+        for line in lines {
+            let record = parse_jsonl_record(line).unwrap();
+            events.extend(parser.parse_record(&record).unwrap());
+        }
+        events.extend(parser.finish());
+        events
+    }
+
+    #[test]
+    fn switch_tid_and_pid() {
+        let events = parse(&[
+            r#"{"ts":1000,"cpu":0,"pid":0,"prev_pid":0,"prev_comm":"swapper/0","next_pid":11,"next_comm":"worker"}"#,
+            r#"{"ts":3000,"cpu":0,"pid":10,"prev_pid":11,"prev_comm":"worker","next_pid":0,"next_comm":"swapper/0"}"#,
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "worker[11]");
+        assert_eq!(events[0].arg("tid"), Some(&11.into()));
+        assert_eq!(events[0].arg("pid"), Some(&10.into()));
+        assert_eq!(events[0].duration, Duration::from_nanos(2000));
+    }
+
+    #[test]
+    fn zero_length_truncated() {
+        // NOTE: the first record truncates the slice of prev task to zero length
+        let events = parse(&[
+            r#"{"ts":1000,"cpu":0,"pid":5,"prev_pid":5,"prev_comm":"bpftrace","next_pid":0,"next_comm":"swapper/0"}"#,
+            r#"{"ts":2000,"cpu":0,"pid":0,"prev_pid":0,"prev_comm":"swapper/0","next_pid":5,"next_comm":"bpftrace"}"#,
+            r#"{"ts":3000,"cpu":0,"pid":5,"prev_pid":5,"prev_comm":"bpftrace","next_pid":0,"next_comm":"swapper/0"}"#,
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].arg("truncated"), None);
+    }
+
+    #[test]
+    fn lost_switch_out() {
+        let events = parse(&[
+            r#"{"ts":1000,"cpu":0,"pid":0,"prev_pid":0,"prev_comm":"swapper/0","next_pid":11,"next_comm":"a"}"#,
+            r#"{"ts":3000,"cpu":0,"pid":12,"prev_pid":12,"prev_comm":"b","next_pid":0,"next_comm":"swapper/0"}"#,
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "a[11]");
+        assert_eq!(events[0].arg("truncated"), Some(&"lost".into()));
+        assert_eq!(events[0].duration, Duration::from_nanos(2000));
+    }
+}
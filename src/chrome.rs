@@ -1,30 +1,108 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::io::Write;
 use std::time::Duration;
 
-use serde::Serializer;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer, Serializer};
 
-fn as_micros<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+fn as_micros<S: Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
     let v = (d.as_secs() * 1_000_000) + (d.subsec_nanos() as u64 / 1_000);
     s.serialize_u64(v)
 }
 
-fn as_float_micros<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
-    if d.subsec_nanos() % 1000 == 0 {
+fn as_float_micros<S: Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+    if d.subsec_nanos().is_multiple_of(1000) {
         return as_micros(d, s);
     }
     let v = (d.as_secs() * 1_000_000) as f64 + (d.subsec_nanos() as f64 / 1_000.0);
     s.serialize_f64(v)
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, Serialize, Debug)]
+// integer or float microseconds
+fn from_float_micros<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Duration, D::Error> {
+    let v = serde_json::Value::deserialize(d)?;
+    let micros = match &v {
+        serde_json::Value::Number(n) => n.as_f64(),
+        // NOTE: some tools output timestamp as string
+        serde_json::Value::String(s) => s.parse::<f64>().ok(),
+        _ => None,
+    };
+    match micros {
+        Some(micros) if micros >= 0.0 => Ok(Duration::from_nanos((micros * 1000.0).round() as u64)),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid microseconds '{}'",
+            v
+        ))),
+    }
+}
+
+// trace event as read from trace file of other tools
+// NOTE: negative 'dur' is read as 0 and kept here to be reported with index of the event
+struct RawEvent {
+    event: Event,
+    negative_duration: Option<Duration>,
+}
+
+impl<'de> Deserialize<'de> for RawEvent {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<RawEvent, D::Error> {
+        let mut value = serde_json::Value::deserialize(d)?;
+        let negative_micros = value
+            .get("dur")
+            .and_then(|dur| match dur {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::String(s) => s.parse::<f64>().ok(),
+                _ => None,
+            })
+            .filter(|micros| *micros < 0.0);
+        if negative_micros.is_some() {
+            value["dur"] = 0.into();
+        }
+        Ok(RawEvent {
+            event: serde_json::from_value(value).map_err(serde::de::Error::custom)?,
+            negative_duration: negative_micros
+                .map(|micros| Duration::from_nanos((-micros * 1000.0).round() as u64)),
+        })
+    }
+}
+
+// string or numeric id
+fn from_string_or_number<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(d)? {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(s)) => Ok(Some(s)),
+        Some(serde_json::Value::Number(n)) => Ok(Some(n.to_string())),
+        Some(v) => Err(serde::de::Error::custom(format!("invalid id '{}'", v))),
+    }
+}
+
+// NOTE: otherData may have non string values
+fn from_other_data<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    let other_data = BTreeMap::<String, serde_json::Value>::deserialize(d)?;
+    Ok(other_data
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => (key, s),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug, Default)]
 pub enum EventType {
     #[serde(rename = "B")]
+    #[default]
     DurationBegin,
     #[serde(rename = "E")]
     DurationEnd,
     #[serde(rename = "X")]
     Complete,
-    #[serde(rename = "i")]
+    #[serde(rename = "i", alias = "I")]
     Instant,
     #[serde(rename = "C")]
     Counter,
@@ -60,31 +138,78 @@ pub enum EventType {
     ClockSync,
     #[serde(rename = "(")]
     ContextStart,
-    #[serde(rename = "(")]
+    #[serde(rename = ")")]
     ContextEnd,
 }
-impl Default for EventType {
-    fn default() -> Self {
-        EventType::DurationBegin
-    }
-}
 
-#[derive(Clone, Copy, Eq, PartialEq, Serialize, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug, Default)]
 pub enum InstantScope {
     #[serde(rename = "g")]
+    #[default]
     Global,
     #[serde(rename = "p")]
     Process,
     #[serde(rename = "t")]
     Thread,
 }
-impl Default for InstantScope {
+
+// pid or tid, string is used for virtual tracks e.g. "CPU 3"
+// NOTE: i64 accepts ids of other tools e.g. '"pid": -1'
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum TrackId {
+    Number(i64),
+    Name(String),
+}
+
+impl TrackId {
+    // None for names and ids out of range of pid/tid
+    pub fn as_number(&self) -> Option<u32> {
+        match self {
+            TrackId::Number(id) => u32::try_from(*id).ok(),
+            TrackId::Name(_) => None,
+        }
+    }
+}
+
+impl Default for TrackId {
     fn default() -> Self {
-        InstantScope::Global
+        TrackId::Number(0)
+    }
+}
+
+impl From<u32> for TrackId {
+    fn from(id: u32) -> Self {
+        TrackId::Number(id as i64)
+    }
+}
+
+impl From<&str> for TrackId {
+    fn from(name: &str) -> Self {
+        TrackId::Name(name.to_string())
+    }
+}
+
+impl From<String> for TrackId {
+    fn from(name: String) -> Self {
+        TrackId::Name(name)
+    }
+}
+
+impl std::fmt::Display for TrackId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TrackId::Number(id) => write!(f, "{}", id),
+            TrackId::Name(name) => write!(f, "{}", name),
+        }
     }
 }
 
-#[derive(Serialize, Clone, Default, Debug)]
+// any JSON values e.g. numbers, bools, nested objects and arrays
+pub type Args = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
 pub struct Event {
     #[serde(rename = "name")]
     pub name: String,
@@ -92,24 +217,638 @@ pub struct Event {
     pub category: String,
     #[serde(rename = "ph")]
     pub event_type: EventType,
-    #[serde(rename = "ts", serialize_with = "as_float_micros")]
+    #[serde(
+        rename = "ts",
+        serialize_with = "as_float_micros",
+        deserialize_with = "from_float_micros"
+    )]
     pub timestamp: Duration,
-    #[serde(rename = "dur", serialize_with = "as_float_micros")]
+    #[serde(
+        rename = "dur",
+        serialize_with = "as_float_micros",
+        deserialize_with = "from_float_micros"
+    )]
     pub duration: Duration,
     #[serde(rename = "pid")]
-    pub process_id: u32,
+    pub process_id: TrackId,
     #[serde(rename = "tid")]
-    pub thread_id: u32,
+    pub thread_id: TrackId,
     #[serde(rename = "s")]
     #[serde(skip_serializing_if = "std::option::Option::is_none")]
     pub instant_scope: Option<InstantScope>,
     #[serde(rename = "scope")]
     #[serde(skip_serializing_if = "std::option::Option::is_none")]
     pub scope: Option<String>,
-    #[serde(rename = "id")]
+    #[serde(rename = "id", deserialize_with = "from_string_or_number")]
     #[serde(skip_serializing_if = "std::option::Option::is_none")]
     pub id: Option<String>,
     #[serde(rename = "args")]
     #[serde(skip_serializing_if = "std::option::Option::is_none")]
-    pub args: Option<HashMap<String, String>>,
+    pub args: Option<Args>,
+    // id of stackFrames
+    #[serde(rename = "sf", deserialize_with = "from_string_or_number")]
+    #[serde(skip_serializing_if = "std::option::Option::is_none")]
+    pub stack_frame: Option<String>,
+}
+
+impl Event {
+    pub fn set_arg(&mut self, key: &str, value: impl Into<serde_json::Value>) -> &mut Event {
+        self.args
+            .get_or_insert_with(Args::new)
+            .insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn with_arg(mut self, key: &str, value: impl Into<serde_json::Value>) -> Event {
+        self.set_arg(key, value);
+        self
+    }
+
+    pub fn arg(&self, key: &str) -> Option<&serde_json::Value> {
+        self.args.as_ref().and_then(|args| args.get(key))
+    }
+}
+
+// sort key of begin and end of slices on a thread to keep nesting at the same timestamp
+// (timestamp, end before begin, outer slice first, input order, begin before end of zero length slice)
+pub type SliceKey = (Duration, u8, Reverse<Duration>, usize, u8);
+
+// NOTE: end of 'B' is unknown (None), so it is treated as the outermost slice
+pub fn slice_begin_key(begin: Duration, end: Option<Duration>, order: usize) -> SliceKey {
+    (begin, 1, Reverse(end.unwrap_or(Duration::MAX)), order, 0)
+}
+
+// NOTE: begin of 'E' is unknown (None), and end of zero length slice must follow its begin
+pub fn slice_end_key(begin: Option<Duration>, end: Duration, order: usize) -> SliceKey {
+    if begin == Some(end) {
+        (end, 1, Reverse(end), order, 1)
+    } else {
+        (end, 0, Reverse(Duration::ZERO), order, 0)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub enum DisplayTimeUnit {
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "ns")]
+    Nanoseconds,
+}
+
+impl std::str::FromStr for DisplayTimeUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DisplayTimeUnit> {
+        match s {
+            "ms" => Ok(DisplayTimeUnit::Milliseconds),
+            "ns" => Ok(DisplayTimeUnit::Nanoseconds),
+            s => Err(anyhow::anyhow!(
+                "invalid display time unit '{}' choose from [ms, ns]",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct StackFrame {
+    #[serde(rename = "category")]
+    pub category: String,
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "parent", deserialize_with = "from_string_or_number")]
+    #[serde(skip_serializing_if = "std::option::Option::is_none")]
+    pub parent: Option<String>,
+}
+
+// JSON Object Format of trace event format
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Trace {
+    #[serde(rename = "traceEvents")]
+    pub trace_events: Vec<Event>,
+    #[serde(rename = "displayTimeUnit")]
+    #[serde(skip_serializing_if = "std::option::Option::is_none")]
+    pub display_time_unit: Option<DisplayTimeUnit>,
+    #[serde(rename = "otherData", deserialize_with = "from_other_data")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub other_data: BTreeMap<String, String>,
+    // NOTE: Linux ftrace text format
+    #[serde(rename = "systemTraceEvents")]
+    #[serde(skip_serializing_if = "std::option::Option::is_none")]
+    pub system_trace_events: Option<String>,
+    #[serde(rename = "stackFrames")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub stack_frames: BTreeMap<String, StackFrame>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RawTrace {
+    #[serde(rename = "traceEvents")]
+    trace_events: Vec<RawEvent>,
+    #[serde(flatten)]
+    trace: Trace,
+}
+
+impl RawTrace {
+    fn into_trace(self) -> Trace {
+        Trace {
+            trace_events: self.trace_events.into_iter().map(|raw| raw.event).collect(),
+            ..self.trace
+        }
+    }
+}
+
+// common interface of output formats
+pub trait EventWriter<W> {
+    fn write_event(&mut self, event: &Event) -> Result<()>;
+    fn event_count(&self) -> usize;
+    // write the end of trace and return inner writer
+    fn finish(self: Box<Self>) -> Result<W>;
+}
+
+// serialize events one by one into JSON array format (or traceEvents of JSON object format)
+// without keeping all events in memory
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    pretty: bool,
+    object_format: bool,
+    event_count: usize,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut writer: W, pretty: bool) -> Result<TraceWriter<W>> {
+        writer.write_all(b"[")?;
+        Ok(TraceWriter {
+            writer,
+            pretty,
+            object_format: false,
+            event_count: 0,
+        })
+    }
+
+    // write fields of trace except traceEvents first, and traceEvents are written by write_event()
+    pub fn with_trace(mut writer: W, pretty: bool, trace: &Trace) -> Result<TraceWriter<W>> {
+        let mut fields = match serde_json::to_value(trace)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+        fields.remove("traceEvents");
+        writer.write_all(b"{")?;
+        for (key, value) in fields.iter() {
+            if pretty {
+                let value_json = serde_json::to_string_pretty(value)?;
+                write!(
+                    writer,
+                    "\n  {}: {},",
+                    serde_json::to_string(key)?,
+                    value_json.replace('\n', "\n  ")
+                )?;
+            } else {
+                write!(writer, "{}:{},", serde_json::to_string(key)?, value)?;
+            }
+        }
+        if pretty {
+            writer.write_all(b"\n  \"traceEvents\": [")?;
+        } else {
+            writer.write_all(b"\"traceEvents\":[")?;
+        }
+        let mut trace_writer = TraceWriter {
+            writer,
+            pretty,
+            object_format: true,
+            event_count: 0,
+        };
+        for event in &trace.trace_events {
+            trace_writer.write_event(event)?;
+        }
+        Ok(trace_writer)
+    }
+
+    pub fn write_event(&mut self, event: &Event) -> Result<()> {
+        if self.event_count > 0 {
+            self.writer.write_all(b",")?;
+        }
+        if self.pretty {
+            // NOTE: same indentation as serde_json::to_string_pretty() of whole array
+            let event_json = serde_json::to_string_pretty(event)?;
+            let indent = if self.object_format { "\n    " } else { "\n  " };
+            self.writer.write_all(indent.as_bytes())?;
+            self.writer
+                .write_all(event_json.replace('\n', indent).as_bytes())?;
+        } else {
+            serde_json::to_writer(&mut self.writer, event)?;
+        }
+        self.event_count += 1;
+        Ok(())
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.event_count
+    }
+
+    // write the end of array (and object) and return inner writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.pretty && self.event_count > 0 {
+            if self.object_format {
+                self.writer.write_all(b"\n  ")?;
+            } else {
+                self.writer.write_all(b"\n")?;
+            }
+        }
+        self.writer.write_all(b"]")?;
+        if self.object_format {
+            if self.pretty {
+                self.writer.write_all(b"\n")?;
+            }
+            self.writer.write_all(b"}")?;
+        }
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> EventWriter<W> for TraceWriter<W> {
+    fn write_event(&mut self, event: &Event) -> Result<()> {
+        TraceWriter::write_event(self, event)
+    }
+
+    fn event_count(&self) -> usize {
+        TraceWriter::event_count(self)
+    }
+
+    fn finish(self: Box<Self>) -> Result<W> {
+        TraceWriter::finish(*self)
+    }
+}
+
+fn read_to_string(filepath: &std::path::Path) -> Result<String> {
+    let mut reader = crate::input::open(filepath)?;
+    let mut buffer = String::new();
+    reader
+        .read_to_string(&mut buffer)
+        .with_context(|| format!("read_trace(): Failed to read file {:?}", filepath))?;
+    Ok(buffer)
+}
+
+// read JSON array format or JSON object format trace file (gzip and zstd compressed file are supported)
+pub fn read_trace(filepath: &std::path::Path) -> Result<Trace> {
+    parse_trace(&read_to_string(filepath)?)
+        .with_context(|| format!("read_trace(): Failed to parse file {:?}", filepath))
+}
+
+pub fn parse_trace(input: &str) -> Result<Trace> {
+    Ok(parse_raw_trace(input)?.into_trace())
+}
+
+// read trace file and validate its events (including negative 'dur' which is read as 0)
+pub fn read_and_validate_trace(
+    filepath: &std::path::Path,
+) -> Result<(Trace, Vec<ValidationError>)> {
+    parse_and_validate_trace(&read_to_string(filepath)?)
+        .with_context(|| format!("read_trace(): Failed to parse file {:?}", filepath))
+}
+
+fn parse_and_validate_trace(input: &str) -> Result<(Trace, Vec<ValidationError>)> {
+    let raw_trace = parse_raw_trace(input)?;
+    let mut errors: Vec<ValidationError> = raw_trace
+        .trace_events
+        .iter()
+        .enumerate()
+        .filter_map(|(index, raw)| {
+            let negative_duration = raw.negative_duration?;
+            Some(ValidationError {
+                index,
+                message: format!(
+                    "{} '{}' has negative dur -{}",
+                    serde_json::to_string(&raw.event.event_type)
+                        .unwrap_or_default()
+                        .trim_matches('"'),
+                    raw.event.name,
+                    negative_duration.as_secs_f64() * 1_000_000.0
+                ),
+            })
+        })
+        .collect();
+    let trace = raw_trace.into_trace();
+    errors.extend(validate(&trace.trace_events));
+    errors.sort_by_key(|error| error.index);
+    Ok((trace, errors))
+}
+
+fn parse_raw_trace(input: &str) -> Result<RawTrace> {
+    let input = input.trim_start();
+    if input.starts_with('[') {
+        // NOTE: the closing bracket of JSON array format is optional
+        let trace_events = match serde_json::from_str::<Vec<RawEvent>>(input) {
+            Ok(trace_events) => trace_events,
+            Err(e) if e.is_eof() => {
+                let input = input.trim_end().trim_end_matches(',');
+                serde_json::from_str::<Vec<RawEvent>>(&format!("{}]", input))?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(RawTrace {
+            trace_events,
+            ..Default::default()
+        })
+    } else if input.starts_with('{') {
+        Ok(serde_json::from_str::<RawTrace>(input)?)
+    } else {
+        Err(anyhow!(
+            "trace file must be JSON array format or JSON object format"
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    // index of traceEvents
+    pub index: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "traceEvents[{}]: {}", self.index, self.message)
+    }
+}
+
+// check unbalanced B/E per thread, async b/e without matching ids, flow steps without start
+// and negative durations of B/E and b/e (see read_and_validate_trace() for negative 'dur' of X)
+pub fn validate(events: &[Event]) -> Vec<ValidationError> {
+    let mut errors: Vec<ValidationError> = Vec::new();
+    // NOTE: events are validated in order of traceEvents (not sorted by timestamp)
+    let mut duration_stacks: HashMap<(&TrackId, &TrackId), Vec<usize>> = HashMap::new();
+    let mut async_stacks: HashMap<(&str, Option<&str>, &str), Vec<usize>> = HashMap::new();
+    let mut flows: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut error = |index: usize, message: String| errors.push(ValidationError { index, message });
+    for (i, event) in events.iter().enumerate() {
+        match event.event_type {
+            EventType::DurationBegin => {
+                duration_stacks
+                    .entry((&event.process_id, &event.thread_id))
+                    .or_default()
+                    .push(i);
+            }
+            EventType::DurationEnd => {
+                let stack = duration_stacks
+                    .entry((&event.process_id, &event.thread_id))
+                    .or_default();
+                match stack.pop() {
+                    Some(begin) => {
+                        let begin_event = &events[begin];
+                        if begin_event.timestamp > event.timestamp {
+                            error(i, format!("E '{}' has negative duration", event.name));
+                        }
+                        if !event.name.is_empty() && event.name != begin_event.name {
+                            error(
+                                i,
+                                format!(
+                                    "E '{}' does not match B '{}' (traceEvents[{}])",
+                                    event.name, begin_event.name, begin
+                                ),
+                            );
+                        }
+                    }
+                    None => error(
+                        i,
+                        format!(
+                            "E '{}' without B on pid={} tid={}",
+                            event.name, event.process_id, event.thread_id
+                        ),
+                    ),
+                }
+            }
+            EventType::AsyncNestableStart | EventType::AsyncNestableEnd => {
+                let id = match &event.id {
+                    Some(id) => id.as_str(),
+                    None => {
+                        error(i, format!("async event '{}' without id", event.name));
+                        continue;
+                    }
+                };
+                let key = (event.category.as_str(), event.scope.as_deref(), id);
+                let stack = async_stacks.entry(key).or_default();
+                if event.event_type == EventType::AsyncNestableStart {
+                    stack.push(i);
+                } else if let Some(begin) = stack.pop() {
+                    if events[begin].timestamp > event.timestamp {
+                        error(i, format!("e '{}' has negative duration", event.name));
+                    }
+                } else {
+                    error(
+                        i,
+                        format!(
+                            "e '{}' without b of same id '{}' (cat '{}')",
+                            event.name, id, event.category
+                        ),
+                    );
+                }
+            }
+            EventType::FlowStart | EventType::FlowStep | EventType::FlowEnd => {
+                let id = match &event.id {
+                    Some(id) => id.as_str(),
+                    None => {
+                        error(i, format!("flow event '{}' without id", event.name));
+                        continue;
+                    }
+                };
+                let key = (event.category.as_str(), id);
+                if event.event_type == EventType::FlowStart {
+                    flows.insert(key, i);
+                } else if !flows.contains_key(&key) {
+                    error(
+                        i,
+                        format!(
+                            "flow step '{}' without s of same id '{}' (cat '{}')",
+                            event.name, id, event.category
+                        ),
+                    );
+                } else if event.event_type == EventType::FlowEnd {
+                    flows.remove(&key);
+                }
+            }
+            _ => {}
+        }
+    }
+    for stack in duration_stacks.values() {
+        for &begin in stack {
+            error(begin, format!("B '{}' without E", events[begin].name));
+        }
+    }
+    for stack in async_stacks.values() {
+        for &begin in stack {
+            error(begin, format!("b '{}' without e", events[begin].name));
+        }
+    }
+    errors.sort_by_key(|error| error.index);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::IntoDeserializer;
+
+    // NOTE: this match fails to compile when a variant is added without its phase
+    fn phase(event_type: EventType) -> &'static str {
+        match event_type {
+            EventType::DurationBegin => "B",
+            EventType::DurationEnd => "E",
+            EventType::Complete => "X",
+            EventType::Instant => "i",
+            EventType::Counter => "C",
+            EventType::AsyncNestableStart => "b",
+            EventType::AsyncNestableInstant => "n",
+            EventType::AsyncNestableEnd => "e",
+            EventType::FlowStart => "s",
+            EventType::FlowStep => "t",
+            EventType::FlowEnd => "f",
+            EventType::Sample => "P",
+            EventType::ObjectCreated => "N",
+            EventType::ObjectSnapshot => "O",
+            EventType::ObjectDestroyed => "D",
+            EventType::Metadata => "M",
+            EventType::MemoryDumpGlobal => "V",
+            EventType::MemoryDumpProcess => "v",
+            EventType::Mark => "R",
+            EventType::ClockSync => "c",
+            EventType::ContextStart => "(",
+            EventType::ContextEnd => ")",
+        }
+    }
+
+    // every variant by variant index of serde (no hand-maintained list)
+    fn all_event_types() -> Vec<EventType> {
+        (0u32..)
+            .map_while(|index| {
+                let deserializer: serde::de::value::U32Deserializer<serde::de::value::Error> =
+                    index.into_deserializer();
+                EventType::deserialize(deserializer).ok()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn event_type_round_trip() {
+        let event_types = all_event_types();
+        assert_eq!(event_types.len(), 22);
+        let mut phases: Vec<String> = Vec::new();
+        for event_type in event_types {
+            let phase_json = serde_json::to_string(&event_type).unwrap();
+            assert_eq!(phase_json, format!("\"{}\"", phase(event_type)));
+            assert!(
+                !phases.contains(&phase_json),
+                "{:?} has duplicated phase {}",
+                event_type,
+                phase_json
+            );
+            let deserialized: EventType = serde_json::from_str(&phase_json).unwrap();
+            assert_eq!(deserialized, event_type);
+            phases.push(phase_json);
+        }
+    }
+
+    fn event(event_type: EventType, name: &str, micros: u64, id: Option<&str>) -> Event {
+        Event {
+            name: name.to_string(),
+            event_type,
+            timestamp: Duration::from_micros(micros),
+            process_id: 1.into(),
+            thread_id: 1.into(),
+            id: id.map(|id| id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn error_indexes(events: &[Event]) -> Vec<usize> {
+        validate(events).iter().map(|error| error.index).collect()
+    }
+
+    #[test]
+    fn validate_nested_begin_end() {
+        let events = vec![
+            event(EventType::DurationBegin, "a", 1, None),
+            event(EventType::DurationBegin, "b", 2, None),
+            event(EventType::DurationEnd, "b", 3, None),
+            event(EventType::DurationEnd, "a", 4, None),
+        ];
+        assert!(validate(&events).is_empty());
+    }
+
+    #[test]
+    fn validate_unbalanced_begin_end() {
+        let events = vec![
+            event(EventType::DurationEnd, "a", 1, None),
+            event(EventType::DurationBegin, "b", 2, None),
+            event(EventType::DurationBegin, "c", 3, None),
+            event(EventType::DurationEnd, "c", 2, None),
+        ];
+        // E without B, B without E and E earlier than B
+        assert_eq!(error_indexes(&events), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn validate_begin_end_per_thread() {
+        let mut end = event(EventType::DurationEnd, "a", 2, None);
+        end.thread_id = 2.into();
+        let events = vec![event(EventType::DurationBegin, "a", 1, None), end];
+        assert_eq!(error_indexes(&events), vec![0, 1]);
+    }
+
+    #[test]
+    fn validate_async_ids() {
+        let events = vec![
+            event(EventType::AsyncNestableStart, "a", 1, Some("1")),
+            event(EventType::AsyncNestableEnd, "a", 2, Some("2")),
+            event(EventType::AsyncNestableStart, "b", 3, Some("3")),
+            event(EventType::AsyncNestableEnd, "b", 4, Some("3")),
+            event(EventType::AsyncNestableEnd, "c", 5, None),
+        ];
+        // b without e, e without b and e without id
+        assert_eq!(error_indexes(&events), vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn validate_flow_steps() {
+        let events = vec![
+            event(EventType::FlowStep, "a", 1, Some("1")),
+            event(EventType::FlowStart, "b", 2, Some("2")),
+            event(EventType::FlowStep, "b", 3, Some("2")),
+            event(EventType::FlowEnd, "b", 4, Some("2")),
+            event(EventType::FlowEnd, "b", 5, Some("2")),
+        ];
+        // step without start and end after the flow is finished
+        assert_eq!(error_indexes(&events), vec![0, 4]);
+    }
+
+    #[test]
+    fn validate_negative_dur() {
+        let (trace, errors) = parse_and_validate_trace(
+            r#"[{"name":"a","ph":"X","ts":1,"dur":2,"pid":1,"tid":1},
+                {"name":"b","ph":"X","ts":1,"dur":-5,"pid":-1,"tid":1},
+                {"name":"c","ph":"B","ts":3,"pid":1,"tid":1},"#,
+        )
+        .unwrap();
+        assert_eq!(trace.trace_events.len(), 3);
+        assert_eq!(trace.trace_events[1].duration, Duration::from_micros(0));
+        let errors: Vec<(usize, &str)> = errors
+            .iter()
+            .map(|error| (error.index, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(1, "X 'b' has negative dur -5"), (2, "B 'c' without E")]
+        );
+        // negative 'dur' is not an error of parse_trace()
+        assert_eq!(
+            parse_trace("[{\"ph\":\"X\",\"dur\":-1}]")
+                .unwrap()
+                .trace_events
+                .len(),
+            1
+        );
+    }
 }
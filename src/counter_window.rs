@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+// fixed intervals of rate counters which start at the first timestamp
+pub(crate) struct CounterWindow {
+    interval: Duration,
+    start: Option<Duration>,
+}
+
+impl CounterWindow {
+    pub(crate) fn new(interval: Duration) -> CounterWindow {
+        CounterWindow {
+            interval,
+            start: None,
+        }
+    }
+
+    // (start, length) of the intervals which end before timestamp
+    pub(crate) fn advance(&mut self, timestamp: Duration) -> Vec<(Duration, Duration)> {
+        let start = *self.start.get_or_insert(timestamp);
+        let interval = self.interval;
+        if interval.is_zero() || timestamp < start + interval {
+            return vec![];
+        }
+        let end = start + interval;
+        let mut windows = vec![(start, interval)];
+        // NOTE: empty intervals are returned once as a long interval (zero rate)
+        // NOTE: u128 nanos is used because number of skipped intervals may overflow u32
+        let remainder = (timestamp - end).as_nanos() % interval.as_nanos();
+        let next_start = timestamp
+            - Duration::new(
+                (remainder / 1_000_000_000) as u64,
+                (remainder % 1_000_000_000) as u32,
+            );
+        if next_start > end {
+            windows.push((end, next_start - end));
+        }
+        self.start = Some(next_start);
+        windows
+    }
+
+    // the last partial interval
+    pub(crate) fn last(&self, last_timestamp: Duration) -> Option<(Duration, Duration)> {
+        self.start
+            .map(|start| (start, last_timestamp.saturating_sub(start)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_window() {
+        let mut window = CounterWindow::new(Duration::from_millis(10));
+        assert_eq!(window.advance(Duration::from_millis(5)), vec![]);
+        assert_eq!(window.advance(Duration::from_millis(14)), vec![]);
+        assert_eq!(
+            window.advance(Duration::from_millis(37)),
+            vec![
+                (Duration::from_millis(5), Duration::from_millis(10)),
+                (Duration::from_millis(15), Duration::from_millis(20)),
+            ]
+        );
+        assert_eq!(
+            window.last(Duration::from_millis(40)),
+            Some((Duration::from_millis(35), Duration::from_millis(5)))
+        );
+    }
+
+    #[test]
+    fn counter_window_many_skipped_intervals() {
+        let mut window = CounterWindow::new(Duration::from_nanos(1));
+        window.advance(Duration::from_nanos(0));
+        // NOTE: more than u32::MAX intervals are skipped
+        let timestamp = Duration::from_secs(10);
+        let windows = window.advance(timestamp);
+        assert_eq!(
+            windows[1],
+            (Duration::from_nanos(1), timestamp - Duration::from_nanos(1))
+        );
+        assert_eq!(
+            window.last(timestamp),
+            Some((timestamp, Duration::from_nanos(0)))
+        );
+    }
+}
@@ -1,41 +1,411 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::path::Path;
-use std::process::Command;
-use std::process::Stdio;
-use std::str;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ElfBitSize {
     Bit32,
     Bit64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfEndian {
+    Little,
+    Big,
+}
+
+impl ElfEndian {
+    pub fn is_host(&self) -> bool {
+        match self {
+            ElfEndian::Little => cfg!(target_endian = "little"),
+            ElfEndian::Big => cfg!(target_endian = "big"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    Relocatable,
+    Executable,
+    SharedObject,
+    Core,
+    Other(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfMachine {
+    X86,
+    X86_64,
+    Arm,
+    AArch64,
+    RiscV,
+    Mips,
+    PowerPC,
+    PowerPC64,
+    Other(u16),
+}
+
+impl ElfMachine {
+    fn from_e_machine(e_machine: u16) -> ElfMachine {
+        match e_machine {
+            3 => ElfMachine::X86,
+            62 => ElfMachine::X86_64,
+            40 => ElfMachine::Arm,
+            183 => ElfMachine::AArch64,
+            243 => ElfMachine::RiscV,
+            8 => ElfMachine::Mips,
+            20 => ElfMachine::PowerPC,
+            21 => ElfMachine::PowerPC64,
+            e_machine => ElfMachine::Other(e_machine),
+        }
+    }
+
+    // GNU binutils target triple prefix e.g. 'aarch64-linux-gnu-objdump'
+    pub fn binutils_prefix(&self, bit_size: ElfBitSize, endian: ElfEndian) -> Option<&'static str> {
+        use ElfBitSize::{Bit32, Bit64};
+        use ElfEndian::{Big, Little};
+        match (self, bit_size, endian) {
+            (ElfMachine::X86, _, Little) => Some("i686-linux-gnu-"),
+            (ElfMachine::X86_64, _, Little) => Some("x86_64-linux-gnu-"),
+            (ElfMachine::Arm, _, Little) => Some("arm-linux-gnueabihf-"),
+            (ElfMachine::Arm, _, Big) => Some("armeb-linux-gnueabihf-"),
+            (ElfMachine::AArch64, _, Little) => Some("aarch64-linux-gnu-"),
+            (ElfMachine::AArch64, _, Big) => Some("aarch64_be-linux-gnu-"),
+            (ElfMachine::RiscV, Bit32, Little) => Some("riscv32-linux-gnu-"),
+            (ElfMachine::RiscV, Bit64, Little) => Some("riscv64-linux-gnu-"),
+            (ElfMachine::Mips, Bit32, Little) => Some("mipsel-linux-gnu-"),
+            (ElfMachine::Mips, Bit32, Big) => Some("mips-linux-gnu-"),
+            (ElfMachine::Mips, Bit64, Little) => Some("mips64el-linux-gnuabi64-"),
+            (ElfMachine::Mips, Bit64, Big) => Some("mips64-linux-gnuabi64-"),
+            (ElfMachine::PowerPC, _, Big) => Some("powerpc-linux-gnu-"),
+            (ElfMachine::PowerPC64, _, Little) => Some("powerpc64le-linux-gnu-"),
+            (ElfMachine::PowerPC64, _, Big) => Some("powerpc64-linux-gnu-"),
+            _ => None,
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        matches!(
+            (self, std::env::consts::ARCH),
+            (ElfMachine::X86, "x86")
+                | (ElfMachine::X86_64, "x86_64")
+                | (ElfMachine::Arm, "arm")
+                | (ElfMachine::AArch64, "aarch64")
+                | (ElfMachine::RiscV, "riscv64")
+                | (ElfMachine::Mips, "mips")
+                | (ElfMachine::PowerPC, "powerpc")
+                | (ElfMachine::PowerPC64, "powerpc64")
+        )
+    }
+}
+
+impl fmt::Display for ElfMachine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfMachine::X86 => write!(f, "x86"),
+            ElfMachine::X86_64 => write!(f, "x86_64"),
+            ElfMachine::Arm => write!(f, "arm"),
+            ElfMachine::AArch64 => write!(f, "aarch64"),
+            ElfMachine::RiscV => write!(f, "riscv"),
+            ElfMachine::Mips => write!(f, "mips"),
+            ElfMachine::PowerPC => write!(f, "powerpc"),
+            ElfMachine::PowerPC64 => write!(f, "powerpc64"),
+            ElfMachine::Other(e_machine) => write!(f, "unknown({})", e_machine),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub bit_size: ElfBitSize,
+    pub endian: ElfEndian,
+    pub elf_type: ElfType,
+    pub machine: ElfMachine,
+    // ET_DYN with PT_INTERP program header
+    pub pie: bool,
+}
+
+impl ElfHeader {
+    pub fn is_32bit(&self) -> bool {
+        self.bit_size == ElfBitSize::Bit32
+    }
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const PT_INTERP: u32 = 3;
+
+pub fn read_elf_header(filepath: &Path) -> Result<ElfHeader> {
+    let mut f = File::open(filepath)
+        .with_context(|| format!("read_elf_header(): Failed to open file {:?}", filepath))?;
+    parse_elf_header(&mut f)
+        .with_context(|| format!("read_elf_header(): Failed to read file {:?}", filepath))
+}
+
+pub fn parse_elf_header<R: Read + Seek>(reader: &mut R) -> Result<ElfHeader> {
+    let mut e_ident = [0u8; 16];
+    reader.read_exact(&mut e_ident)?;
+    if e_ident[0..4] != ELF_MAGIC {
+        return Err(anyhow!("not an ELF file"));
+    }
+    let bit_size = match e_ident[4] {
+        1 => ElfBitSize::Bit32,
+        2 => ElfBitSize::Bit64,
+        c => return Err(anyhow!("invalid ELF class {}", c)),
+    };
+    let endian = match e_ident[5] {
+        1 => ElfEndian::Little,
+        2 => ElfEndian::Big,
+        d => return Err(anyhow!("invalid ELF data encoding {}", d)),
+    };
+    // rest of ELF header (Elf32_Ehdr is 52 bytes and Elf64_Ehdr is 64 bytes)
+    let header_size = match bit_size {
+        ElfBitSize::Bit32 => 52,
+        ElfBitSize::Bit64 => 64,
+    };
+    let mut buffer = vec![0u8; header_size - e_ident.len()];
+    reader
+        .read_exact(&mut buffer)
+        .context("truncated ELF header")?;
+    match endian {
+        ElfEndian::Little => {
+            read_elf_header_body::<LittleEndian, R>(reader, buffer, bit_size, endian)
+        }
+        ElfEndian::Big => read_elf_header_body::<BigEndian, R>(reader, buffer, bit_size, endian),
+    }
+}
+
+fn read_elf_header_body<T: ByteOrder, R: Read + Seek>(
+    f: &mut R,
+    buffer: Vec<u8>,
+    bit_size: ElfBitSize,
+    endian: ElfEndian,
+) -> Result<ElfHeader> {
+    let mut cur = Cursor::new(buffer);
+    let e_type = cur.read_u16::<T>()?;
+    let e_machine = cur.read_u16::<T>()?;
+    let _e_version = cur.read_u32::<T>()?;
+    let (e_phoff, program_header_size) = match bit_size {
+        ElfBitSize::Bit32 => {
+            let _e_entry = cur.read_u32::<T>()?;
+            (cur.read_u32::<T>()? as u64, 32)
+        }
+        ElfBitSize::Bit64 => {
+            let _e_entry = cur.read_u64::<T>()?;
+            (cur.read_u64::<T>()?, 56)
+        }
+    };
+    // skip e_shoff, e_flags, e_ehsize
+    let skip = match bit_size {
+        ElfBitSize::Bit32 => 4 + 4 + 2,
+        ElfBitSize::Bit64 => 8 + 4 + 2,
+    };
+    cur.seek(SeekFrom::Current(skip))?;
+    let e_phentsize = cur.read_u16::<T>()? as u64;
+    let e_phnum = cur.read_u16::<T>()? as u64;
+
+    let elf_type = match e_type {
+        1 => ElfType::Relocatable,
+        2 => ElfType::Executable,
+        3 => ElfType::SharedObject,
+        4 => ElfType::Core,
+        e_type => ElfType::Other(e_type),
+    };
+
+    let mut pie = false;
+    if elf_type == ElfType::SharedObject && e_phentsize >= program_header_size {
+        for i in 0..e_phnum {
+            f.seek(SeekFrom::Start(e_phoff + i * e_phentsize))?;
+            if f.read_u32::<T>()? == PT_INTERP {
+                pie = true;
+                break;
+            }
+        }
+    }
+
+    Ok(ElfHeader {
+        bit_size,
+        endian,
+        elf_type,
+        machine: ElfMachine::from_e_machine(e_machine),
+        pie,
+    })
+}
+
 pub fn detect_elf_bit_size(filepath: &Path) -> Result<ElfBitSize> {
-    let child = Command::new("file")
-        .arg(filepath)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("file command failed to start");
-    let output = child.wait_with_output()?;
-    let exit_code = output.status.code().unwrap();
-    if exit_code != 0 {
-        let stderr_output = String::from_utf8(output.stderr).unwrap();
-        return Err(anyhow!(
-            "Failed to run file command: exit_code={}, stderr={}",
-            exit_code,
-            stderr_output
-        ));
-    }
-
-    let line = str::from_utf8(&output.stdout).unwrap();
-
-    if line.contains("32-bit") {
-        Ok(ElfBitSize::Bit32)
-    } else if line.contains("64-bit") {
-        Ok(ElfBitSize::Bit64)
-    } else {
-        Err(anyhow!("Failed parse file result '{}'", line))
+    Ok(read_elf_header(filepath)?.bit_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    // minimal ELF header followed by program headers of the given types
+    fn elf<T: ByteOrder>(
+        bit_size: ElfBitSize,
+        e_type: u16,
+        e_machine: u16,
+        p_types: &[u32],
+    ) -> Vec<u8> {
+        let mut data = ELF_MAGIC.to_vec();
+        data.push(if bit_size == ElfBitSize::Bit32 { 1 } else { 2 });
+        // EI_DATA is 2 (big endian) if T reads [0, 1] as 1
+        data.push(if T::read_u16(&[0, 1]) == 1 { 2 } else { 1 });
+        data.resize(16, 0);
+        data.write_u16::<T>(e_type).unwrap();
+        data.write_u16::<T>(e_machine).unwrap();
+        data.write_u32::<T>(1).unwrap();
+        let (header_size, phentsize) = match bit_size {
+            ElfBitSize::Bit32 => {
+                data.write_u32::<T>(0).unwrap();
+                data.write_u32::<T>(52).unwrap();
+                data.write_u32::<T>(0).unwrap();
+                (52, 32)
+            }
+            ElfBitSize::Bit64 => {
+                data.write_u64::<T>(0).unwrap();
+                data.write_u64::<T>(64).unwrap();
+                data.write_u64::<T>(0).unwrap();
+                (64, 56)
+            }
+        };
+        data.write_u32::<T>(0).unwrap();
+        data.write_u16::<T>(header_size).unwrap();
+        data.write_u16::<T>(phentsize).unwrap();
+        data.write_u16::<T>(p_types.len() as u16).unwrap();
+        data.resize(header_size as usize, 0);
+        for p_type in p_types {
+            let offset = data.len();
+            data.write_u32::<T>(*p_type).unwrap();
+            data.resize(offset + phentsize as usize, 0);
+        }
+        data
+    }
+
+    fn parse(data: Vec<u8>) -> Result<ElfHeader> {
+        parse_elf_header(&mut Cursor::new(data))
+    }
+
+    const PT_LOAD: u32 = 1;
+
+    #[test]
+    fn elf64_little_endian() {
+        let header = parse(elf::<LittleEndian>(ElfBitSize::Bit64, 2, 62, &[PT_LOAD])).unwrap();
+        assert_eq!(header.bit_size, ElfBitSize::Bit64);
+        assert_eq!(header.endian, ElfEndian::Little);
+        assert_eq!(header.elf_type, ElfType::Executable);
+        assert_eq!(header.machine, ElfMachine::X86_64);
+        assert!(!header.pie);
+        assert_eq!(
+            header
+                .machine
+                .binutils_prefix(header.bit_size, header.endian),
+            Some("x86_64-linux-gnu-")
+        );
+    }
+
+    #[test]
+    fn elf32_little_endian() {
+        let header = parse(elf::<LittleEndian>(ElfBitSize::Bit32, 2, 40, &[PT_LOAD])).unwrap();
+        assert!(header.is_32bit());
+        assert_eq!(header.endian, ElfEndian::Little);
+        assert_eq!(header.machine, ElfMachine::Arm);
+        assert_eq!(
+            header
+                .machine
+                .binutils_prefix(header.bit_size, header.endian),
+            Some("arm-linux-gnueabihf-")
+        );
+    }
+
+    #[test]
+    fn elf64_big_endian() {
+        let header = parse(elf::<BigEndian>(ElfBitSize::Bit64, 2, 21, &[PT_LOAD])).unwrap();
+        assert_eq!(header.bit_size, ElfBitSize::Bit64);
+        assert_eq!(header.endian, ElfEndian::Big);
+        assert_eq!(header.machine, ElfMachine::PowerPC64);
+        assert_eq!(
+            header
+                .machine
+                .binutils_prefix(header.bit_size, header.endian),
+            Some("powerpc64-linux-gnu-")
+        );
+    }
+
+    #[test]
+    fn elf32_big_endian() {
+        let header = parse(elf::<BigEndian>(ElfBitSize::Bit32, 1, 8, &[])).unwrap();
+        assert!(header.is_32bit());
+        assert_eq!(header.endian, ElfEndian::Big);
+        assert_eq!(header.elf_type, ElfType::Relocatable);
+        assert_eq!(header.machine, ElfMachine::Mips);
+        assert_eq!(
+            header
+                .machine
+                .binutils_prefix(header.bit_size, header.endian),
+            Some("mips-linux-gnu-")
+        );
+        // no prefix of 32bit powerpc little endian and unknown machine
+        assert_eq!(
+            ElfMachine::PowerPC.binutils_prefix(ElfBitSize::Bit32, ElfEndian::Little),
+            None
+        );
+        assert_eq!(
+            ElfMachine::Other(0).binutils_prefix(ElfBitSize::Bit64, ElfEndian::Little),
+            None
+        );
+    }
+
+    #[test]
+    fn pie_and_shared_library() {
+        // PT_INTERP after PT_LOAD
+        let pie = parse(elf::<LittleEndian>(
+            ElfBitSize::Bit64,
+            3,
+            183,
+            &[PT_LOAD, PT_INTERP],
+        ))
+        .unwrap();
+        assert_eq!(pie.elf_type, ElfType::SharedObject);
+        assert!(pie.pie);
+        let pie = parse(elf::<BigEndian>(
+            ElfBitSize::Bit32,
+            3,
+            8,
+            &[PT_INTERP, PT_LOAD],
+        ))
+        .unwrap();
+        assert!(pie.pie);
+        let shared_library = parse(elf::<LittleEndian>(
+            ElfBitSize::Bit64,
+            3,
+            183,
+            &[PT_LOAD, PT_LOAD],
+        ))
+        .unwrap();
+        assert_eq!(shared_library.elf_type, ElfType::SharedObject);
+        assert!(!shared_library.pie);
+        // PT_INTERP of ET_EXEC is not PIE
+        let executable =
+            parse(elf::<LittleEndian>(ElfBitSize::Bit64, 2, 62, &[PT_INTERP])).unwrap();
+        assert!(!executable.pie);
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(parse(b"#!/bin/sh\necho hello\n".to_vec()).is_err());
+        let mut data = elf::<LittleEndian>(ElfBitSize::Bit64, 2, 62, &[]);
+        data[4] = 3;
+        assert!(parse(data).is_err());
+        let mut data = elf::<LittleEndian>(ElfBitSize::Bit64, 2, 62, &[]);
+        data.truncate(40);
+        assert!(parse(data).is_err());
     }
 }
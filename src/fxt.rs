@@ -0,0 +1,747 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::chrome;
+use crate::chrome::{EventType, TrackId};
+use crate::hash;
+
+// Fuchsia trace format
+// https://fuchsia.dev/fuchsia-src/reference/tracing/trace-format
+const MAGIC_NUMBER_RECORD: u64 = 0x0016547846040010;
+
+const RECORD_TYPE_INITIALIZATION: u64 = 1;
+const RECORD_TYPE_STRING: u64 = 2;
+const RECORD_TYPE_THREAD: u64 = 3;
+const RECORD_TYPE_EVENT: u64 = 4;
+const RECORD_TYPE_KERNEL_OBJECT: u64 = 7;
+
+const EVENT_TYPE_INSTANT: u64 = 0;
+const EVENT_TYPE_COUNTER: u64 = 1;
+const EVENT_TYPE_DURATION_BEGIN: u64 = 2;
+const EVENT_TYPE_DURATION_END: u64 = 3;
+const EVENT_TYPE_DURATION_COMPLETE: u64 = 4;
+const EVENT_TYPE_ASYNC_BEGIN: u64 = 5;
+const EVENT_TYPE_ASYNC_INSTANT: u64 = 6;
+const EVENT_TYPE_ASYNC_END: u64 = 7;
+const EVENT_TYPE_FLOW_BEGIN: u64 = 8;
+const EVENT_TYPE_FLOW_STEP: u64 = 9;
+const EVENT_TYPE_FLOW_END: u64 = 10;
+
+const ARGUMENT_TYPE_INT32: u64 = 1;
+const ARGUMENT_TYPE_UINT32: u64 = 2;
+const ARGUMENT_TYPE_INT64: u64 = 3;
+const ARGUMENT_TYPE_UINT64: u64 = 4;
+const ARGUMENT_TYPE_DOUBLE: u64 = 5;
+const ARGUMENT_TYPE_STRING: u64 = 6;
+const ARGUMENT_TYPE_KOID: u64 = 8;
+const ARGUMENT_TYPE_BOOL: u64 = 9;
+
+const ZX_OBJ_TYPE_PROCESS: u64 = 1;
+const ZX_OBJ_TYPE_THREAD: u64 = 2;
+
+const TICKS_PER_SECOND: u64 = 1_000_000_000;
+
+// string index is 15 bits and thread index is 8 bits (0 means inline or empty)
+const MAX_STRING_INDEX: u16 = 0x7fff;
+const MAX_THREAD_INDEX: u8 = 0xff;
+const MAX_ARGUMENT_COUNT: usize = 15;
+// NOTE: record size is 12 bits of 8 bytes words (including header word)
+const MAX_STRING_LENGTH: usize = (0xfff - 1) * 8;
+
+// koid of string pid/tid e.g. "CPU 3"
+const VIRTUAL_KOID_BASE: u64 = 1 << 32;
+
+fn header(record_type: u64, size_words: usize) -> u64 {
+    record_type | ((size_words as u64) << 4)
+}
+
+fn padded_words(len: usize) -> usize {
+    len.div_ceil(8)
+}
+
+fn truncate_str(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+enum ArgumentValue {
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(u16),
+    Koid(u64),
+    Bool(bool),
+}
+
+struct Argument {
+    name: u16,
+    value: ArgumentValue,
+}
+
+impl Argument {
+    fn size_words(&self) -> usize {
+        match self.value {
+            ArgumentValue::Int64(_)
+            | ArgumentValue::UInt64(_)
+            | ArgumentValue::Double(_)
+            | ArgumentValue::Koid(_) => 2,
+            _ => 1,
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let (argument_type, value_bits, value_word) = match self.value {
+            ArgumentValue::Int32(v) => (ARGUMENT_TYPE_INT32, v as u32 as u64, None),
+            ArgumentValue::UInt32(v) => (ARGUMENT_TYPE_UINT32, v as u64, None),
+            ArgumentValue::Int64(v) => (ARGUMENT_TYPE_INT64, 0, Some(v as u64)),
+            ArgumentValue::UInt64(v) => (ARGUMENT_TYPE_UINT64, 0, Some(v)),
+            ArgumentValue::Double(v) => (ARGUMENT_TYPE_DOUBLE, 0, Some(v.to_bits())),
+            ArgumentValue::String(v) => (ARGUMENT_TYPE_STRING, v as u64, None),
+            ArgumentValue::Koid(v) => (ARGUMENT_TYPE_KOID, 0, Some(v)),
+            ArgumentValue::Bool(v) => (ARGUMENT_TYPE_BOOL, v as u64, None),
+        };
+        let word = argument_type
+            | ((self.size_words() as u64) << 4)
+            | ((self.name as u64) << 16)
+            | (value_bits << 32);
+        writer.write_u64::<LittleEndian>(word)?;
+        if let Some(value_word) = value_word {
+            writer.write_u64::<LittleEndian>(value_word)?;
+        }
+        Ok(())
+    }
+}
+
+// NOTE: the least recently used index is reused when the table is full, so the record is written again
+// and entries used by the current record are pinned not to be reused before its header is written
+struct RefTable<K> {
+    indexes: HashMap<K, u64>,
+    // (key, last used tick) of each index
+    slots: Vec<Option<(K, u64)>>,
+    // (last used tick, index) from the least recently used one
+    lru: BTreeSet<(u64, u64)>,
+    tick: u64,
+    // entries used after this tick are pinned
+    pin_tick: u64,
+    max_index: u64,
+}
+
+impl<K: std::hash::Hash + Eq + Clone> RefTable<K> {
+    fn new(max_index: u64) -> RefTable<K> {
+        RefTable {
+            indexes: HashMap::new(),
+            slots: vec![None; max_index as usize + 1],
+            lru: BTreeSet::new(),
+            tick: 0,
+            pin_tick: 0,
+            max_index,
+        }
+    }
+
+    // unpin entries of the previous record
+    fn begin_record(&mut self) {
+        self.pin_tick = self.tick;
+    }
+
+    // (index, is_new)
+    fn get_or_insert(&mut self, key: &K) -> Result<(u64, bool)> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(&index) = self.indexes.get(key) {
+            let slot = self.slots[index as usize].as_mut().unwrap();
+            self.lru.remove(&(slot.1, index));
+            self.lru.insert((tick, index));
+            slot.1 = tick;
+            return Ok((index, false));
+        }
+        // NOTE: index 0 is not used
+        let index = if (self.lru.len() as u64) < self.max_index {
+            self.lru.len() as u64 + 1
+        } else {
+            let (last_tick, index) = *self.lru.iter().next().unwrap();
+            if last_tick > self.pin_tick {
+                return Err(anyhow!(
+                    "one record needs more than {} references",
+                    self.max_index
+                ));
+            }
+            self.lru.remove(&(last_tick, index));
+            let (old_key, _) = self.slots[index as usize].take().unwrap();
+            self.indexes.remove(&old_key);
+            index
+        };
+        self.slots[index as usize] = Some((key.clone(), tick));
+        self.lru.insert((tick, index));
+        self.indexes.insert(key.clone(), index);
+        Ok((index, true))
+    }
+}
+
+// convert chrome::Event stream into FXT records
+pub struct FxtWriter<W: Write> {
+    writer: W,
+    strings: RefTable<String>,
+    threads: RefTable<(u64, u64)>,
+    virtual_koids: HashMap<TrackId, u64>,
+    event_count: usize,
+    unsupported_count: usize,
+}
+
+impl<W: Write> FxtWriter<W> {
+    pub fn new(mut writer: W) -> Result<FxtWriter<W>> {
+        writer.write_u64::<LittleEndian>(MAGIC_NUMBER_RECORD)?;
+        writer.write_u64::<LittleEndian>(header(RECORD_TYPE_INITIALIZATION, 2))?;
+        writer.write_u64::<LittleEndian>(TICKS_PER_SECOND)?;
+        Ok(FxtWriter {
+            writer,
+            strings: RefTable::new(MAX_STRING_INDEX as u64),
+            threads: RefTable::new(MAX_THREAD_INDEX as u64),
+            virtual_koids: HashMap::new(),
+            event_count: 0,
+            unsupported_count: 0,
+        })
+    }
+
+    // string ref (0 means empty string)
+    fn string_ref(&mut self, s: &str) -> Result<u16> {
+        let s = truncate_str(s, MAX_STRING_LENGTH);
+        if s.is_empty() {
+            return Ok(0);
+        }
+        let (index, is_new) = self.strings.get_or_insert(&s.to_string())?;
+        if is_new {
+            let words = padded_words(s.len());
+            let word =
+                header(RECORD_TYPE_STRING, 1 + words) | (index << 16) | ((s.len() as u64) << 32);
+            self.writer.write_u64::<LittleEndian>(word)?;
+            self.write_padded(s.as_bytes())?;
+        }
+        Ok(index as u16)
+    }
+
+    fn write_padded(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        let padding = padded_words(data.len()) * 8 - data.len();
+        self.writer.write_all(&[0u8; 8][..padding])?;
+        Ok(())
+    }
+
+    fn koid(&mut self, id: &TrackId, object_type: u64) -> Result<u64> {
+        match id {
+            TrackId::Number(id) => Ok(*id as u64),
+            TrackId::Name(name) => {
+                if let Some(koid) = self.virtual_koids.get(id) {
+                    return Ok(*koid);
+                }
+                let koid = VIRTUAL_KOID_BASE + self.virtual_koids.len() as u64;
+                self.virtual_koids.insert(id.clone(), koid);
+                // NOTE: string pid/tid is used as the name of the object
+                let name = name.clone();
+                self.write_kernel_object(koid, object_type, &name, None)?;
+                Ok(koid)
+            }
+        }
+    }
+
+    fn thread_ref(&mut self, pid: &TrackId, tid: &TrackId) -> Result<u8> {
+        let process_koid = self.koid(pid, ZX_OBJ_TYPE_PROCESS)?;
+        let thread_koid = self.koid(tid, ZX_OBJ_TYPE_THREAD)?;
+        let (index, is_new) = self.threads.get_or_insert(&(process_koid, thread_koid))?;
+        if is_new {
+            self.writer
+                .write_u64::<LittleEndian>(header(RECORD_TYPE_THREAD, 3) | (index << 16))?;
+            self.writer.write_u64::<LittleEndian>(process_koid)?;
+            self.writer.write_u64::<LittleEndian>(thread_koid)?;
+        }
+        Ok(index as u8)
+    }
+
+    fn write_kernel_object(
+        &mut self,
+        koid: u64,
+        object_type: u64,
+        name: &str,
+        process_koid: Option<u64>,
+    ) -> Result<()> {
+        let name = self.string_ref(name)?;
+        let mut arguments: Vec<Argument> = Vec::new();
+        if let Some(process_koid) = process_koid {
+            arguments.push(Argument {
+                name: self.string_ref("process")?,
+                value: ArgumentValue::Koid(process_koid),
+            });
+        }
+        let size_words = 2 + arguments.iter().map(|a| a.size_words()).sum::<usize>();
+        let word = header(RECORD_TYPE_KERNEL_OBJECT, size_words)
+            | (object_type << 16)
+            | ((name as u64) << 24)
+            | ((arguments.len() as u64) << 40);
+        self.writer.write_u64::<LittleEndian>(word)?;
+        self.writer.write_u64::<LittleEndian>(koid)?;
+        for argument in &arguments {
+            argument.write(&mut self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn argument(&mut self, key: &str, value: &serde_json::Value) -> Result<Argument> {
+        let name = self.string_ref(key)?;
+        let value = match value {
+            serde_json::Value::Bool(v) => ArgumentValue::Bool(*v),
+            serde_json::Value::Number(n) => {
+                if let Some(v) = n.as_i64() {
+                    if let Ok(v) = i32::try_from(v) {
+                        ArgumentValue::Int32(v)
+                    } else {
+                        ArgumentValue::Int64(v)
+                    }
+                } else if let Some(v) = n.as_u64() {
+                    if let Ok(v) = u32::try_from(v) {
+                        ArgumentValue::UInt32(v)
+                    } else {
+                        ArgumentValue::UInt64(v)
+                    }
+                } else {
+                    ArgumentValue::Double(n.as_f64().unwrap_or(0.0))
+                }
+            }
+            serde_json::Value::String(s) => ArgumentValue::String(self.string_ref(s)?),
+            // NOTE: nested values are stored as JSON string
+            value => ArgumentValue::String(self.string_ref(&value.to_string())?),
+        };
+        Ok(Argument { name, value })
+    }
+
+    fn write_event_record(
+        &mut self,
+        event: &chrome::Event,
+        event_type: u64,
+        extra_word: Option<u64>,
+    ) -> Result<()> {
+        let thread = self.thread_ref(&event.process_id, &event.thread_id)?;
+        let category = self.string_ref(&event.category)?;
+        let name = self.string_ref(&event.name)?;
+        let mut arguments: Vec<Argument> = Vec::new();
+        if let Some(args) = &event.args {
+            if args.len() > MAX_ARGUMENT_COUNT {
+                log::warn!(
+                    "'{}' has {} args, but FXT supports only {} args",
+                    event.name,
+                    args.len(),
+                    MAX_ARGUMENT_COUNT
+                );
+            }
+            for (key, value) in args.iter().take(MAX_ARGUMENT_COUNT) {
+                arguments.push(self.argument(key, value)?);
+            }
+        }
+        let size_words =
+            2 + arguments.iter().map(|a| a.size_words()).sum::<usize>() + extra_word.iter().count();
+        let word = header(RECORD_TYPE_EVENT, size_words)
+            | (event_type << 16)
+            | ((arguments.len() as u64) << 20)
+            | ((thread as u64) << 24)
+            | ((category as u64) << 32)
+            | ((name as u64) << 48);
+        self.writer.write_u64::<LittleEndian>(word)?;
+        self.writer
+            .write_u64::<LittleEndian>(event.timestamp.as_nanos() as u64)?;
+        for argument in &arguments {
+            argument.write(&mut self.writer)?;
+        }
+        if let Some(extra_word) = extra_word {
+            self.writer.write_u64::<LittleEndian>(extra_word)?;
+        }
+        Ok(())
+    }
+
+    fn write_metadata_event(&mut self, event: &chrome::Event) -> Result<()> {
+        let name = match event.arg("name").and_then(|name| name.as_str()) {
+            Some(name) => name.to_string(),
+            None => return Ok(()),
+        };
+        match event.name.as_str() {
+            "process_name" => {
+                let koid = self.koid(&event.process_id, ZX_OBJ_TYPE_PROCESS)?;
+                self.write_kernel_object(koid, ZX_OBJ_TYPE_PROCESS, &name, None)?;
+            }
+            "thread_name" => {
+                let process_koid = self.koid(&event.process_id, ZX_OBJ_TYPE_PROCESS)?;
+                let koid = self.koid(&event.thread_id, ZX_OBJ_TYPE_THREAD)?;
+                self.write_kernel_object(koid, ZX_OBJ_TYPE_THREAD, &name, Some(process_koid))?;
+            }
+            // NOTE: sort index and labels are not supported
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn write_event(&mut self, event: &chrome::Event) -> Result<()> {
+        // NOTE: every string and thread of a record is referenced before its header is written
+        self.strings.begin_record();
+        self.threads.begin_record();
+        match event.event_type {
+            EventType::Metadata => self.write_metadata_event(event)?,
+            EventType::DurationBegin => {
+                self.write_event_record(event, EVENT_TYPE_DURATION_BEGIN, None)?
+            }
+            EventType::DurationEnd => {
+                self.write_event_record(event, EVENT_TYPE_DURATION_END, None)?
+            }
+            EventType::Complete => {
+                let end_timestamp = (event.timestamp + event.duration).as_nanos() as u64;
+                self.write_event_record(event, EVENT_TYPE_DURATION_COMPLETE, Some(end_timestamp))?
+            }
+            EventType::Instant => self.write_event_record(event, EVENT_TYPE_INSTANT, None)?,
+            // NOTE: counter id distinguishes series of same name
+            EventType::Counter => self.write_event_record(
+                event,
+                EVENT_TYPE_COUNTER,
+                Some(correlation_id(&event.category, event.id.as_deref())),
+            )?,
+            EventType::AsyncNestableStart
+            | EventType::AsyncNestableInstant
+            | EventType::AsyncNestableEnd => {
+                let event_type = match event.event_type {
+                    EventType::AsyncNestableStart => EVENT_TYPE_ASYNC_BEGIN,
+                    EventType::AsyncNestableEnd => EVENT_TYPE_ASYNC_END,
+                    _ => EVENT_TYPE_ASYNC_INSTANT,
+                };
+                let id = correlation_id(&event.category, event.id.as_deref());
+                self.write_event_record(event, event_type, Some(id))?
+            }
+            EventType::FlowStart | EventType::FlowStep | EventType::FlowEnd => {
+                let event_type = match event.event_type {
+                    EventType::FlowStart => EVENT_TYPE_FLOW_BEGIN,
+                    EventType::FlowStep => EVENT_TYPE_FLOW_STEP,
+                    _ => EVENT_TYPE_FLOW_END,
+                };
+                let id = correlation_id(&event.category, event.id.as_deref());
+                self.write_event_record(event, event_type, Some(id))?
+            }
+            _ => {
+                self.unsupported_count += 1;
+                return Ok(());
+            }
+        }
+        self.event_count += 1;
+        Ok(())
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.event_count
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        if self.unsupported_count > 0 {
+            log::warn!(
+                "{} events are skipped because FXT does not support the phase",
+                self.unsupported_count
+            );
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> chrome::EventWriter<W> for FxtWriter<W> {
+    fn write_event(&mut self, event: &chrome::Event) -> Result<()> {
+        FxtWriter::write_event(self, event)
+    }
+
+    fn event_count(&self) -> usize {
+        FxtWriter::event_count(self)
+    }
+
+    fn finish(self: Box<Self>) -> Result<W> {
+        FxtWriter::finish(*self)
+    }
+}
+
+// numeric id (e.g. "0x10" or "16") is used as is, otherwise FNV-1a of category and id
+fn correlation_id(category: &str, id: Option<&str>) -> u64 {
+    let id = id.unwrap_or("");
+    let numeric_id = match id.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => id.parse::<u64>().ok(),
+    };
+    if let Some(numeric_id) = numeric_id {
+        return numeric_id;
+    }
+    hash::fnv1a(category.bytes().chain([0u8]).chain(id.bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    enum Record {
+        String(u64, String),
+        Thread(u64, u64, u64),
+        KernelObject(u64, u64, String),
+        // (event type, (process koid, thread koid), category, name, timestamp, args, extra word)
+        Event(
+            u64,
+            (u64, u64),
+            String,
+            String,
+            u64,
+            Vec<(String, String)>,
+            Option<u64>,
+        ),
+    }
+
+    fn bits(word: u64, begin: u32, end: u32) -> u64 {
+        (word >> begin) & ((1 << (end - begin + 1)) - 1)
+    }
+
+    // decode records with string and thread tables (references are resolved at each record)
+    fn decode(data: &[u8]) -> Vec<Record> {
+        let words: Vec<u64> = data.chunks(8).map(LittleEndian::read_u64).collect();
+        assert_eq!(words[0], MAGIC_NUMBER_RECORD);
+        assert_eq!(words[1], header(RECORD_TYPE_INITIALIZATION, 2));
+        assert_eq!(words[2], TICKS_PER_SECOND);
+        let mut strings: HashMap<u64, String> = HashMap::new();
+        let mut threads: HashMap<u64, (u64, u64)> = HashMap::new();
+        let mut records = Vec::new();
+        let mut offset = 3;
+        while offset < words.len() {
+            let word = words[offset];
+            let size_words = bits(word, 4, 15) as usize;
+            let body = &words[offset + 1..offset + size_words];
+            let string = |index: u64| -> String {
+                if index == 0 {
+                    String::new()
+                } else {
+                    strings[&index].clone()
+                }
+            };
+            let record = match bits(word, 0, 3) {
+                RECORD_TYPE_STRING => {
+                    let len = bits(word, 32, 46) as usize;
+                    let bytes: Vec<u8> = body.iter().flat_map(|w| w.to_le_bytes()).collect();
+                    let s = String::from_utf8(bytes[..len].to_vec()).unwrap();
+                    strings.insert(bits(word, 16, 30), s.clone());
+                    Record::String(bits(word, 16, 30), s)
+                }
+                RECORD_TYPE_THREAD => {
+                    threads.insert(bits(word, 16, 23), (body[0], body[1]));
+                    Record::Thread(bits(word, 16, 23), body[0], body[1])
+                }
+                RECORD_TYPE_KERNEL_OBJECT => {
+                    Record::KernelObject(body[0], bits(word, 16, 23), string(bits(word, 24, 39)))
+                }
+                RECORD_TYPE_EVENT => {
+                    let mut args = Vec::new();
+                    let mut arg_offset = 1;
+                    for _ in 0..bits(word, 20, 23) {
+                        let arg = body[arg_offset];
+                        let value = match bits(arg, 0, 3) {
+                            ARGUMENT_TYPE_STRING => string(bits(arg, 32, 47)),
+                            ARGUMENT_TYPE_INT32 => (bits(arg, 32, 63) as u32 as i32).to_string(),
+                            ARGUMENT_TYPE_UINT64 => body[arg_offset + 1].to_string(),
+                            ARGUMENT_TYPE_BOOL => (bits(arg, 32, 32) == 1).to_string(),
+                            argument_type => format!("type {}", argument_type),
+                        };
+                        args.push((string(bits(arg, 16, 31)), value));
+                        arg_offset += bits(arg, 4, 15) as usize;
+                    }
+                    Record::Event(
+                        bits(word, 16, 19),
+                        threads[&bits(word, 24, 31)],
+                        string(bits(word, 32, 47)),
+                        string(bits(word, 48, 63)),
+                        body[0],
+                        args,
+                        body.get(arg_offset).copied(),
+                    )
+                }
+                record_type => panic!("unknown record type {}", record_type),
+            };
+            records.push(record);
+            offset += size_words;
+        }
+        records
+    }
+
+    fn event(
+        event_type: EventType,
+        name: &str,
+        micros: u64,
+        args: &[(&str, &str)],
+    ) -> chrome::Event {
+        let mut event = chrome::Event {
+            name: name.to_string(),
+            category: String::from("cat"),
+            event_type,
+            timestamp: Duration::from_micros(micros),
+            process_id: 1u32.into(),
+            thread_id: 2u32.into(),
+            ..Default::default()
+        };
+        for (key, value) in args {
+            event.set_arg(key, *value);
+        }
+        event
+    }
+
+    fn write(fxt_writer: FxtWriter<Vec<u8>>, events: &[chrome::Event]) -> Vec<Record> {
+        let mut fxt_writer = fxt_writer;
+        for event in events {
+            fxt_writer.write_event(event).unwrap();
+        }
+        decode(&fxt_writer.finish().unwrap())
+    }
+
+    #[test]
+    fn records() {
+        let mut complete = event(EventType::Complete, "main", 10, &[("arg", "value")]);
+        complete.duration = Duration::from_micros(5);
+        complete.set_arg("count", 3).set_arg("flag", true);
+        let records = write(
+            FxtWriter::new(Vec::new()).unwrap(),
+            &[complete, event(EventType::Instant, "main", 20, &[])],
+        );
+        assert_eq!(
+            records,
+            vec![
+                Record::Thread(1, 1, 2),
+                Record::String(1, String::from("cat")),
+                Record::String(2, String::from("main")),
+                Record::String(3, String::from("arg")),
+                Record::String(4, String::from("value")),
+                Record::String(5, String::from("count")),
+                Record::String(6, String::from("flag")),
+                Record::Event(
+                    EVENT_TYPE_DURATION_COMPLETE,
+                    (1, 2),
+                    String::from("cat"),
+                    String::from("main"),
+                    10_000,
+                    vec![
+                        (String::from("arg"), String::from("value")),
+                        (String::from("count"), String::from("3")),
+                        (String::from("flag"), String::from("true")),
+                    ],
+                    Some(15_000),
+                ),
+                // strings and thread are referenced without records
+                Record::Event(
+                    EVENT_TYPE_INSTANT,
+                    (1, 2),
+                    String::from("cat"),
+                    String::from("main"),
+                    20_000,
+                    vec![],
+                    None,
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_track_ids() {
+        let mut counter = event(EventType::Counter, "memory", 10, &[]);
+        counter.process_id = chrome::TrackId::from("CPU 3");
+        counter.set_arg("value", u64::MAX);
+        counter.id = Some(String::from("0x10"));
+        let records = write(FxtWriter::new(Vec::new()).unwrap(), &[counter]);
+        assert_eq!(
+            records[..2],
+            [
+                Record::String(1, String::from("CPU 3")),
+                Record::KernelObject(
+                    VIRTUAL_KOID_BASE,
+                    ZX_OBJ_TYPE_PROCESS,
+                    String::from("CPU 3")
+                ),
+            ]
+        );
+        assert_eq!(
+            records.last(),
+            Some(&Record::Event(
+                EVENT_TYPE_COUNTER,
+                (VIRTUAL_KOID_BASE, 2),
+                String::from("cat"),
+                String::from("memory"),
+                10_000,
+                vec![(String::from("value"), u64::MAX.to_string())],
+                Some(0x10),
+            ))
+        );
+    }
+
+    #[test]
+    fn string_slots_are_reused() {
+        let mut fxt_writer = FxtWriter::new(Vec::new()).unwrap();
+        fxt_writer.strings = RefTable::new(4);
+        let events: Vec<chrome::Event> = (0..6)
+            .map(|i| {
+                let value = format!("value{}", i);
+                event(EventType::Instant, "name", i, &[("arg", value.as_str())])
+            })
+            .collect();
+        let records = write(fxt_writer, &events);
+        let decoded_events: Vec<&Record> = records
+            .iter()
+            .filter(|record| matches!(record, Record::Event(..)))
+            .collect();
+        // every event refers its own strings even if slots of category and name are reused
+        for (i, record) in decoded_events.iter().enumerate() {
+            assert_eq!(
+                **record,
+                Record::Event(
+                    EVENT_TYPE_INSTANT,
+                    (1, 2),
+                    String::from("cat"),
+                    String::from("name"),
+                    i as u64 * 1000,
+                    vec![(String::from("arg"), format!("value{}", i))],
+                    None,
+                )
+            );
+        }
+        let string_count = records
+            .iter()
+            .filter(|record| matches!(record, Record::String(..)))
+            .count();
+        assert!(string_count > 4 + 1);
+
+        // record which needs more strings than the table
+        let mut fxt_writer = FxtWriter::new(Vec::new()).unwrap();
+        fxt_writer.strings = RefTable::new(3);
+        let event = event(EventType::Instant, "name", 0, &[("arg", "value")]);
+        assert!(fxt_writer.write_event(&event).is_err());
+    }
+
+    #[test]
+    fn lru_table() {
+        let mut table: RefTable<&str> = RefTable::new(2);
+        assert_eq!(table.get_or_insert(&"a").unwrap(), (1, true));
+        assert_eq!(table.get_or_insert(&"b").unwrap(), (2, true));
+        table.begin_record();
+        assert_eq!(table.get_or_insert(&"a").unwrap(), (1, false));
+        // 'b' is the least recently used one
+        assert_eq!(table.get_or_insert(&"c").unwrap(), (2, true));
+        // 'a' and 'c' are pinned in this record
+        assert!(table.get_or_insert(&"d").is_err());
+        table.begin_record();
+        assert_eq!(table.get_or_insert(&"d").unwrap(), (1, true));
+        assert_eq!(table.get_or_insert(&"c").unwrap(), (2, false));
+    }
+}
@@ -1,60 +1,71 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use num_traits::FromPrimitive;
 
-use std::collections::HashMap;
-use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
-use std::io::Cursor;
+use std::io::BufReader;
 use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use crate::chrome;
 
-pub fn parse_text_files(files: &Vec<PathBuf>) -> Result<Vec<chrome::Event>> {
-    let mut events: Vec<chrome::Event> = Vec::new();
-    for file in files {
-        let mut result = parse_text_file(&file)?;
-        events.append(&mut result);
-    }
-    Ok(events)
+// event of iftracer with the function address which is not resolved yet
+pub struct Record {
+    pub event: chrome::Event,
+    pub address: Option<u64>,
 }
 
-pub fn parse_text_file(filename: &PathBuf) -> Result<Vec<chrome::Event>> {
-    let buffer = get_file_as_byte_vec(filename)?;
-    parse_text_buffer(buffer)
+pub type RecordIter = Box<dyn Iterator<Item = Result<Record>>>;
+
+fn open_file(filename: &PathBuf) -> Result<BufReader<File>> {
+    let f = File::open(filename)
+        .with_context(|| format!("open_file(): Failed to open file {:?}", filename))?;
+    Ok(BufReader::new(f))
 }
 
-pub fn parse_binary_files(files: &Vec<PathBuf>, bit32_flag: bool) -> Result<Vec<chrome::Event>> {
-    let mut events: Vec<chrome::Event> = Vec::new();
-    if files.len() == 0 {
-        return Ok(events);
-    }
-    for file in files {
-        let mut result = parse_binary_file(&file, bit32_flag)?;
-        events.append(&mut result);
-    }
-    Ok(events)
+// NOTE: files are opened lazily, so every file is read one by one
+pub fn iter_text_files(files: &[PathBuf]) -> RecordIter {
+    let files = files.to_vec();
+    Box::new(files.into_iter().flat_map(|file| -> RecordIter {
+        match open_file(&file) {
+            Ok(reader) => Box::new(TextEventIter::new(reader)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }))
+}
+
+pub fn iter_binary_files(files: &[PathBuf], bit32_flag: bool) -> RecordIter {
+    let files = files.to_vec();
+    Box::new(files.into_iter().flat_map(move |file| -> RecordIter {
+        match open_file(&file).and_then(|reader| BinaryEventIter::new(reader, bit32_flag)) {
+            Ok(iter) => Box::new(iter),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }))
+}
+
+pub fn parse_text_files(files: &[PathBuf]) -> Result<Vec<chrome::Event>> {
+    iter_text_files(files)
+        .map(|record| record.map(|record| record.event))
+        .collect()
 }
 
-fn parse_binary_file(filename: &PathBuf, bit32_flag: bool) -> Result<Vec<chrome::Event>> {
-    let buffer = get_file_as_byte_vec(filename)?;
-    parse_binary_buffer(buffer, bit32_flag)
+pub fn parse_text_file(filename: &PathBuf) -> Result<Vec<chrome::Event>> {
+    TextEventIter::new(open_file(filename)?)
+        .map(|record| record.map(|record| record.event))
+        .collect()
 }
 
-fn get_file_as_byte_vec(filename: &PathBuf) -> Result<Vec<u8>> {
-    let mut f = File::open(&filename).expect("no file found");
-    let metadata = fs::metadata(&filename).expect("unable to read metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
-    Ok(buffer)
+pub fn parse_binary_files(files: &[PathBuf], bit32_flag: bool) -> Result<Vec<chrome::Event>> {
+    iter_binary_files(files, bit32_flag)
+        .map(|record| record.map(|record| record.event))
+        .collect()
 }
 
-fn parse_line_to_event(line: &str) -> Result<chrome::Event> {
+fn parse_line_to_record(line: &str) -> Result<Record> {
     let fields: Vec<&str> = line.split_whitespace().collect();
     if fields.len() != 5 {
         return Err(anyhow!(
@@ -66,9 +77,9 @@ fn parse_line_to_event(line: &str) -> Result<chrome::Event> {
     let timestamp = Duration::from_micros(fields[1].parse::<u64>()?);
     let action = fields[2];
     let _caller_address = fields[3];
-    let callee_address = fields[4];
+    let callee_address = u64::from_str_radix(fields[4].trim_start_matches("0x"), 16)?;
 
-    let func_name: String = callee_address.to_string();
+    let func_name: String = format!("0x{:x}", callee_address);
 
     let event_type = match action {
         "enter" => chrome::EventType::DurationBegin,
@@ -88,28 +99,47 @@ fn parse_line_to_event(line: &str) -> Result<chrome::Event> {
         duration: Duration::from_millis(0),
         event_type,
         name: func_name,
-        process_id: 1234,
-        thread_id: tid,
+        // NOTE: text format does not have pid, so tid is used instead (overwritten by caller)
+        process_id: tid.into(),
+        thread_id: tid.into(),
         instant_scope: None,
         scope: None,
         id: None,
         timestamp,
+        stack_frame: None,
     };
-    Ok(event)
+    Ok(Record {
+        event,
+        address: Some(callee_address),
+    })
 }
 
-fn parse_text_buffer(buffer: Vec<u8>) -> Result<Vec<chrome::Event>> {
-    let mut events: Vec<chrome::Event> = Vec::with_capacity(10);
-    let mut cur = Cursor::new(buffer);
+pub struct TextEventIter<R: BufRead> {
+    reader: R,
+    line: String,
+}
 
-    let mut line = String::new();
-    while cur.read_line(&mut line).unwrap() > 0 {
-        // debug!("iftracer input line: {:?}", line);
-        let event = parse_line_to_event(&line)?;
-        events.push(event);
-        line.clear();
+impl<R: BufRead> TextEventIter<R> {
+    pub fn new(reader: R) -> TextEventIter<R> {
+        TextEventIter {
+            reader,
+            line: String::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TextEventIter<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.line.clear();
+        match self.reader.read_line(&mut self.line) {
+            Ok(0) => None,
+            // debug!("iftracer input line: {:?}", line);
+            Ok(_) => Some(parse_line_to_record(&self.line)),
+            Err(e) => Some(Err(e.into())),
+        }
     }
-    Ok(events)
 }
 
 #[derive(PartialEq, FromPrimitive, ToPrimitive)]
@@ -146,39 +176,61 @@ fn update_to_complete_event(event: &mut chrome::Event, end_timestamp: Duration)
     if duration == zero_duration {
         let virtual_duration = Duration::from_nanos(200);
         duration = virtual_duration;
-        let event_args = event.args.get_or_insert(HashMap::new());
-        event_args.insert(String::from("virtual_duration"), String::from("true"));
+        event.set_arg("virtual_duration", true);
     }
     event.duration = duration;
     event.event_type = chrome::EventType::Complete;
 }
 
-fn read_text_form_binary(cur: &mut std::io::Cursor<Vec<u8>>) -> String {
-    let text_size = cur.read_u32::<LittleEndian>().unwrap();
+fn read_text_form_binary<R: Read>(reader: &mut R) -> Result<String> {
+    let text_size = reader.read_u32::<LittleEndian>()?;
     let mut text_buf = vec![0; text_size as usize];
-    cur.read_exact(&mut text_buf).unwrap();
+    reader.read_exact(&mut text_buf)?;
     let text_align = 4;
     let dummy_padding_size = (((text_size) + (text_align - 1)) & !(text_align - 1)) - text_size;
-    cur.seek(SeekFrom::Current(dummy_padding_size as i64))
-        .unwrap();
-    String::from_utf8(text_buf).unwrap()
+    let mut padding = [0u8; 4];
+    reader.read_exact(&mut padding[..dummy_padding_size as usize])?;
+    Ok(String::from_utf8(text_buf)?)
 }
 
-fn parse_binary_buffer(buffer: Vec<u8>, bit32_flag: bool) -> Result<Vec<chrome::Event>> {
-    let mut events: Vec<chrome::Event> = Vec::with_capacity(10);
-    let mut cur = Cursor::new(buffer);
-    let cur_len = cur.get_ref().len();
-    let mut event_stack = Vec::<chrome::Event>::new();
+// NOTE: memory usage is bounded by the depth of call stack, not by the length of trace
+pub struct BinaryEventIter<R: Read> {
+    reader: R,
+    bit32_flag: bool,
+    pid: u32,
+    tid: u32,
+    pre_timestamp: Duration,
+    record_stack: Vec<Record>,
+    finished: bool,
+}
+
+impl<R: Read> BinaryEventIter<R> {
+    pub fn new(mut reader: R, bit32_flag: bool) -> Result<BinaryEventIter<R>> {
+        let base_timestamp = Duration::from_micros(reader.read_u64::<LittleEndian>()?);
+        let pid = reader.read_i32::<LittleEndian>()? as u32;
+        let tid = reader.read_i32::<LittleEndian>()? as u32;
+        Ok(BinaryEventIter {
+            reader,
+            bit32_flag,
+            pid,
+            tid,
+            pre_timestamp: base_timestamp,
+            record_stack: Vec::new(),
+            finished: false,
+        })
+    }
 
-    let base_timestamp = Duration::from_micros(cur.read_u64::<LittleEndian>().unwrap());
-    let mut pre_timestamp = base_timestamp;
-    let pid = cur.read_i32::<LittleEndian>().unwrap() as u32;
-    let tid = cur.read_i32::<LittleEndian>().unwrap() as u32;
-    while (cur.position() as usize) < cur_len - 1 {
-        let mut timestamp_with_extra_flag = cur.read_u32::<LittleEndian>().unwrap();
+    // read one record, None at the end of file
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let (pid, tid) = (self.pid, self.tid);
+        let mut timestamp_with_extra_flag = match self.reader.read_u32::<LittleEndian>() {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
         if timestamp_with_extra_flag == 0 {
             log::warn!("get zero timestamp, maybe broken file");
-            break;
+            return Ok(None);
         }
         // sub offset which used to distinguish broken file or not
         let dummy_offset = 1;
@@ -187,34 +239,39 @@ fn parse_binary_buffer(buffer: Vec<u8>, bit32_flag: bool) -> Result<Vec<chrome::
         let mut timestamp =
             Duration::from_micros((timestamp_with_extra_flag & !((0x3) << (32 - 2))) as u64);
 
-        timestamp += pre_timestamp;
+        timestamp += self.pre_timestamp;
+        self.pre_timestamp = timestamp;
 
-        let event: chrome::Event = match extra_flag {
+        let record = match extra_flag {
             ExtraFlag::NormalEnter => {
-                let func_addr = if !bit32_flag {
-                    cur.read_u64::<LittleEndian>().unwrap()
+                let func_addr = if !self.bit32_flag {
+                    self.reader.read_u64::<LittleEndian>()?
                 } else {
-                    cur.read_u32::<LittleEndian>().unwrap() as u64
+                    self.reader.read_u32::<LittleEndian>()? as u64
                 };
                 // debug!("enter, func_addr = {:#02x}", func_addr);
-                chrome::Event {
+                let event = chrome::Event {
                     args: None,
                     category: String::from("call"),
                     duration: Duration::from_millis(0),
                     event_type: chrome::EventType::DurationBegin,
                     name: String::from("0x") + &format!("{:x}", func_addr),
-                    process_id: pid,
-                    thread_id: tid,
+                    process_id: pid.into(),
+                    thread_id: tid.into(),
                     instant_scope: None,
                     scope: None,
                     id: None,
                     timestamp,
+                    stack_frame: None,
+                };
+                Record {
+                    event,
+                    address: Some(func_addr),
                 }
             }
             ExtraFlag::ExtendEnter => {
                 // debug!("internal or external enter, func_addr");
-                let extend_type: ExtendType =
-                    FromPrimitive::from_u32(cur.read_u32::<LittleEndian>().unwrap()).unwrap();
+                let extend_type = read_extend_type(&mut self.reader)?;
                 let event_type = chrome::EventType::from(extend_type);
                 let mut event = chrome::Event {
                     args: None,
@@ -222,42 +279,47 @@ fn parse_binary_buffer(buffer: Vec<u8>, bit32_flag: bool) -> Result<Vec<chrome::
                     duration: Duration::from_millis(0),
                     event_type,
                     name: String::from(""),
-                    process_id: pid,
-                    thread_id: tid,
+                    process_id: pid.into(),
+                    thread_id: tid.into(),
                     instant_scope: None,
                     scope: None,
                     id: None,
                     timestamp,
+                    stack_frame: None,
                 };
                 if event_type == chrome::EventType::AsyncNestableStart {
-                    event.name = read_text_form_binary(&mut cur);
+                    event.name = read_text_form_binary(&mut self.reader)?;
                     event.id = Some(event.name.clone());
                     event.scope = Some(tid.to_string());
                 }
-                event
+                Record {
+                    event,
+                    address: None,
+                }
             }
             ExtraFlag::NormalExit => {
                 // debug!("internal or normal exit");
-                let mut event = event_stack.pop().unwrap();
-                update_to_complete_event(&mut event, timestamp);
-                if event.name == "" {
+                let mut record = self.pop_record()?;
+                let event = &mut record.event;
+                update_to_complete_event(event, timestamp);
+                if event.name.is_empty() {
                     event.category = String::from("internal");
                     event.name = String::from("[internal]");
                 }
-                event
+                record
             }
             ExtraFlag::ExtendExit => {
                 // debug!("external exit");
-                let extend_type: ExtendType =
-                    FromPrimitive::from_u32(cur.read_u32::<LittleEndian>().unwrap()).unwrap();
+                let extend_type = read_extend_type(&mut self.reader)?;
                 let event_type = chrome::EventType::from(extend_type);
-                let text = read_text_form_binary(&mut cur);
+                let text = read_text_form_binary(&mut self.reader)?;
                 if event_type == chrome::EventType::DurationEnd {
-                    let mut event = event_stack.pop().unwrap();
+                    let mut record = self.pop_record()?;
+                    let event = &mut record.event;
                     event.category = String::from("external");
                     event.name = text;
-                    update_to_complete_event(&mut event, timestamp);
-                    event
+                    update_to_complete_event(event, timestamp);
+                    record
                 } else {
                     let instant_scope = if event_type == chrome::EventType::Instant {
                         Some(chrome::InstantScope::Global)
@@ -274,48 +336,89 @@ fn parse_binary_buffer(buffer: Vec<u8>, bit32_flag: bool) -> Result<Vec<chrome::
                     } else {
                         None
                     };
-                    chrome::Event {
+                    let event = chrome::Event {
                         args: None,
                         category: String::from("extend"),
                         duration: Duration::from_millis(0),
                         event_type,
                         name: text,
-                        process_id: pid,
-                        thread_id: tid,
+                        process_id: pid.into(),
+                        thread_id: tid.into(),
                         instant_scope,
-                        scope: scope,
-                        id: id,
+                        scope,
+                        id,
                         timestamp,
+                        stack_frame: None,
+                    };
+                    Record {
+                        event,
+                        address: None,
                     }
                 }
             }
         };
-        // if timestamp is same chrome tracing viewer doesn't show the item,
-        // so add virtual duration to end timestamp
-        match event.event_type {
-            chrome::EventType::DurationBegin => {
-                event_stack.push(event);
-            }
-            chrome::EventType::AsyncNestableStart
-            | chrome::EventType::AsyncNestableEnd
-            | chrome::EventType::Instant
-            | chrome::EventType::Complete => {
-                events.push(event);
-            }
-            _ => {
-                return Err(anyhow!("invalid event type '{:?}'", event.event_type));
-            }
-        }
-        pre_timestamp = timestamp;
+        Ok(Some(record))
     }
-    if event_stack.len() > 0 {
-        log::warn!(
-            "parsed event stack size is {}, maybe broken file",
-            event_stack.len()
-        );
-        for event in event_stack {
-            events.push(event);
+
+    fn pop_record(&mut self) -> Result<Record> {
+        self.record_stack
+            .pop()
+            .ok_or_else(|| anyhow!("exit record without enter record, maybe broken file"))
+    }
+}
+
+fn read_extend_type<R: Read>(reader: &mut R) -> Result<ExtendType> {
+    let value = reader.read_u32::<LittleEndian>()?;
+    FromPrimitive::from_u32(value).ok_or_else(|| anyhow!("invalid extend type '{}'", value))
+}
+
+impl<R: Read> Iterator for BinaryEventIter<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let record = match self.read_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    self.finished = true;
+                    if !self.record_stack.is_empty() {
+                        log::warn!(
+                            "parsed event stack size is {}, maybe broken file",
+                            self.record_stack.len()
+                        );
+                        // NOTE: output remaining events from the bottom of the stack
+                        self.record_stack.reverse();
+                    }
+                    break;
+                }
+                Err(e) => {
+                    self.finished = true;
+                    self.record_stack.clear();
+                    return Some(Err(e));
+                }
+            };
+            // if timestamp is same chrome tracing viewer doesn't show the item,
+            // so add virtual duration to end timestamp
+            match record.event.event_type {
+                chrome::EventType::DurationBegin => {
+                    self.record_stack.push(record);
+                }
+                chrome::EventType::AsyncNestableStart
+                | chrome::EventType::AsyncNestableEnd
+                | chrome::EventType::Instant
+                | chrome::EventType::Complete => {
+                    return Some(Ok(record));
+                }
+                _ => {
+                    self.finished = true;
+                    self.record_stack.clear();
+                    return Some(Err(anyhow!(
+                        "invalid event type '{:?}'",
+                        record.event.event_type
+                    )));
+                }
+            }
         }
+        self.record_stack.pop().map(Ok)
     }
-    Ok(events)
 }
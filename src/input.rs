@@ -0,0 +1,32 @@
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use flate2::bufread::MultiGzDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// '-' means stdin, and gzip or zstd compressed input is detected by magic number
+pub fn open(filepath: &Path) -> Result<Box<dyn BufRead>> {
+    let mut reader: Box<dyn BufRead> = if filepath.as_os_str() == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        let file = File::open(filepath)
+            .with_context(|| format!("open(): Failed to open file {:?}", filepath))?;
+        Box::new(BufReader::new(file))
+    };
+    let head = reader.fill_buf()?;
+    if head.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if head.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(
+            reader,
+        )?)))
+    } else {
+        Ok(reader)
+    }
+}
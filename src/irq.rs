@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::chrome;
+use crate::counter_window::CounterWindow;
+use crate::perf_script::Record;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+enum IrqKind {
+    Irq,
+    Softirq,
+}
+
+impl IrqKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IrqKind::Irq => "irq",
+            IrqKind::Softirq => "softirq",
+        }
+    }
+}
+
+// 'vec=1 [action=TIMER]' -> value of 'action'
+fn arg_value<'a>(args: &'a str, key: &str) -> Option<&'a str> {
+    args.split_whitespace()
+        .map(|token| token.trim_start_matches('[').trim_end_matches(']'))
+        .filter_map(|token| token.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+fn irq_track(cpu: u32, kind: IrqKind) -> (chrome::TrackId, chrome::TrackId) {
+    (
+        "CPU".into(),
+        format!("CPU {} {}", cpu, kind.as_str()).into(),
+    )
+}
+
+#[derive(Default)]
+struct IrqStats {
+    name: String,
+    count: u64,
+    total_time: Duration,
+    // count in the current counter interval
+    window_count: u64,
+}
+
+// irq:irq_handler_entry/exit and irq:softirq_entry/exit -> slices of per-CPU tracks and counters
+pub struct IrqTracker {
+    counter_window: CounterWindow,
+    // irq number -> name of irq_handler_entry
+    irq_names: HashMap<u32, String>,
+    // (cpu, kind) -> entered slices (irq may be nested)
+    open_slices: HashMap<(u32, IrqKind), Vec<chrome::Event>>,
+    stats: BTreeMap<(IrqKind, u32), IrqStats>,
+    first_timestamp: Option<Duration>,
+}
+
+impl IrqTracker {
+    pub fn new(counter_interval: Duration) -> IrqTracker {
+        IrqTracker {
+            counter_window: CounterWindow::new(counter_interval),
+            irq_names: HashMap::new(),
+            open_slices: HashMap::new(),
+            stats: BTreeMap::new(),
+            first_timestamp: None,
+        }
+    }
+
+    fn label(&self, kind: IrqKind, id: u32, name: Option<&str>) -> String {
+        let name = match (kind, name) {
+            (_, Some(name)) => name,
+            (IrqKind::Irq, None) => self
+                .irq_names
+                .get(&id)
+                .map(|name| name.as_str())
+                .unwrap_or("unknown"),
+            (IrqKind::Softirq, None) => "unknown",
+        };
+        format!("{}({})", name, id)
+    }
+
+    // returns false if the record is not an irq event (or broken)
+    pub fn convert(&mut self, record: &Record, events: &mut Vec<chrome::Event>) -> bool {
+        self.first_timestamp.get_or_insert(record.timestamp);
+        let (kind, entry) = match record.event.as_str() {
+            "irq:irq_handler_entry" => (IrqKind::Irq, true),
+            "irq:irq_handler_exit" => (IrqKind::Irq, false),
+            "irq:softirq_entry" => (IrqKind::Softirq, true),
+            "irq:softirq_exit" => (IrqKind::Softirq, false),
+            "irq:softirq_raise" => {
+                let (cpu, id) = match (record.cpu, arg_value(&record.args, "vec")) {
+                    (Some(cpu), Some(id)) => (cpu, id),
+                    _ => return false,
+                };
+                let id = match id.parse::<u32>() {
+                    Ok(id) => id,
+                    Err(_) => return false,
+                };
+                let label = self.label(IrqKind::Softirq, id, arg_value(&record.args, "action"));
+                let mut event = self.new_event(
+                    &format!("raise {}", label),
+                    IrqKind::Softirq,
+                    cpu,
+                    record.timestamp,
+                );
+                event.event_type = chrome::EventType::Instant;
+                event.instant_scope = Some(chrome::InstantScope::Thread);
+                events.push(event);
+                return true;
+            }
+            _ => return false,
+        };
+        let id_key = match kind {
+            IrqKind::Irq => "irq",
+            IrqKind::Softirq => "vec",
+        };
+        let (cpu, id) = match (
+            record.cpu,
+            arg_value(&record.args, id_key).and_then(|id| id.parse::<u32>().ok()),
+        ) {
+            (Some(cpu), Some(id)) => (cpu, id),
+            _ => {
+                log::warn!("broken irq event '{} {}'", record.event, record.args);
+                return false;
+            }
+        };
+        let name = match kind {
+            IrqKind::Irq => arg_value(&record.args, "name"),
+            IrqKind::Softirq => arg_value(&record.args, "action"),
+        };
+        if let (IrqKind::Irq, Some(name)) = (kind, name) {
+            self.irq_names.insert(id, name.to_string());
+        }
+        let label = self.label(kind, id, name);
+        if entry {
+            let event = self.new_event(&label, kind, cpu, record.timestamp);
+            self.open_slices.entry((cpu, kind)).or_default().push(event);
+            return true;
+        }
+        let open_slice = self.open_slices.get_mut(&(cpu, kind)).and_then(|stack| {
+            stack
+                .iter()
+                .rposition(|event| event.name == label)
+                .map(|i| stack.remove(i))
+        });
+        let mut event = match open_slice {
+            Some(event) => event,
+            // the beginning of the trace
+            None => {
+                let first_timestamp = self.first_timestamp.unwrap_or(record.timestamp);
+                let mut event = self.new_event(&label, kind, cpu, first_timestamp);
+                event.set_arg("truncated", "begin");
+                event
+            }
+        };
+        event.duration = record.timestamp.saturating_sub(event.timestamp);
+        if let Some(ret) = arg_value(&record.args, "ret") {
+            event.set_arg("ret", ret);
+        }
+        let stats = self.stats.entry((kind, id)).or_default();
+        stats.name = label;
+        stats.count += 1;
+        stats.total_time += event.duration;
+        stats.window_count += 1;
+        events.push(event);
+        true
+    }
+
+    fn new_event(&self, name: &str, kind: IrqKind, cpu: u32, timestamp: Duration) -> chrome::Event {
+        let track = irq_track(cpu, kind);
+        chrome::Event {
+            name: name.to_string(),
+            category: kind.as_str().to_string(),
+            event_type: chrome::EventType::Complete,
+            timestamp,
+            duration: Duration::from_micros(0),
+            process_id: track.0,
+            thread_id: track.1,
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+        .with_arg("cpu", cpu)
+    }
+
+    fn counter_event(name: &str, timestamp: Duration, key: &str, value: f64) -> chrome::Event {
+        chrome::Event {
+            name: name.to_string(),
+            category: String::from("irq"),
+            event_type: chrome::EventType::Counter,
+            timestamp,
+            duration: Duration::from_micros(0),
+            process_id: "IRQ".into(),
+            thread_id: "IRQ".into(),
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+        .with_arg(key, value)
+    }
+
+    // rate of the window which starts at window_start and total time at the end of the window
+    fn write_counters(
+        &mut self,
+        window_start: Duration,
+        window: Duration,
+        events: &mut Vec<chrome::Event>,
+    ) {
+        if window.is_zero() {
+            return;
+        }
+        for ((kind, _), stats) in self.stats.iter_mut() {
+            events.push(Self::counter_event(
+                &format!("{}: {} rate", kind.as_str(), stats.name),
+                window_start,
+                "per_sec",
+                stats.window_count as f64 / window.as_secs_f64(),
+            ));
+            events.push(Self::counter_event(
+                &format!("{}: {} total time", kind.as_str(), stats.name),
+                window_start + window,
+                "ms",
+                stats.total_time.as_secs_f64() * 1000.0,
+            ));
+            stats.window_count = 0;
+        }
+    }
+
+    // write counters of the intervals which end before timestamp
+    pub fn flush(&mut self, timestamp: Duration, events: &mut Vec<chrome::Event>) {
+        for (window_start, window) in self.counter_window.advance(timestamp) {
+            self.write_counters(window_start, window, events);
+        }
+    }
+
+    // irqs which do not exit last until the end of trace
+    pub fn finish(&mut self, last_timestamp: Duration, events: &mut Vec<chrome::Event>) {
+        for (_, stack) in self.open_slices.drain() {
+            for mut event in stack {
+                event.duration = last_timestamp.saturating_sub(event.timestamp);
+                event.set_arg("unfinished", true);
+                events.push(event);
+            }
+        }
+        if let Some((window_start, window)) = self.counter_window.last(last_timestamp) {
+            self.write_counters(window_start, window, events);
+        }
+        for ((kind, _), stats) in &self.stats {
+            log::info!(
+                "{} {}: {} times, total {:?}",
+                kind.as_str(),
+                stats.name,
+                stats.count,
+                stats.total_time
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf_script::parse_header;
+
+    fn convert(tracker: &mut IrqTracker, headers: &[&str]) -> Vec<chrome::Event> {
+        let mut events = Vec::new();
+        for header in headers {
+            let record = parse_header(header).unwrap();
+            tracker.flush(record.timestamp, &mut events);
+            tracker.convert(&record, &mut events);
+        }
+        events
+    }
+
+    #[test]
+    fn irq_name_of_exit() {
+        let mut tracker = IrqTracker::new(Duration::from_secs(0));
+        let events = convert(
+            &mut tracker,
+            &[
+                "swapper     0 [002]  10.000000: irq:irq_handler_entry: irq=17 name=twd",
+                "swapper     0 [002]  10.100000: irq:irq_handler_exit: irq=17 ret=handled",
+            ],
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "twd(17)");
+        assert_eq!(events[0].event_type, chrome::EventType::Complete);
+        assert_eq!(events[0].duration, Duration::from_millis(100));
+        assert_eq!(events[0].thread_id, "CPU 2 irq".into());
+        assert_eq!(events[0].arg("ret"), Some(&"handled".into()));
+    }
+
+    #[test]
+    fn nested_irqs() {
+        let mut tracker = IrqTracker::new(Duration::from_secs(0));
+        let events = convert(
+            &mut tracker,
+            &[
+                "swapper     0 [000]  10.000000: irq:irq_handler_entry: irq=17 name=twd",
+                "swapper     0 [000]  10.100000: irq:irq_handler_entry: irq=30 name=eth0",
+                "swapper     0 [000]  10.200000: irq:irq_handler_exit: irq=30 ret=handled",
+                "swapper     0 [000]  10.300000: irq:irq_handler_exit: irq=17 ret=handled",
+            ],
+        );
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "eth0(30)");
+        assert_eq!(events[0].timestamp, Duration::from_millis(10100));
+        assert_eq!(events[0].duration, Duration::from_millis(100));
+        assert_eq!(events[1].name, "twd(17)");
+        assert_eq!(events[1].timestamp, Duration::from_secs(10));
+        assert_eq!(events[1].duration, Duration::from_millis(300));
+        assert_eq!(events[0].thread_id, events[1].thread_id);
+    }
+
+    #[test]
+    fn exit_without_entry() {
+        let mut tracker = IrqTracker::new(Duration::from_secs(0));
+        let events = convert(
+            &mut tracker,
+            &[
+                "swapper     0 [001]  10.000000: sched:sched_wakeup: comm=a pid=1",
+                "swapper     0 [001]  10.500000: irq:softirq_exit: vec=1 [action=TIMER]",
+            ],
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "TIMER(1)");
+        assert_eq!(events[0].category, "softirq");
+        assert_eq!(events[0].timestamp, Duration::from_secs(10));
+        assert_eq!(events[0].duration, Duration::from_millis(500));
+        assert_eq!(events[0].arg("truncated"), Some(&"begin".into()));
+    }
+
+    #[test]
+    fn counters() {
+        let mut tracker = IrqTracker::new(Duration::from_secs(1));
+        let mut events = convert(
+            &mut tracker,
+            &[
+                "swapper     0 [000]  10.000000: irq:irq_handler_entry: irq=17 name=twd",
+                "swapper     0 [000]  10.250000: irq:irq_handler_exit: irq=17 ret=handled",
+                "swapper     0 [000]  10.500000: irq:irq_handler_entry: irq=17 name=twd",
+                "swapper     0 [000]  10.750000: irq:irq_handler_exit: irq=17 ret=handled",
+                "swapper     0 [000]  11.200000: sched:sched_wakeup: comm=a pid=1",
+            ],
+        );
+        tracker.finish(Duration::from_millis(11500), &mut events);
+        let counters: Vec<_> = events
+            .iter()
+            .filter(|event| event.event_type == chrome::EventType::Counter)
+            .map(|event| {
+                let (key, value) = event.args.as_ref().unwrap().iter().next().unwrap();
+                (
+                    event.name.as_str(),
+                    event.timestamp,
+                    key.as_str(),
+                    value.as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counters,
+            vec![
+                ("irq: twd(17) rate", Duration::from_secs(10), "per_sec", 2.0),
+                (
+                    "irq: twd(17) total time",
+                    Duration::from_secs(11),
+                    "ms",
+                    500.0
+                ),
+                ("irq: twd(17) rate", Duration::from_secs(11), "per_sec", 0.0),
+                (
+                    "irq: twd(17) total time",
+                    Duration::from_millis(11500),
+                    "ms",
+                    500.0
+                ),
+            ]
+        );
+    }
+}
@@ -1,8 +1,24 @@
+pub mod bpftrace_sched;
 pub mod chrome;
+pub mod counter_window;
 pub mod file;
+pub mod fxt;
+pub mod hash;
 pub mod iftrace;
+pub mod input;
+pub mod irq;
+pub mod ltrace;
 pub mod objdump;
+pub mod output;
+pub mod perf_sched;
+pub mod perf_script;
+pub mod perfetto;
 pub mod proc_maps;
+pub mod process_info;
+pub mod sched_stats;
+pub mod strace;
+pub mod symbol_cache;
+pub mod symbolizer;
 
 #[macro_use]
 extern crate serde_derive;
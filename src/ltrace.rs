@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chrome;
+use crate::input;
+
+// '1624633549.138701' (-ttt), '12:34:56.138701' (-tt) or '12:34:56' (-t)
+pub(crate) fn parse_timestamp(s: &str) -> Option<Duration> {
+    if s.contains(':') {
+        let fields: Vec<&str> = s.split(':').collect();
+        if fields.len() != 3 {
+            return None;
+        }
+        let hours = fields[0].parse::<u64>().ok()?;
+        let minutes = fields[1].parse::<u64>().ok()?;
+        let seconds = parse_seconds(fields[2])?;
+        return Some(Duration::from_secs(hours * 3600 + minutes * 60) + seconds);
+    }
+    parse_seconds(s)
+}
+
+// '1.000123' -> 1s 123us
+pub(crate) fn parse_seconds(s: &str) -> Option<Duration> {
+    let (secs, frac) = match s.split_once('.') {
+        Some((secs, frac)) => (secs, frac),
+        None => (s, ""),
+    };
+    if secs.is_empty() || !secs.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if !frac.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut nanos = 0u32;
+    for (i, c) in frac.bytes().take(9).enumerate() {
+        nanos += (c - b'0') as u32 * 10u32.pow(8 - i as u32);
+    }
+    Some(Duration::new(secs.parse::<u64>().ok()?, nanos))
+}
+
+// split '[pid 1234] ' (stdout) or '1234 ' (-f -o) and timestamp prefix of ltrace/strace line
+// returns (tid, timestamp, rest of line)
+pub(crate) fn split_line_header(line: &str) -> Option<(Option<u32>, Duration, &str)> {
+    let mut rest = line.trim_start();
+    let mut tid = None;
+    if let Some(s) = rest.strip_prefix("[pid") {
+        let (id, s) = s.trim_start().split_once(']')?;
+        tid = Some(id.trim().parse::<u32>().ok()?);
+        rest = s.trim_start();
+    }
+    let (first, s) = rest.split_once(' ')?;
+    if tid.is_none() && !first.is_empty() && first.bytes().all(|c| c.is_ascii_digit()) {
+        tid = Some(first.parse::<u32>().ok()?);
+        rest = s.trim_start();
+    }
+    let (first, s) = match rest.split_once(' ') {
+        Some((first, s)) => (first, s),
+        None => (rest, ""),
+    };
+    let timestamp = parse_timestamp(first)?;
+    Some((tid, timestamp, s))
+}
+
+// '<0.000123>'
+pub(crate) fn strip_elapsed_time(s: &str) -> (&str, Option<Duration>) {
+    let s = s.trim_end();
+    if s.ends_with('>') {
+        if let Some(start) = s.rfind('<') {
+            if let Some(elapsed_time) = parse_seconds(&s[start + 1..s.len() - 1]) {
+                return (s[..start].trim_end(), Some(elapsed_time));
+            }
+        }
+    }
+    (s, None)
+}
+
+// split 'ARGS)       = RETVAL' (return value is aligned by spaces)
+pub(crate) fn split_return_value(s: &str) -> (&str, Option<&str>) {
+    if let Some((arguments, retval)) = s.rsplit_once(" = ") {
+        if let Some(arguments) = arguments.trim_end().strip_suffix(')') {
+            return (arguments, Some(retval.trim()));
+        }
+    }
+    (s.trim_end().trim_end_matches(')'), None)
+}
+
+// return value as number if possible
+pub(crate) fn return_value(s: &str) -> serde_json::Value {
+    match s.parse::<i64>() {
+        Ok(v) => serde_json::Value::from(v),
+        Err(_) => serde_json::Value::from(s),
+    }
+}
+
+// split 'X' events into 'B' and 'E' events sorted by timestamp
+// NOTE: 'E' comes before 'B' at the same timestamp and outer 'B' comes first to keep nesting
+pub(crate) fn begin_end_events(events: Vec<chrome::Event>) -> Vec<chrome::Event> {
+    let mut keyed_events = Vec::with_capacity(events.len() * 2);
+    for (order, event) in events.into_iter().enumerate() {
+        let end_timestamp = event.timestamp + event.duration;
+        let begin_key = chrome::slice_begin_key(event.timestamp, Some(end_timestamp), order);
+        if event.event_type != chrome::EventType::Complete {
+            keyed_events.push((begin_key, event));
+            continue;
+        }
+        let end_key = chrome::slice_end_key(Some(event.timestamp), end_timestamp, order);
+        let mut begin = event.clone();
+        begin.event_type = chrome::EventType::DurationBegin;
+        begin.duration = Duration::from_micros(0);
+        let end = chrome::Event {
+            event_type: chrome::EventType::DurationEnd,
+            timestamp: end_timestamp,
+            duration: Duration::from_micros(0),
+            args: None,
+            ..event
+        };
+        keyed_events.push((begin_key, begin));
+        keyed_events.push((end_key, end));
+    }
+    keyed_events.sort_by_key(|(key, _)| *key);
+    keyed_events.into_iter().map(|(_, event)| event).collect()
+}
+
+// position of '(' of the argument list in 'NAME(ARGUMENTS' whose closing parenthesis is removed
+// NOTE: parentheses are matched from the end because C++ names of -C have parentheses
+// e.g. 'operator new(unsigned long)(16', and parentheses in string arguments are ignored
+pub(crate) fn argument_list_start(call: &str) -> Option<usize> {
+    let bytes = call.as_bytes();
+    let is_quote = |i: usize| {
+        bytes[i] == b'"' && bytes[..i].iter().rev().take_while(|c| **c == b'\\').count() % 2 == 0
+    };
+    let mut depth = 1;
+    let mut in_string = false;
+    for i in (0..bytes.len()).rev() {
+        if is_quote(i) {
+            in_string = !in_string;
+        } else if !in_string && bytes[i] == b')' {
+            depth += 1;
+        } else if !in_string && bytes[i] == b'(' {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    // 'X' events
+    Complete,
+    // 'B' and 'E' events
+    BeginEnd,
+}
+
+// ltrace -ttt -T -f [-S] [-e '*'] [-i] [-n N] [-C] output
+pub struct LtraceParser {
+    mode: OutputMode,
+    default_tid: u32,
+    // (tid, indentation of -n) -> calls which are '<unfinished ...>'
+    pending_calls: HashMap<(u32, usize), Vec<chrome::Event>>,
+    events: Vec<chrome::Event>,
+    last_timestamp: Duration,
+}
+
+impl LtraceParser {
+    // default_tid is used for the output without -f
+    pub fn new(mode: OutputMode, default_tid: u32) -> LtraceParser {
+        LtraceParser {
+            mode,
+            default_tid,
+            pending_calls: HashMap::new(),
+            events: Vec::new(),
+            last_timestamp: Duration::from_micros(0),
+        }
+    }
+
+    pub fn parse_file(&mut self, filepath: &Path) -> Result<()> {
+        let reader = input::open(filepath)?;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read file {:?}", filepath))?;
+            if let Err(e) = self.parse_line(&line) {
+                log::warn!("{:?}:{}: {}", filepath, i + 1, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn new_event(
+        &self,
+        tid: u32,
+        timestamp: Duration,
+        name: &str,
+        category: &str,
+    ) -> chrome::Event {
+        chrome::Event {
+            name: name.to_string(),
+            category: category.to_string(),
+            event_type: chrome::EventType::Complete,
+            timestamp,
+            duration: Duration::from_micros(0),
+            // NOTE: ltrace output does not have pid, so tid is used instead
+            process_id: tid.into(),
+            thread_id: tid.into(),
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let (tid, timestamp, rest) =
+            split_line_header(line).ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        let tid = tid.unwrap_or(self.default_tid);
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+
+        // -i: '[0x7f0123456789] '
+        let mut instruction_pointer = None;
+        let mut rest = rest;
+        if let Some(s) = rest.strip_prefix('[') {
+            if let Some((ip, s)) = s.split_once(']') {
+                instruction_pointer = Some(ip.to_string());
+                rest = s.strip_prefix(' ').unwrap_or(s);
+            }
+        }
+        // -n: indentation by the depth of nested calls (the resumed line has the same indentation)
+        let indent = rest.len() - rest.trim_start().len();
+        let rest = rest.trim_start();
+
+        if let Some(s) = rest.strip_prefix("+++") {
+            let name = s.trim_end_matches("+++").trim();
+            let mut event = self.new_event(tid, timestamp, name, "process");
+            event.event_type = chrome::EventType::Instant;
+            event.instant_scope = Some(chrome::InstantScope::Thread);
+            self.events.push(event);
+            return Ok(());
+        }
+        if let Some(s) = rest.strip_prefix("---") {
+            let signal = s.trim_end_matches("---").trim();
+            let name = signal.split_whitespace().next().unwrap_or(signal);
+            let mut event = self.new_event(tid, timestamp, name, "signal");
+            event.event_type = chrome::EventType::Instant;
+            event.instant_scope = Some(chrome::InstantScope::Thread);
+            event.set_arg("signal", signal);
+            self.events.push(event);
+            return Ok(());
+        }
+        if let Some(s) = rest.strip_prefix("<...") {
+            // '<... fork resumed> ) = 0 <0.000300>'
+            let (name, s) = s
+                .split_once(" resumed>")
+                .ok_or_else(|| anyhow!("invalid resumed line '{}'", line))?;
+            let name = name.trim();
+            let (s, elapsed_time) = strip_elapsed_time(s);
+            let (_, retval) = split_return_value(s);
+            self.resume_call(tid, indent, timestamp, name, retval, elapsed_time);
+            return Ok(());
+        }
+
+        // 'libc.so.6->malloc(16) = 0x55d0c8e2a2a0 <0.000123>'
+        let (call, elapsed_time) = strip_elapsed_time(rest);
+        let (call, retval, suffix) = if let Some(call) = call.strip_suffix("<unfinished ...>") {
+            (call, None, Some("unfinished"))
+        } else if let Some(call) = call.strip_suffix("<no return ...>") {
+            (call, None, Some("no_return"))
+        } else {
+            let (call, retval) = split_return_value(call);
+            (call, retval, None)
+        };
+        let open = argument_list_start(call)
+            .filter(|open| *open > 0)
+            .ok_or_else(|| anyhow!("invalid call line '{}'", line))?;
+        let (library, name) = match call[..open].split_once("->") {
+            Some((library, name)) => (Some(library), name),
+            None => (None, &call[..open]),
+        };
+        let category = if name.starts_with("SYS_") {
+            "syscall"
+        } else {
+            "library"
+        };
+        let mut event = self.new_event(tid, timestamp, name, category);
+        if let Some(library) = library {
+            event.set_arg("library", library);
+        }
+        if let Some(instruction_pointer) = instruction_pointer {
+            event.set_arg("ip", instruction_pointer);
+        }
+        let arguments = &call[open + 1..];
+        match suffix {
+            Some("unfinished") => {
+                event.set_arg("arguments", arguments.trim_end().trim_end_matches(','));
+                self.pending_calls
+                    .entry((tid, indent))
+                    .or_default()
+                    .push(event);
+            }
+            Some(_) => {
+                event.set_arg("arguments", arguments.trim_end().trim_end_matches(','));
+                event.set_arg("no_return", true);
+                self.push_call(event);
+            }
+            None => {
+                event.set_arg("arguments", arguments);
+                if let Some(retval) = retval {
+                    event.set_arg("return_value", return_value(retval));
+                }
+                event.duration = elapsed_time.unwrap_or_default();
+                self.push_call(event);
+            }
+        }
+        Ok(())
+    }
+
+    // NOTE: the call of the same indentation is preferred (e.g. inner recursive call which never returns by longjmp),
+    // otherwise the latest call of the same name on the thread
+    fn pop_pending_call(&mut self, tid: u32, indent: usize, name: &str) -> Option<chrome::Event> {
+        let latest =
+            |stack: &Vec<chrome::Event>| stack.iter().rposition(|event| event.name == name);
+        let (key, i) = match self.pending_calls.get(&(tid, indent)).and_then(latest) {
+            Some(i) => ((tid, indent), i),
+            None => self
+                .pending_calls
+                .iter()
+                .filter(|((call_tid, _), _)| *call_tid == tid)
+                .filter_map(|(key, stack)| Some((*key, latest(stack)?)))
+                .max_by_key(|(key, i)| (self.pending_calls[key][*i].timestamp, key.1))?,
+        };
+        Some(self.pending_calls.get_mut(&key)?.remove(i))
+    }
+
+    fn resume_call(
+        &mut self,
+        tid: u32,
+        indent: usize,
+        timestamp: Duration,
+        name: &str,
+        retval: Option<&str>,
+        elapsed_time: Option<Duration>,
+    ) {
+        let mut event = match self.pop_pending_call(tid, indent, name) {
+            Some(mut event) => {
+                // NOTE: elapsed time is measured from the call
+                event.duration = match elapsed_time {
+                    Some(elapsed_time) => elapsed_time,
+                    None => timestamp.saturating_sub(event.timestamp),
+                };
+                event
+            }
+            None => {
+                log::warn!(
+                    "'{}' is resumed without unfinished call on tid {}",
+                    name,
+                    tid
+                );
+                let elapsed_time = elapsed_time.unwrap_or_default();
+                let mut event =
+                    self.new_event(tid, timestamp.saturating_sub(elapsed_time), name, "library");
+                event.duration = elapsed_time;
+                event
+            }
+        };
+        if let Some(retval) = retval {
+            event.set_arg("return_value", return_value(retval));
+        }
+        self.push_call(event);
+    }
+
+    fn push_call(&mut self, event: chrome::Event) {
+        self.events.push(event);
+    }
+
+    // calls which are not resumed last until the end of trace
+    pub fn finish(mut self) -> Vec<chrome::Event> {
+        let mut pending_calls: Vec<chrome::Event> = self
+            .pending_calls
+            .drain()
+            .flat_map(|(_, stack)| stack)
+            .collect();
+        pending_calls.sort_by_key(|event| event.timestamp);
+        for mut event in pending_calls {
+            event.duration = self.last_timestamp.saturating_sub(event.timestamp);
+            event.set_arg("unfinished", true);
+            self.push_call(event);
+        }
+        match self.mode {
+            OutputMode::Complete => self.events,
+            OutputMode::BeginEnd => begin_end_events(self.events),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases(events: &[chrome::Event]) -> Vec<(String, chrome::EventType, u128)> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    event.name.clone(),
+                    event.event_type,
+                    event.timestamp.as_micros() % 1000,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn begin_end_nested_same_timestamp() {
+        let mut parser = LtraceParser::new(OutputMode::BeginEnd, 1);
+        for line in [
+            "1000 1624633549.000010 outer(1 <unfinished ...>",
+            "1000 1624633549.000010   inner(2) = 0 <0.000005>",
+            "1000 1624633549.000015   next(3) = 0 <0.000000>",
+            "1000 1624633549.000020 <... outer resumed> ) = 0 <0.000010>",
+        ] {
+            parser.parse_line(line).unwrap();
+        }
+        let events = parser.finish();
+        use chrome::EventType::{DurationBegin as B, DurationEnd as E};
+        assert_eq!(
+            phases(&events),
+            vec![
+                ("outer".to_string(), B, 10),
+                ("inner".to_string(), B, 10),
+                ("inner".to_string(), E, 15),
+                ("next".to_string(), B, 15),
+                ("next".to_string(), E, 15),
+                ("outer".to_string(), E, 20),
+            ]
+        );
+        assert!(chrome::validate(&events).is_empty());
+    }
+
+    fn parse(lines: &[&str]) -> Vec<chrome::Event> {
+        let mut parser = LtraceParser::new(OutputMode::Complete, 1);
+        for line in lines {
+            parser.parse_line(line).unwrap();
+        }
+        parser.finish()
+    }
+
+    fn arg(event: &chrome::Event, key: &str) -> String {
+        match event.arg(key) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => String::new(),
+        }
+    }
+
+    #[test]
+    fn argument_list() {
+        assert_eq!(argument_list_start("malloc(16"), Some(6));
+        assert_eq!(
+            argument_list_start("operator new(unsigned long)(16"),
+            Some(27)
+        );
+        assert_eq!(argument_list_start("puts(\"a(b\\\")c(\""), Some(4));
+        assert_eq!(argument_list_start("f(g(1), (2)"), Some(1));
+        assert_eq!(argument_list_start("invalid"), None);
+    }
+
+    #[test]
+    fn cpp_names() {
+        let events = parse(&[
+            "1000 1624633549.000010 libstdc++.so.6->operator new(unsigned long)(16 <unfinished ...>",
+            "1000 1624633549.000011   libc.so.6->malloc(16) = 0x55d0c8e2a2a0 <0.000001>",
+            "1000 1624633549.000020 <... operator new(unsigned long) resumed> ) = 0x55d0c8e2a2a0 <0.000010>",
+            "1000 1624633549.000030 std::vector<int, std::allocator<int> >::push_back(int const&)(0x7ffd, 0x7ffc) = <void> <0.000001>",
+            "1000 1624633549.000040 puts(\"f(x) = (\") = 9 <0.000002>",
+        ]);
+        let calls: Vec<(&str, String, String, u128)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.name.as_str(),
+                    arg(event, "arguments"),
+                    arg(event, "return_value"),
+                    event.duration.as_micros(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("malloc", "16".to_string(), "0x55d0c8e2a2a0".to_string(), 1),
+                (
+                    "operator new(unsigned long)",
+                    "16".to_string(),
+                    "0x55d0c8e2a2a0".to_string(),
+                    10
+                ),
+                (
+                    "std::vector<int, std::allocator<int> >::push_back(int const&)",
+                    "0x7ffd, 0x7ffc".to_string(),
+                    "<void>".to_string(),
+                    1
+                ),
+                ("puts", "\"f(x) = (\"".to_string(), "9".to_string(), 2),
+            ]
+        );
+        assert_eq!(arg(&events[1], "library"), "libstdc++.so.6");
+    }
+
+    #[test]
+    fn indentation_nesting() {
+        // ltrace -n 2 -i: inner 'foo' never returns (e.g. longjmp)
+        let events = parse(&[
+            "1000 1624633549.000010 [0x401000] foo(1 <unfinished ...>",
+            "1000 1624633549.000011 [0x401100]   foo(2 <unfinished ...>",
+            "1000 1624633549.000012 [0x401200]     bar(3) = 0 <0.000001>",
+            "1000 1624633549.000020 [0x401000] <... foo resumed> ) = 0 <0.000010>",
+        ]);
+        let calls: Vec<(String, String, u128, String)> = events
+            .iter()
+            .map(|event| {
+                (
+                    arg(event, "arguments"),
+                    arg(event, "ip"),
+                    event.duration.as_micros(),
+                    arg(event, "unfinished"),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("3".to_string(), "0x401200".to_string(), 1, String::new()),
+                ("1".to_string(), "0x401000".to_string(), 10, String::new()),
+                (
+                    "2".to_string(),
+                    "0x401100".to_string(),
+                    9,
+                    "true".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn resumed_without_indentation() {
+        // recursive calls without -n are paired with the latest call
+        let events = parse(&[
+            "1000 1624633549.000010 foo(1 <unfinished ...>",
+            "1001 1624633549.000011 foo(3 <unfinished ...>",
+            "1000 1624633549.000012 foo(2 <unfinished ...>",
+            "1000 1624633549.000013 <... foo resumed> ) = 2 <0.000001>",
+            "1001 1624633549.000014 <... foo resumed> ) = 3 <0.000003>",
+            "1000 1624633549.000020 <... foo resumed> ) = 1 <0.000010>",
+        ]);
+        let calls: Vec<(String, String, String)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.thread_id.to_string(),
+                    arg(event, "arguments"),
+                    arg(event, "return_value"),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("1000".to_string(), "2".to_string(), "2".to_string()),
+                ("1001".to_string(), "3".to_string(), "3".to_string()),
+                ("1000".to_string(), "1".to_string(), "1".to_string()),
+            ]
+        );
+    }
+}
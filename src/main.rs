@@ -1,20 +1,71 @@
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
+use std::rc::Rc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use cpp_demangle::Symbol;
-use log::info;
+use log::{info, warn};
 use structopt::StructOpt;
 
+use x2trace::bpftrace_sched;
 use x2trace::chrome;
 use x2trace::file;
+use x2trace::fxt;
 use x2trace::iftrace;
+use x2trace::ltrace;
 use x2trace::objdump;
+use x2trace::output::OutputWriter;
+use x2trace::perf_sched;
+use x2trace::perf_script;
+use x2trace::perfetto;
 use x2trace::proc_maps;
+use x2trace::process_info;
+use x2trace::strace;
+use x2trace::symbol_cache;
+use x2trace::symbolizer;
+
+#[derive(StructOpt)]
+struct ProcessInfoOpts {
+    #[structopt(
+        long = "pid-comm-cmdline",
+        parse(from_os_str),
+        help = "csv filepath which has 'pid,comm,cmdline' header for process and thread names"
+    )]
+    pid_comm_cmdline: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "proc-snapshot",
+        parse(from_os_str),
+        help = "/proc snapshot directory for process and thread names (which has $PID/comm and $PID/task/$TID/comm)"
+    )]
+    proc_snapshot: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "process-name",
+        number_of_values = 1,
+        help = "Process name e.g. '1234=server'"
+    )]
+    process_names: Vec<String>,
+    #[structopt(
+        long = "thread-name",
+        number_of_values = 1,
+        help = "Thread name e.g. '1234=worker'"
+    )]
+    thread_names: Vec<String>,
+    #[structopt(
+        long = "process-sort-index",
+        number_of_values = 1,
+        help = "Process sort index e.g. '1234=-1'"
+    )]
+    process_sort_indexes: Vec<String>,
+    #[structopt(
+        long = "thread-sort-index",
+        number_of_values = 1,
+        help = "Thread sort index e.g. '1234=1'"
+    )]
+    thread_sort_indexes: Vec<String>,
+}
 
 #[derive(StructOpt)]
 struct IftracerCli {
@@ -24,16 +75,23 @@ struct IftracerCli {
         long = "bin",
         parse(from_os_str),
         default_value(""),
-        help = "Target binary filepath"
+        help = "Target binary filepath (with --proc-maps, used instead of the module which has same file name)"
     )]
     bin_filepath: std::path::PathBuf,
     #[structopt(
         long = "proc-maps",
         parse(from_os_str),
         default_value(""),
-        help = "Target /proc/$PID/maps dump filepath (for resolve runtime address)"
+        help = "Target /proc/$PID/maps dump filepath (for resolve runtime address of all executable modules)"
     )]
     proc_maps_filepath: std::path::PathBuf,
+    #[structopt(
+        long = "sysroot",
+        parse(from_os_str),
+        default_value("/"),
+        help = "Prefix of module paths in --proc-maps (for cross target trace)"
+    )]
+    sysroot: std::path::PathBuf,
     #[structopt(
         long = "base-address",
         default_value("0x0"),
@@ -42,19 +100,77 @@ struct IftracerCli {
     base_address: String,
     #[structopt(long = "text", help = "Deprecated option")]
     text_flag: bool,
+    #[structopt(
+        long = "pid",
+        help = "pid of text format trace (default: detected by --proc-snapshot or same as tid)"
+    )]
+    pid: Option<u32>,
     #[structopt(
         long = "bit",
         default_value("auto"),
-        help = "Target arch is 32bit or not"
+        help = "Target arch is 32bit or not (auto: detected from ELF header of --bin or main executable of --proc-maps)"
     )]
     bit: String,
     #[structopt(
         long = "function-file-location",
-        help = "Disable add function file location to output args field"
+        help = "Add function file location and inlined frames to output args field"
     )]
     function_file_location: bool,
     #[structopt(long = "no-demangle", help = "Disable demangle function name")]
     no_demangle: bool,
+    #[structopt(
+        long = "objdump",
+        help = "Resolve function name by objdump command instead of reading ELF symbol table (fallback)"
+    )]
+    objdump_flag: bool,
+    #[structopt(
+        long = "debug-dir",
+        parse(from_os_str),
+        number_of_values = 1,
+        help = "Separate debug info search directory for build-id and .gnu_debuglink (/usr/lib/debug is always searched)"
+    )]
+    debug_dirs: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "cache-dir",
+        parse(from_os_str),
+        help = "Symbol cache directory (default: $X2TRACE_CACHE_DIR, $XDG_CACHE_HOME/x2trace or ~/.cache/x2trace)"
+    )]
+    cache_dir: Option<std::path::PathBuf>,
+    #[structopt(long = "no-cache", help = "Disable symbol cache")]
+    no_cache: bool,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+#[derive(StructOpt)]
+enum CacheCli {
+    #[structopt(name = "build", about = "Prebuild symbol cache of binaries")]
+    Build {
+        #[structopt(parse(from_os_str), help = "Target binary filepaths")]
+        bin_filepaths: Vec<std::path::PathBuf>,
+        #[structopt(
+            long = "debug-dir",
+            parse(from_os_str),
+            number_of_values = 1,
+            help = "Separate debug info search directory for build-id and .gnu_debuglink (/usr/lib/debug is always searched)"
+        )]
+        debug_dirs: Vec<std::path::PathBuf>,
+        #[structopt(
+            long = "cache-dir",
+            parse(from_os_str),
+            help = "Symbol cache directory"
+        )]
+        cache_dir: Option<std::path::PathBuf>,
+    },
+    #[structopt(name = "purge", about = "Remove all symbol cache files")]
+    Purge {
+        #[structopt(
+            long = "cache-dir",
+            parse(from_os_str),
+            help = "Symbol cache directory"
+        )]
+        cache_dir: Option<std::path::PathBuf>,
+    },
 }
 
 #[derive(StructOpt)]
@@ -65,14 +181,254 @@ struct Cli {
         help = "Output tracing json file pretty or not"
     )]
     pretty: bool,
+    #[structopt(
+        short = "o",
+        long = "output",
+        parse(from_os_str),
+        default_value("out.json"),
+        global = true,
+        help = "Output filepath ('-' means stdout, '.gz' or '.zst' extension means compressed output)"
+    )]
+    output: std::path::PathBuf,
+    #[structopt(
+        long = "format",
+        global = true,
+        help = "Output format [json: JSON object format, json-array: JSON array format (without metadata), perfetto: perfetto protobuf format, fxt: Fuchsia trace format] (default: perfetto for '.perfetto-trace' or '.pftrace' extension, fxt for '.fxt' extension, otherwise json)"
+    )]
+    format: Option<OutputFormat>,
+    #[structopt(
+        long = "display-time-unit",
+        global = true,
+        help = "displayTimeUnit of JSON object format [ms, ns]"
+    )]
+    display_time_unit: Option<chrome::DisplayTimeUnit>,
+    #[structopt(
+        long = "other-data",
+        number_of_values = 1,
+        global = true,
+        help = "Additional otherData of JSON object format e.g. 'version=1.0' (command line, host and binary build-id are added automatically)"
+    )]
+    other_data: Vec<String>,
+    #[structopt(
+        long = "system-trace-events",
+        parse(from_os_str),
+        global = true,
+        help = "ftrace text output filepath for systemTraceEvents of JSON object format"
+    )]
+    system_trace_events: Option<std::path::PathBuf>,
     #[structopt(subcommand)]
     pub sub: CliSubCommands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    JsonArray,
+    Perfetto,
+    Fxt,
+}
+
+impl OutputFormat {
+    // NOTE: compression extension is ignored e.g. 'trace.pftrace.gz'
+    fn from_filepath(filepath: &Path) -> OutputFormat {
+        let filename = filepath.to_string_lossy().to_lowercase();
+        let filename = filename.trim_end_matches(".gz").trim_end_matches(".zst");
+        if filename.ends_with(".perfetto-trace") || filename.ends_with(".pftrace") {
+            OutputFormat::Perfetto
+        } else if filename.ends_with(".fxt") {
+            OutputFormat::Fxt
+        } else {
+            OutputFormat::Json
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "json-array" => Ok(OutputFormat::JsonArray),
+            "perfetto" => Ok(OutputFormat::Perfetto),
+            "fxt" => Ok(OutputFormat::Fxt),
+            s => Err(anyhow!(
+                "Failed parse --format flag '{}' choose from [json, json-array, perfetto, fxt]",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct ValidateCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "Target trace.json files (gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct LtraceCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "ltrace -ttt -T -f output files ('-' means stdin, gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "begin-end",
+        help = "Output 'B' and 'E' events instead of 'X' events"
+    )]
+    begin_end: bool,
+    #[structopt(long = "pid", help = "pid of ltrace output (default: tid)")]
+    pid: Option<u32>,
+    #[structopt(
+        long = "tid",
+        default_value = "0",
+        help = "tid of ltrace output without -f"
+    )]
+    tid: u32,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+#[derive(StructOpt)]
+struct StraceCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "strace -ttt -T -f output files ('-' means stdin, gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "begin-end",
+        help = "Output 'B' and 'E' events instead of 'X' events"
+    )]
+    begin_end: bool,
+    #[structopt(
+        long = "relative",
+        help = "Timestamps are relative to the previous line (strace -r)"
+    )]
+    relative: bool,
+    #[structopt(long = "pid", help = "pid of strace output (default: tid)")]
+    pid: Option<u32>,
+    #[structopt(
+        long = "tid",
+        default_value = "0",
+        help = "tid of strace output without -f (tid of strace -ff output is taken from '.PID' suffix of the filename)"
+    )]
+    tid: u32,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+#[derive(StructOpt)]
+struct PerfSchedCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "perf sched timehist output files ('-' means stdin, gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "timestamp-offset",
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Offset added to timestamps e.g. CLOCK_REALTIME - CLOCK_MONOTONIC (unit is sec)"
+    )]
+    timestamp_offset: perf_sched::TimestampOffset,
+    #[structopt(
+        long = "delay-threshold",
+        default_value = "0.01",
+        help = "Scheduling delays longer than this are shown as 'sch_delay' slices (unit is msec)"
+    )]
+    delay_threshold: f64,
+    #[structopt(
+        long = "flow",
+        help = "Connect run slices of same task on CPU tracks by flow events"
+    )]
+    flow: bool,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+#[derive(StructOpt)]
+struct BpftraceSchedCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "bpftrace sched_switch output files of JSONL or CSV format ('-' means stdin, gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "timestamp-offset",
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Offset added to timestamps e.g. CLOCK_REALTIME - CLOCK_MONOTONIC (unit is sec)"
+    )]
+    timestamp_offset: perf_sched::TimestampOffset,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+#[derive(StructOpt)]
+struct PerfScriptCli {
+    #[structopt(
+        parse(from_os_str),
+        help = "perf script output files ('-' means stdin, gzip and zstd compressed files are supported)"
+    )]
+    input_files: Vec<std::path::PathBuf>,
+    #[structopt(
+        long = "timestamp-offset",
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Offset added to timestamps e.g. CLOCK_REALTIME - CLOCK_MONOTONIC (unit is sec)"
+    )]
+    timestamp_offset: perf_sched::TimestampOffset,
+    #[structopt(
+        long = "counter-interval",
+        alias = "irq-counter-interval",
+        default_value = "1000",
+        help = "Interval of irq and context switch rate counters (unit is msec, 0 disables counters)"
+    )]
+    counter_interval: u64,
+    #[structopt(
+        long = "sched-stats-top",
+        default_value = "20",
+        help = "Print context switch statistics of top N tasks of sched:sched_switch to stderr (0 disables the report)"
+    )]
+    sched_stats_top: usize,
+    #[structopt(
+        long = "sched-stats-csv",
+        parse(from_os_str),
+        help = "Write context switch statistics of all tasks and CPUs as CSV ('-' means stdout)"
+    )]
+    sched_stats_csv: Option<std::path::PathBuf>,
+    #[structopt(flatten)]
+    process_info: ProcessInfoOpts,
+}
+
+// NOTE: parsed only once, so size of variants does not matter
+#[allow(clippy::large_enum_variant, clippy::enum_variant_names)]
 #[derive(StructOpt)]
 enum CliSubCommands {
     #[structopt(name = "iftracer", about = "Select iftracer")]
     IftracerCli(IftracerCli),
+    #[structopt(name = "cache", about = "Manage symbol cache")]
+    CacheCli(CacheCli),
+    #[structopt(name = "validate", about = "Validate trace.json files")]
+    ValidateCli(ValidateCli),
+    #[structopt(name = "ltrace", about = "Convert ltrace output")]
+    LtraceCli(LtraceCli),
+    #[structopt(name = "strace", about = "Convert strace output")]
+    StraceCli(StraceCli),
+    #[structopt(name = "perf-sched", about = "Convert perf sched timehist output")]
+    PerfSchedCli(PerfSchedCli),
+    #[structopt(
+        name = "bpftrace-sched",
+        about = "Convert bpftrace sched_switch output"
+    )]
+    BpftraceSchedCli(BpftraceSchedCli),
+    #[structopt(name = "perf-script", about = "Convert perf script output")]
+    PerfScriptCli(PerfScriptCli),
 }
 
 fn main() -> Result<()> {
@@ -83,126 +439,706 @@ fn main() -> Result<()> {
     env_logger::init();
     let args = Cli::from_args();
     let ret = match &args.sub {
-        CliSubCommands::IftracerCli(sub_args) => run_iftracer_main(&args, &sub_args),
+        CliSubCommands::IftracerCli(sub_args) => run_iftracer_main(&args, sub_args),
+        CliSubCommands::CacheCli(sub_args) => run_cache_main(sub_args),
+        CliSubCommands::ValidateCli(sub_args) => run_validate_main(sub_args),
+        CliSubCommands::LtraceCli(sub_args) => run_ltrace_main(&args, sub_args),
+        CliSubCommands::StraceCli(sub_args) => run_strace_main(&args, sub_args),
+        CliSubCommands::PerfSchedCli(sub_args) => run_perf_sched_main(&args, sub_args),
+        CliSubCommands::BpftraceSchedCli(sub_args) => run_bpftrace_sched_main(&args, sub_args),
+        CliSubCommands::PerfScriptCli(sub_args) => run_perf_script_main(&args, sub_args),
     };
     ret?;
     Ok(())
 }
 
-fn parse_elf_bit_option(opt: &str, target_bin_file: &Path) -> Result<bool> {
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_string())
+        .or_else(|| env::var("HOSTNAME").ok())
+}
+
+// metadata of JSON object format (traceEvents are written by TraceWriter)
+fn create_trace(args: &Cli) -> Result<chrome::Trace> {
+    let mut trace = chrome::Trace {
+        display_time_unit: args.display_time_unit,
+        ..Default::default()
+    };
+    let command_line = env::args().collect::<Vec<_>>().join(" ");
+    trace
+        .other_data
+        .insert(String::from("command_line"), command_line);
+    if let Some(hostname) = hostname() {
+        trace.other_data.insert(String::from("host"), hostname);
+    }
+    trace.other_data.insert(
+        String::from("x2trace_version"),
+        env!("CARGO_PKG_VERSION").to_string(),
+    );
+    for key_value in &args.other_data {
+        let (key, value) = key_value.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Failed parse --other-data flag '{}' required format is 'key=value'",
+                key_value
+            )
+        })?;
+        trace.other_data.insert(key.to_string(), value.to_string());
+    }
+    if let Some(filepath) = &args.system_trace_events {
+        let system_trace_events = std::fs::read_to_string(filepath).with_context(|| {
+            format!(
+                "create_trace(): Failed to read --system-trace-events file {:?}",
+                filepath
+            )
+        })?;
+        trace.system_trace_events = Some(system_trace_events);
+    }
+    Ok(trace)
+}
+
+fn create_trace_writer(
+    args: &Cli,
+    trace: &chrome::Trace,
+) -> Result<Box<dyn chrome::EventWriter<OutputWriter>>> {
+    let outfile = OutputWriter::create(&args.output)?;
+    let format = args
+        .format
+        .unwrap_or_else(|| OutputFormat::from_filepath(&args.output));
+    if format != OutputFormat::Json
+        && (!args.other_data.is_empty()
+            || args.display_time_unit.is_some()
+            || args.system_trace_events.is_some())
+    {
+        warn!(
+            "metadata of JSON object format is ignored with --format json-array, perfetto or fxt"
+        );
+    }
+    Ok(match format {
+        OutputFormat::Json => Box::new(chrome::TraceWriter::with_trace(
+            outfile,
+            args.pretty,
+            trace,
+        )?),
+        OutputFormat::JsonArray => Box::new(chrome::TraceWriter::new(outfile, args.pretty)?),
+        OutputFormat::Perfetto => Box::new(perfetto::PerfettoWriter::new(outfile)?),
+        OutputFormat::Fxt => Box::new(fxt::FxtWriter::new(outfile)?),
+    })
+}
+
+// pid of tid only output is --pid, pid of --proc-snapshot or tid
+fn set_process_ids(
+    events: &mut [chrome::Event],
+    pid: Option<u32>,
+    process_info_table: &process_info::ProcessInfoTable,
+) {
+    for event in events {
+        if let Some(tid) = event.thread_id.as_number() {
+            event.process_id = pid
+                .or_else(|| process_info_table.thread_pid(tid))
+                .unwrap_or(tid)
+                .into();
+        }
+    }
+}
+
+// comm of the trace is used if the name is not given by options
+fn set_task_name(
+    process_info_table: &mut process_info::ProcessInfoTable,
+    tid: u32,
+    pid: u32,
+    comm: &str,
+) {
+    if process_info_table.thread_name(tid).is_none() {
+        process_info_table.set_thread_name(tid, comm);
+    }
+    if tid == pid && process_info_table.process_name(pid).is_none() {
+        process_info_table.set_process_name(pid, comm);
+    }
+}
+
+// write metadata events of threads in events and events
+fn write_events(
+    args: &Cli,
+    trace: &chrome::Trace,
+    process_info_table: &process_info::ProcessInfoTable,
+    events: &[chrome::Event],
+) -> Result<()> {
+    let threads: BTreeSet<_> = events
+        .iter()
+        .map(|event| (event.process_id.clone(), event.thread_id.clone()))
+        .collect();
+    let mut trace_writer = create_trace_writer(args, trace)?;
+    for event in process_info_table.metadata_events(&threads) {
+        trace_writer.write_event(&event)?;
+    }
+    for event in events {
+        trace_writer.write_event(event)?;
+    }
+    info!("{} events", trace_writer.event_count());
+    trace_writer.finish()?.finish()?;
+    Ok(())
+}
+
+// CLI overrides have priority over --proc-snapshot, and --proc-snapshot has priority over csv
+fn load_process_info(opts: &ProcessInfoOpts) -> Result<process_info::ProcessInfoTable> {
+    let mut table = process_info::ProcessInfoTable::default();
+    if let Some(filepath) = &opts.pid_comm_cmdline {
+        table.load_csv(filepath)?;
+    }
+    if let Some(proc_dir) = &opts.proc_snapshot {
+        table.load_proc_dir(proc_dir)?;
+    }
+    for s in &opts.process_names {
+        let (pid, name) = process_info::parse_id_value(s)?;
+        table.set_process_name(pid, &name);
+    }
+    for s in &opts.thread_names {
+        let (tid, name) = process_info::parse_id_value(s)?;
+        table.set_thread_name(tid, &name);
+    }
+    for s in &opts.process_sort_indexes {
+        let (pid, sort_index) = process_info::parse_id_value(s)?;
+        table.set_process_sort_index(pid, sort_index.parse()?);
+    }
+    for s in &opts.thread_sort_indexes {
+        let (tid, sort_index) = process_info::parse_id_value(s)?;
+        table.set_thread_sort_index(tid, sort_index.parse()?);
+    }
+    Ok(table)
+}
+
+fn parse_elf_bit_option(opt: &str, elf_header: Option<&file::ElfHeader>) -> Result<bool> {
     match opt {
         "32" => Ok(true),
         "64" => Ok(false),
-        "auto" => {
-            let elf_bit_size = file::detect_elf_bit_size(target_bin_file);
-            match elf_bit_size {
-                Ok(file::ElfBitSize::Bit32) => Ok(true),
-                Ok(file::ElfBitSize::Bit64) => Ok(false),
-                Err(e) => return Err(e),
+        "auto" => match elf_header {
+            Some(elf_header) => Ok(elf_header.is_32bit()),
+            None => Err(anyhow!(
+                "Failed to detect address width, set --bin, --proc-maps or --bit flag"
+            )),
+        },
+        s => Err(anyhow!(
+            "Failed parse --bit flag '{}' choose from [32, 64, auto]",
+            s
+        )),
+    }
+}
+
+fn find_command(command: &str) -> Option<std::path::PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(command))
+        .find(|filepath| filepath.is_file())
+}
+
+// OBJDUMP environment variable or target arch objdump e.g. 'aarch64-linux-gnu-objdump'
+fn get_objdump_command(filepath: &Path) -> String {
+    if let Ok(val) = env::var("OBJDUMP") {
+        return val;
+    }
+    if let Ok(elf_header) = file::read_elf_header(filepath) {
+        // NOTE: e.g. big endian aarch64 binary on little endian aarch64 host requires aarch64_be objdump
+        if !(elf_header.machine.is_host() && elf_header.endian.is_host()) {
+            if let Some(prefix) = elf_header
+                .machine
+                .binutils_prefix(elf_header.bit_size, elf_header.endian)
+            {
+                let command = format!("{}objdump", prefix);
+                if find_command(&command).is_some() {
+                    return command;
+                }
+                warn!(
+                    "{} is not found for {} binary {:?}, use objdump",
+                    command, elf_header.machine, filepath
+                );
             }
         }
-        s => {
-            return Err(anyhow!(
-                "Failed parse --bit flag '{}' choose from [32, 64, auto]",
-                s
-            ))
+    }
+    "objdump".to_string()
+}
+
+fn demangle(name: &str, no_demangle: bool) -> String {
+    if !no_demangle {
+        if let Ok(sym) = Symbol::new(name) {
+            return sym.to_string();
         }
     }
+    name.to_string()
 }
 
-fn run_iftracer_main(args: &Cli, sub_args: &IftracerCli) -> Result<()> {
-    info!("[parse trace file step]");
-    let mut events = if sub_args.text_flag {
-        iftrace::parse_text_files(&sub_args.input_files)?
+fn resolve_addresses(
+    sub_args: &IftracerCli,
+    filepath: &Path,
+    base_address: u64,
+    address_list: &[u64],
+) -> Result<HashMap<u64, objdump::AddressInformation>> {
+    if sub_args.objdump_flag {
+        info!("[objdump step]");
+        let objdump_command = get_objdump_command(filepath);
+        objdump::get_addr2info_map(&objdump_command, filepath, base_address, address_list)
     } else {
-        let bit32flag =
-            parse_elf_bit_option(sub_args.bit.as_str(), sub_args.bin_filepath.as_path())?;
-        iftrace::parse_binary_files(&sub_args.input_files, bit32flag)?
+        info!("[symbol resolution step]");
+        let mut debug_dirs = sub_args.debug_dirs.clone();
+        debug_dirs.push(std::path::PathBuf::from(symbolizer::DEFAULT_DEBUG_DIR));
+        let cache_dir = if sub_args.no_cache {
+            None
+        } else {
+            sub_args
+                .cache_dir
+                .clone()
+                .or_else(symbol_cache::default_cache_dir)
+        };
+        symbolizer::get_addr2info_map(
+            filepath,
+            &debug_dirs,
+            cache_dir.as_deref(),
+            base_address,
+            address_list,
+        )
+    }
+}
+
+fn rename_event(
+    event: &mut chrome::Event,
+    info: Option<&objdump::AddressInformation>,
+    nearest_mapping: Option<&String>,
+    sub_args: &IftracerCli,
+) {
+    if let Some(info) = info {
+        event.name = demangle(&info.function_name, sub_args.no_demangle);
+        if event.event_type == chrome::EventType::DurationEnd {
+            return;
+        }
+        if sub_args.function_file_location && !info.file_location.is_empty() {
+            event.set_arg("file_location", info.file_location.as_str());
+        }
+        if sub_args.function_file_location && !info.inlined_frames.is_empty() {
+            let inlined_frames = info
+                .inlined_frames
+                .iter()
+                .map(|frame| {
+                    format!(
+                        "{} at {}",
+                        demangle(&frame.function_name, sub_args.no_demangle),
+                        frame.file_location
+                    )
+                })
+                .collect::<Vec<_>>();
+            event.set_arg("inlined_frames", inlined_frames);
+        }
+    } else if let Some(nearest_mapping) = nearest_mapping {
+        if event.event_type == chrome::EventType::DurationEnd {
+            return;
+        }
+        event.set_arg("nearest_mapping", nearest_mapping.as_str());
+    }
+}
+
+fn run_cache_main(sub_args: &CacheCli) -> Result<()> {
+    let get_cache_dir = |cache_dir: &Option<std::path::PathBuf>| {
+        cache_dir
+            .clone()
+            .or_else(symbol_cache::default_cache_dir)
+            .ok_or_else(|| anyhow!("Failed to get cache directory, please set --cache-dir"))
     };
+    match sub_args {
+        CacheCli::Build {
+            bin_filepaths,
+            debug_dirs,
+            cache_dir,
+        } => {
+            let cache_dir = get_cache_dir(cache_dir)?;
+            let mut debug_dirs = debug_dirs.clone();
+            debug_dirs.push(std::path::PathBuf::from(symbolizer::DEFAULT_DEBUG_DIR));
+            for bin_filepath in bin_filepaths {
+                let symbolizer = symbolizer::Symbolizer::load(bin_filepath, &debug_dirs)?;
+                let key = symbol_cache::cache_key(bin_filepath)?;
+                let cache_filepath = symbol_cache::store(
+                    &cache_dir,
+                    &key,
+                    &symbolizer.to_symbol_table(&symbolizer.symbol_addresses()),
+                )?;
+                info!("{:?} -> {:?}", bin_filepath, cache_filepath);
+            }
+        }
+        CacheCli::Purge { cache_dir } => {
+            let cache_dir = get_cache_dir(cache_dir)?;
+            let count = symbol_cache::purge(&cache_dir)?;
+            info!("removed {} symbol cache files in {:?}", count, cache_dir);
+        }
+    }
+    Ok(())
+}
 
-    let mut address_hash = HashSet::new();
-    for event in events.iter() {
-        // info!("address: {}", &event.name);
-        if event.name.starts_with("0x") {
-            address_hash.insert(&event.name);
+fn run_validate_main(sub_args: &ValidateCli) -> Result<()> {
+    let mut error_count = 0;
+    for input_file in &sub_args.input_files {
+        let (trace, errors) = chrome::read_and_validate_trace(input_file)?;
+        for error in &errors {
+            println!("{}: {}", input_file.display(), error);
         }
+        info!(
+            "{:?}: {} events, {} errors",
+            input_file,
+            trace.trace_events.len(),
+            errors.len()
+        );
+        error_count += errors.len();
     }
-    let address_list = address_hash.into_iter().collect::<Vec<_>>();
+    if error_count > 0 {
+        return Err(anyhow!("found {} invalid events", error_count));
+    }
+    Ok(())
+}
 
-    let filename2addr_map = if !sub_args
+fn run_iftracer_main(args: &Cli, sub_args: &IftracerCli) -> Result<()> {
+    let module_map = if !sub_args
         .proc_maps_filepath
         .as_path()
         .to_str()
         .unwrap()
         .is_empty()
     {
-        proc_maps::parse_proc_maps(&sub_args.proc_maps_filepath)?
+        proc_maps::ModuleMap::parse(&sub_args.proc_maps_filepath)?
     } else {
-        HashMap::new()
+        proc_maps::ModuleMap::default()
     };
-
-    // rename address to function name by objdump
-    if !sub_args.bin_filepath.as_path().to_str().unwrap().is_empty() {
-        info!("[objdump step]");
-        let objdump_command = match env::var("OBJDUMP") {
-            Ok(val) => val,
-            Err(_) => "objdump".to_string(),
-        };
-        let base_address = if let Some(base_address) = filename2addr_map.get(
-            &sub_args
-                .bin_filepath
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string(),
-        ) {
-            base_address.clone()
+    let bin_flag = !sub_args.bin_filepath.as_path().to_str().unwrap().is_empty();
+    let module_filepath = |module_path: &str| {
+        let filepath = if bin_flag
+            && sub_args.bin_filepath.file_name() == Path::new(module_path).file_name()
+        {
+            sub_args.bin_filepath.clone()
         } else {
-            u64::from_str_radix(sub_args.base_address.trim_start_matches("0x"), 16)?
+            sub_args.sysroot.join(module_path.trim_start_matches('/'))
         };
+        if filepath.is_file() {
+            Some(filepath)
+        } else {
+            None
+        }
+    };
 
-        let add2info_map = objdump::get_addr2info_map(
-            &objdump_command,
+    // NOTE: target ELF is used to detect address width of binary trace file
+    let target_elf_filepath = if bin_flag {
+        Some(sub_args.bin_filepath.clone())
+    } else {
+        module_map
+            .executable_modules()
+            .first()
+            .and_then(|module_path| module_filepath(module_path))
+    };
+    let elf_header = match &target_elf_filepath {
+        Some(filepath) => match file::read_elf_header(filepath) {
+            Ok(elf_header) => {
+                info!("{:?}: {:?}", filepath, elf_header);
+                Some(elf_header)
+            }
+            Err(e) => {
+                warn!("{:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let base_address = u64::from_str_radix(sub_args.base_address.trim_start_matches("0x"), 16)?;
+    if let Some(elf_header) = &elf_header {
+        if module_map.is_empty()
+            && elf_header.elf_type == file::ElfType::SharedObject
+            && base_address == 0
+        {
+            warn!(
+                "{:?} is {}, set --proc-maps or --base-address to resolve runtime address",
+                target_elf_filepath.as_ref().unwrap(),
+                if elf_header.pie {
+                    "PIE"
+                } else {
+                    "shared library"
+                }
+            );
+        }
+    }
+
+    info!("[parse trace file step]");
+    let bit32flag = if sub_args.text_flag {
+        false
+    } else {
+        parse_elf_bit_option(sub_args.bit.as_str(), elf_header.as_ref())?
+    };
+    // NOTE: trace files are read twice (collect addresses and convert) to keep memory usage low
+    let process_info_table = Rc::new(load_process_info(&sub_args.process_info)?);
+    let iter_records = || -> iftrace::RecordIter {
+        if sub_args.text_flag {
+            let pid = sub_args.pid;
+            let table = process_info_table.clone();
+            let records = iftrace::iter_text_files(&sub_args.input_files).map(move |record| {
+                record.map(|mut record| {
+                    let event = &mut record.event;
+                    if let Some(tid) = event.thread_id.as_number() {
+                        event.process_id =
+                            pid.or_else(|| table.thread_pid(tid)).unwrap_or(tid).into();
+                    }
+                    record
+                })
+            });
+            Box::new(records)
+        } else {
+            iftrace::iter_binary_files(&sub_args.input_files, bit32flag)
+        }
+    };
+
+    let mut address_hash = HashSet::new();
+    let mut threads = BTreeSet::new();
+    for record in iter_records() {
+        let record = record?;
+        let event = &record.event;
+        threads.insert((event.process_id.clone(), event.thread_id.clone()));
+        if let Some(address) = record.address {
+            address_hash.insert(address);
+        }
+    }
+    let address_list = address_hash.into_iter().collect::<Vec<_>>();
+
+    // rename address to function name by ELF symbol table or objdump
+    let mut add2info_map = HashMap::new();
+    let mut unmapped_address_map: HashMap<u64, String> = HashMap::new();
+    if !module_map.is_empty() {
+        // NOTE: --bin which is not in --proc-maps is resolved with --base-address (e.g. dlopen after the dump)
+        let bin_in_maps = bin_flag
+            && module_map.executable_modules().iter().any(|module_path| {
+                Path::new(module_path).file_name() == sub_args.bin_filepath.file_name()
+            });
+        if bin_in_maps && base_address != 0 {
+            return Err(anyhow!(
+                "--base-address {} conflicts with the mapping of {:?} in --proc-maps",
+                sub_args.base_address,
+                sub_args.bin_filepath
+            ));
+        }
+        // resolve addresses of every executable module (main exe and shared libraries)
+        let module2bias_map = module_map.load_biases(module_filepath);
+        let mut module2address_list_map: HashMap<&str, Vec<u64>> = HashMap::new();
+        let mut bin_address_list: Vec<u64> = Vec::new();
+        for &runtime_address in &address_list {
+            if let Some(segment) = module_map.find_executable_segment(runtime_address) {
+                module2address_list_map
+                    .entry(segment.pathname.as_str())
+                    .or_default()
+                    .push(runtime_address);
+            } else if bin_flag && !bin_in_maps {
+                bin_address_list.push(runtime_address);
+            } else if let Some(segment) = module_map.find_nearest_segment(runtime_address) {
+                unmapped_address_map.insert(
+                    runtime_address,
+                    format!(
+                        "{} ({:#x}-{:#x})",
+                        segment.pathname, segment.begin, segment.end
+                    ),
+                );
+            }
+        }
+        for (module_path, module_address_list) in module2address_list_map {
+            let filepath = match module_filepath(module_path) {
+                Some(filepath) => filepath,
+                None => {
+                    warn!(
+                        "module {:?} not found in sysroot {:?}, {} addresses are not resolved",
+                        module_path,
+                        sub_args.sysroot,
+                        module_address_list.len()
+                    );
+                    continue;
+                }
+            };
+            let load_bias = module2bias_map[module_path];
+            info!("[resolve {:?} with load bias {:#x}]", filepath, load_bias);
+            add2info_map.extend(resolve_addresses(
+                sub_args,
+                &filepath,
+                load_bias,
+                &module_address_list,
+            )?);
+        }
+        if !bin_address_list.is_empty() {
+            info!(
+                "[resolve {:?} with base address {:#x}]",
+                sub_args.bin_filepath, base_address
+            );
+            add2info_map.extend(resolve_addresses(
+                sub_args,
+                &sub_args.bin_filepath,
+                base_address,
+                &bin_address_list,
+            )?);
+        }
+    } else if bin_flag {
+        add2info_map = resolve_addresses(
+            sub_args,
             &sub_args.bin_filepath,
             base_address,
             &address_list,
         )?;
-        for resolved_function in &add2info_map {
-            info!("{:?}", resolved_function.1);
-        }
-        for mut event in &mut events {
-            if let Some(info) = add2info_map.get(&event.name) {
-                let mut name = info.function_name.to_string();
-                if !sub_args.no_demangle {
-                    if let Ok(sym) = Symbol::new(&name) {
-                        name = sym.to_string();
-                    }
-                }
-                event.name = name;
-                if event.event_type == chrome::EventType::DurationEnd {
-                    continue;
-                }
-                if sub_args.function_file_location && !info.file_location.is_empty() {
-                    let event_args = event.args.get_or_insert(HashMap::new());
-                    event_args.insert(
-                        String::from("file_location"),
-                        info.file_location.to_string(),
-                    );
-                }
+    }
+
+    for resolved_function in &add2info_map {
+        info!("{:?}", resolved_function.1);
+    }
+    info!("[json output step]");
+    let mut trace = create_trace(args)?;
+    if let Some(filepath) = &target_elf_filepath {
+        trace.other_data.insert(
+            String::from("binary"),
+            filepath.to_string_lossy().to_string(),
+        );
+        match symbolizer::read_file_build_id(filepath) {
+            Ok(Some(build_id)) => {
+                trace.other_data.insert(String::from("build_id"), build_id);
             }
+            Ok(None) => {}
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+    let mut trace_writer = create_trace_writer(args, &trace)?;
+    for event in process_info_table.metadata_events(&threads) {
+        trace_writer.write_event(&event)?;
+    }
+    for record in iter_records() {
+        let iftrace::Record { mut event, address } = record?;
+        if let Some(address) = address {
+            rename_event(
+                &mut event,
+                add2info_map.get(&address),
+                unmapped_address_map.get(&address),
+                sub_args,
+            );
         }
+        trace_writer.write_event(&event)?;
     }
+    info!("{} events", trace_writer.event_count());
+    trace_writer.finish()?.finish()?;
+    Ok(())
+}
 
-    info!("[json parse step]");
-    let events_json = if args.pretty {
-        serde_json::to_string_pretty(&events)?
+fn run_ltrace_main(args: &Cli, sub_args: &LtraceCli) -> Result<()> {
+    let process_info_table = load_process_info(&sub_args.process_info)?;
+    let mode = if sub_args.begin_end {
+        ltrace::OutputMode::BeginEnd
     } else {
-        serde_json::to_string(&events)?
+        ltrace::OutputMode::Complete
     };
-    // info!("{}", events_json);
+    let mut parser = ltrace::LtraceParser::new(mode, sub_args.tid);
+    for input_file in &sub_args.input_files {
+        info!("[parse {:?}]", input_file);
+        parser.parse_file(input_file)?;
+    }
+    let mut events = parser.finish();
+    set_process_ids(&mut events, sub_args.pid, &process_info_table);
+    write_events(args, &create_trace(args)?, &process_info_table, &events)
+}
 
-    info!("[json output step]");
-    let mut outfile = File::create("out.json")?;
-    outfile.write_all(events_json.as_bytes())?;
+fn run_strace_main(args: &Cli, sub_args: &StraceCli) -> Result<()> {
+    let process_info_table = load_process_info(&sub_args.process_info)?;
+    let mode = if sub_args.begin_end {
+        ltrace::OutputMode::BeginEnd
+    } else {
+        ltrace::OutputMode::Complete
+    };
+    let mut parser = strace::StraceParser::new(mode, sub_args.tid, sub_args.relative);
+    for input_file in &sub_args.input_files {
+        info!("[parse {:?}]", input_file);
+        parser.parse_file(input_file)?;
+    }
+    let mut events = parser.finish();
+    set_process_ids(&mut events, sub_args.pid, &process_info_table);
+    write_events(args, &create_trace(args)?, &process_info_table, &events)
+}
+
+fn run_perf_sched_main(args: &Cli, sub_args: &PerfSchedCli) -> Result<()> {
+    let mut process_info_table = load_process_info(&sub_args.process_info)?;
+    if sub_args.delay_threshold < 0.0 {
+        return Err(anyhow!(
+            "--delay-threshold {} is negative",
+            sub_args.delay_threshold
+        ));
+    }
+    let mut parser = perf_sched::TimehistParser::new(perf_sched::TimehistOptions {
+        timestamp_offset: sub_args.timestamp_offset,
+        delay_threshold: std::time::Duration::from_secs_f64(sub_args.delay_threshold / 1000.0),
+        flow: sub_args.flow,
+    });
+    let trace = create_trace(args)?;
+    let mut trace_writer = create_trace_writer(args, &trace)?;
+    let mut threads = BTreeSet::new();
+    for input_file in &sub_args.input_files {
+        info!("[parse {:?}]", input_file);
+        parser.parse_file(input_file, |event| {
+            threads.insert((event.process_id.clone(), event.thread_id.clone()));
+            trace_writer.write_event(event)
+        })?;
+    }
+    // NOTE: metadata events are written at the end, because task names are found while parsing
+    for (&tid, (pid, comm)) in parser.tasks() {
+        set_task_name(&mut process_info_table, tid, *pid, comm);
+    }
+    for event in process_info_table.metadata_events(&threads) {
+        trace_writer.write_event(&event)?;
+    }
+    info!("{} events", trace_writer.event_count());
+    trace_writer.finish()?.finish()?;
     Ok(())
 }
+
+fn run_bpftrace_sched_main(args: &Cli, sub_args: &BpftraceSchedCli) -> Result<()> {
+    let process_info_table = load_process_info(&sub_args.process_info)?;
+    let mut parser =
+        bpftrace_sched::BpftraceSchedParser::new(&process_info_table, sub_args.timestamp_offset);
+    let trace = create_trace(args)?;
+    let mut trace_writer = create_trace_writer(args, &trace)?;
+    for input_file in &sub_args.input_files {
+        info!("[parse {:?}]", input_file);
+        parser.parse_file(input_file, |event| trace_writer.write_event(event))?;
+    }
+    for event in parser.finish() {
+        trace_writer.write_event(&event)?;
+    }
+    info!("{} events", trace_writer.event_count());
+    trace_writer.finish()?.finish()?;
+    Ok(())
+}
+
+fn run_perf_script_main(args: &Cli, sub_args: &PerfScriptCli) -> Result<()> {
+    let mut process_info_table = load_process_info(&sub_args.process_info)?;
+    let mut converter = perf_script::PerfScriptConverter::new(std::time::Duration::from_millis(
+        sub_args.counter_interval,
+    ));
+    for input_file in &sub_args.input_files {
+        info!("[parse {:?}]", input_file);
+        perf_script::read_records(input_file, sub_args.timestamp_offset, |mut record| {
+            record.pid = record
+                .pid
+                .or_else(|| process_info_table.thread_pid(record.tid));
+            set_task_name(
+                &mut process_info_table,
+                record.tid,
+                record.pid.unwrap_or(record.tid),
+                &record.comm,
+            );
+            converter.convert(&record);
+        })?;
+    }
+    let (events, stack_frames, sched_stats) = converter.finish();
+    if !sched_stats.is_empty() {
+        if sub_args.sched_stats_top > 0 {
+            sched_stats.write_report(&mut std::io::stderr(), sub_args.sched_stats_top)?;
+        }
+        if let Some(csv_file) = &sub_args.sched_stats_csv {
+            let mut writer = OutputWriter::create(csv_file)?;
+            sched_stats.write_csv(&mut writer)?;
+            writer.finish()?;
+        }
+    }
+    let mut trace = create_trace(args)?;
+    trace.stack_frames = stack_frames;
+    write_events(args, &trace, &process_info_table, &events)
+}
@@ -1,25 +1,34 @@
 use std::collections::HashMap;
 use std::io::BufRead;
 use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::Path;
 use std::process::Command;
 use std::process::Stdio;
 
 use anyhow::{anyhow, Result};
+use object::{Object, ObjectSection};
+
+#[derive(Debug, Clone)]
+pub struct InlinedFrame {
+    pub function_name: String,
+    pub file_location: String,
+}
 
 #[derive(Debug)]
 pub struct AddressInformation {
-    pub address: String,
+    pub address: u64,
     pub file_location: String,
     pub function_name: String,
+    // NOTE: innermost inlined function first
+    pub inlined_frames: Vec<InlinedFrame>,
 }
 
 pub fn get_addr2info_map(
     objdump_command: &str,
-    filepath: &PathBuf,
+    filepath: &Path,
     base_address: u64,
-    address_list: &Vec<&String>,
-) -> Result<HashMap<String, AddressInformation>> {
+    address_list: &[u64],
+) -> Result<HashMap<u64, AddressInformation>> {
     let child = Command::new(objdump_command)
         .arg("--disassemble")
         .arg("--prefix-addresses")
@@ -40,39 +49,92 @@ pub fn get_addr2info_map(
             stderr_output
         ));
     }
-    let mut addr2info_map: HashMap<String, AddressInformation> = HashMap::new();
-
-    let mut address_list_clone = address_list.clone();
-    address_list_clone.sort();
-    let address_list_sorted: Vec<String> = address_list_clone
-        .iter()
-        .map(|x| {
-            let x = x.to_lowercase();
-            if x.starts_with("0x") {
-                x.strip_prefix("0x").unwrap().to_string()
-            } else {
-                x
-            }
+
+    let section_ends = read_section_ends(filepath);
+    let ranges = parse_ranges(Cursor::new(output.stdout), &section_ends)?;
+
+    let mut addr2info_map: HashMap<u64, AddressInformation> = HashMap::new();
+    for runtime_address in address_list {
+        // NOTE: load bias may be "negative" (wrapped) when the mapping is below p_vaddr
+        let address = runtime_address.wrapping_sub(base_address);
+        let (func_name, file_location) = match find_range(&ranges, address) {
+            Some(range) => range,
+            None => continue,
+        };
+        let address_information = AddressInformation {
+            address: *runtime_address,
+            file_location: file_location.to_string(),
+            function_name: func_name.to_string(),
+            inlined_frames: Vec::new(),
+        };
+        addr2info_map.insert(*runtime_address, address_information);
+    }
+    Ok(addr2info_map)
+}
+
+// section name -> end address of sections in the ELF file (empty if the file can not be parsed)
+fn read_section_ends(filepath: &Path) -> HashMap<String, u64> {
+    let data = match std::fs::read(filepath) {
+        Ok(data) => data,
+        Err(_) => return HashMap::new(),
+    };
+    let elf = match object::File::parse(&*data) {
+        Ok(elf) => elf,
+        Err(_) => return HashMap::new(),
+    };
+    elf.sections()
+        .filter_map(|section| {
+            let name = section.name().ok()?;
+            Some((name.to_string(), section.address() + section.size()))
         })
-        .collect();
-    // for addr in address_list_sorted.iter() {
-    // info!("address: {}", addr);
-    // }
-    let mut address_list_index = 0;
-    let address_list_length = address_list_sorted.len();
-    // TODO: get information of file line
-    let mut cur = Cursor::new(output.stdout);
+        .collect()
+}
+
+// (start address, function name, file location) of each function or file location range
+// NOTE: a range with empty function name terminates the last function of each section
+fn parse_ranges(
+    mut reader: impl BufRead,
+    section_ends: &HashMap<String, u64>,
+) -> Result<Vec<(u64, String, String)>> {
+    let mut ranges: Vec<(u64, String, String)> = Vec::new();
     let mut line = String::new();
 
     let mut addr_file_location = String::new();
-    while cur.read_line(&mut line).unwrap() > 0 && address_list_index < address_list_length {
-        if line.starts_with("/") {
+    let mut section_name: Option<String> = None;
+    let mut pre_address: Option<u64> = None;
+    // NOTE: the end of the last instruction is unknown without the section header
+    let close_section = |ranges: &mut Vec<(u64, String, String)>,
+                         section_name: &Option<String>,
+                         pre_address: Option<u64>| {
+        if let Some(address) = pre_address {
+            let end_address = section_name
+                .as_ref()
+                .and_then(|section_name| section_ends.get(section_name))
+                .copied()
+                .filter(|end_address| *end_address > address)
+                .unwrap_or(address + 1);
+            ranges.push((end_address, String::new(), String::new()));
+        }
+    };
+    while reader.read_line(&mut line)? > 0 {
+        if line.starts_with('/') {
             // filepath information
             addr_file_location = line.trim_end().to_string();
         }
+        // NOTE: file location is unknown until the next filepath line in a new function or section
+        // e.g. '_fini():'
+        if line.trim_end().ends_with("():") {
+            addr_file_location.clear();
+        }
+        // e.g. 'Disassembly of section .text:'
+        if let Some(name) = line.strip_prefix("Disassembly of section ") {
+            close_section(&mut ranges, &section_name, pre_address);
+            section_name = Some(name.trim_end().trim_end_matches(':').to_string());
+            addr_file_location.clear();
+            pre_address = None;
+        }
         // debug!("objdump output line: {:?}", line);
-        if line.starts_with("0") || line.starts_with(" ") {
-        } else {
+        if !(line.starts_with('0') || line.starts_with(' ')) {
             line.clear();
             continue;
         }
@@ -80,35 +142,119 @@ pub fn get_addr2info_map(
         if fields.len() <= 2 {
             return Err(anyhow!("Failed parse line '{}'", line));
         }
-        let address = fields[0].trim_start_matches(|c| c == '0' || c == ' ');
-        let runtime_address = base_address + u64::from_str_radix(address, 16)?;
-        let address = &format!("{:x}", runtime_address);
-        let func_name = fields[1].trim_matches(|c| c == '<' || c == '>');
-        while address_list_index < address_list_length {
-            let target_address = &address_list_sorted[address_list_index];
-            if address == target_address {
-                // found target address
-                let hex_address = String::from("0x") + &target_address;
-                let address_information = AddressInformation {
-                    address: hex_address.clone(),
-                    file_location: addr_file_location.clone(),
-                    function_name: String::from(func_name),
-                };
-                addr2info_map.insert(hex_address, address_information);
-                // debug!("objdump hit address: {:?}", line);
-                address_list_index += 1;
-                break;
-            } else if address < target_address {
-                // continue to search target address
-                break;
-            } else {
-                // debug!("objdump output line: {:?}", line);
-                // skipped to search target address
-                address_list_index += 1;
-                continue;
+        let address = u64::from_str_radix(fields[0], 16)?;
+        let symbol = fields[1].trim_matches(['<', '>']);
+        let func_name = match symbol.rsplit_once("+0x") {
+            Some((func_name, _)) => func_name,
+            None => symbol,
+        };
+        let changed = match ranges.last() {
+            Some((_, pre_func_name, pre_file_location)) => {
+                pre_address.is_none()
+                    || pre_func_name != func_name
+                    || *pre_file_location != addr_file_location
             }
+            None => true,
+        };
+        if changed {
+            ranges.push((address, func_name.to_string(), addr_file_location.clone()));
         }
+        pre_address = Some(address);
         line.clear();
     }
-    Ok(addr2info_map)
+    close_section(&mut ranges, &section_name, pre_address);
+    ranges.sort_by_key(|(address, _, _)| *address);
+    Ok(ranges)
+}
+
+// (function name, file location) of the range which contains the link time address
+fn find_range(ranges: &[(u64, String, String)], address: u64) -> Option<(&str, &str)> {
+    let index = ranges.partition_point(|(start_address, _, _)| *start_address <= address);
+    if index == 0 {
+        return None;
+    }
+    let (_, func_name, file_location) = &ranges[index - 1];
+    if func_name.is_empty() {
+        return None;
+    }
+    Some((func_name, file_location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // objdump --disassemble --prefix-addresses --line-numbers of x86_64 PIE
+    const OBJDUMP_OUTPUT: &str = "
+a.out:     file format elf64-x86-64
+
+
+Disassembly of section .init:
+_init():
+0000000000001000 <_init> sub    $0x8,%rsp
+0000000000001004 <_init+0x4> mov    0x2fc5(%rip),%rax        # 0000000000003fd0 <__gmon_start__@Base>
+0000000000001016 <_init+0x16> ret
+
+Disassembly of section .text:
+main():
+/tmp/t/a.cpp:4
+0000000000001050 <main> sub    $0x8,%rsp
+/tmp/t/a.cpp:3
+0000000000001054 <main+0x4> call   0000000000001170 <_ZN2ns3fooEi>
+frame_dummy():
+0000000000001160 <frame_dummy> endbr64
+0000000000001164 <frame_dummy+0x4> jmp    00000000000010e0 <register_tm_clones>
+0000000000001169 <frame_dummy+0x9> nopl   0x0(%rax)
+_ZN2ns3fooEi():
+/tmp/t/a.cpp:2
+0000000000001170 <_ZN2ns3fooEi> lea    (%rdi,%rdi,1),%eax
+0000000000001173 <_ZN2ns3fooEi+0x3> ret
+
+Disassembly of section .fini:
+_fini():
+0000000000001174 <_fini> sub    $0x8,%rsp
+0000000000001178 <_fini+0x4> add    $0x8,%rsp
+000000000000117c <_fini+0x8> ret
+";
+
+    fn section_ends() -> HashMap<String, u64> {
+        [(".init", 0x101b), (".text", 0x1174), (".fini", 0x117d)]
+            .iter()
+            .map(|(name, end_address)| (name.to_string(), *end_address))
+            .collect()
+    }
+
+    #[test]
+    fn ranges() {
+        let ranges = parse_ranges(OBJDUMP_OUTPUT.as_bytes(), &section_ends()).unwrap();
+        assert_eq!(find_range(&ranges, 0x1000), Some(("_init", "")));
+        assert_eq!(find_range(&ranges, 0x101a), Some(("_init", "")));
+        assert_eq!(find_range(&ranges, 0x101b), None);
+        assert_eq!(
+            find_range(&ranges, 0x1052),
+            Some(("main", "/tmp/t/a.cpp:4"))
+        );
+        assert_eq!(
+            find_range(&ranges, 0x1058),
+            Some(("main", "/tmp/t/a.cpp:3"))
+        );
+        assert_eq!(
+            find_range(&ranges, 0x1173),
+            Some(("_ZN2ns3fooEi", "/tmp/t/a.cpp:2"))
+        );
+        assert_eq!(find_range(&ranges, 0x117c), Some(("_fini", "")));
+        assert_eq!(find_range(&ranges, 0x1164), Some(("frame_dummy", "")));
+    }
+
+    #[test]
+    fn last_function_of_last_section() {
+        let ranges = parse_ranges(OBJDUMP_OUTPUT.as_bytes(), &section_ends()).unwrap();
+        assert_eq!(find_range(&ranges, 0x117d), None);
+        assert_eq!(find_range(&ranges, 0x2000), None);
+        // NOTE: without section headers, the last function ends at its last instruction
+        let ranges = parse_ranges(OBJDUMP_OUTPUT.as_bytes(), &HashMap::new()).unwrap();
+        assert_eq!(find_range(&ranges, 0x117c), Some(("_fini", "")));
+        assert_eq!(find_range(&ranges, 0x117d), None);
+        assert_eq!(find_range(&ranges, 0x1017), None);
+    }
 }
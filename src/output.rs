@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+pub enum OutputWriter {
+    Stdout(BufWriter<io::Stdout>),
+    File(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputWriter {
+    // '-' means stdout, and '.gz' or '.zst' extension means compressed output
+    pub fn create(filepath: &Path) -> Result<OutputWriter> {
+        if filepath.as_os_str() == "-" {
+            return Ok(OutputWriter::Stdout(BufWriter::new(io::stdout())));
+        }
+        let file = File::create(filepath).with_context(|| {
+            format!(
+                "OutputWriter::create(): Failed to create file {:?}",
+                filepath
+            )
+        })?;
+        let writer = BufWriter::new(file);
+        let extension = filepath
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        Ok(match extension.as_deref() {
+            Some("gz") => OutputWriter::Gzip(GzEncoder::new(writer, Compression::default())),
+            Some("zst") => OutputWriter::Zstd(zstd::Encoder::new(writer, 0)?),
+            _ => OutputWriter::File(writer),
+        })
+    }
+
+    // NOTE: compressed stream is broken without calling finish()
+    pub fn finish(self) -> Result<()> {
+        match self {
+            OutputWriter::Stdout(mut writer) => writer.flush()?,
+            OutputWriter::File(mut writer) => writer.flush()?,
+            OutputWriter::Gzip(writer) => writer.finish()?.flush()?,
+            OutputWriter::Zstd(writer) => writer.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Stdout(writer) => writer.write(buf),
+            OutputWriter::File(writer) => writer.write(buf),
+            OutputWriter::Gzip(writer) => writer.write(buf),
+            OutputWriter::Zstd(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Stdout(writer) => writer.flush(),
+            OutputWriter::File(writer) => writer.flush(),
+            OutputWriter::Gzip(writer) => writer.flush(),
+            OutputWriter::Zstd(writer) => writer.flush(),
+        }
+    }
+}
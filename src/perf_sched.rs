@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chrome;
+use crate::input;
+use crate::ltrace::parse_seconds;
+
+// offset to convert CLOCK_MONOTONIC timestamp to CLOCK_REALTIME timestamp (unit is sec)
+// e.g. '1646610039.304117326', '-0.5'
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimestampOffset {
+    negative: bool,
+    offset: Duration,
+}
+
+impl TimestampOffset {
+    pub fn apply(&self, timestamp: Duration) -> Result<Duration> {
+        if self.negative {
+            timestamp.checked_sub(self.offset).ok_or_else(|| {
+                anyhow!(
+                    "timestamp {:?} is negative with offset -{:?}",
+                    timestamp,
+                    self.offset
+                )
+            })
+        } else {
+            Ok(timestamp + self.offset)
+        }
+    }
+}
+
+impl std::str::FromStr for TimestampOffset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TimestampOffset> {
+        let (negative, offset) = match s.strip_prefix('-') {
+            Some(offset) => (true, offset),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let offset = parse_seconds(offset)
+            .ok_or_else(|| anyhow!("Failed parse timestamp offset '{}' unit is sec", s))?;
+        Ok(TimestampOffset { negative, offset })
+    }
+}
+
+// 'ms' columns of perf sched timehist e.g. '0.002'
+fn parse_msecs(s: &str) -> Option<Duration> {
+    parse_seconds(s).map(|duration| duration / 1000)
+}
+
+// 'tmux: server[27077]' or 'fzf[8489/4922]' -> (comm, Some((tid, pid)))
+// ':27060' (comm is unknown) -> ("", Some((tid, tid)))
+// ':-1[-1]' (task is unknown) -> (":-1", None)
+fn parse_task_name(s: &str) -> Option<(&str, Option<(u32, u32)>)> {
+    let start = match s.rfind('[') {
+        Some(start) => start,
+        None => {
+            let tid = s.strip_prefix(':')?.parse().ok()?;
+            return Some(("", Some((tid, tid))));
+        }
+    };
+    let ids = s[start + 1..].strip_suffix(']')?;
+    if ids == "-1" {
+        return Some((&s[..start], None));
+    }
+    let (tid, pid) = match ids.split_once('/') {
+        Some((tid, pid)) => (tid.parse().ok()?, pid.parse().ok()?),
+        None => {
+            let tid = ids.parse().ok()?;
+            (tid, tid)
+        }
+    };
+    Some((&s[..start], Some((tid, pid))))
+}
+
+pub(crate) fn cpu_track(cpu: u32) -> (chrome::TrackId, chrome::TrackId) {
+    ("CPU".into(), format!("CPU {}", cpu).into())
+}
+
+pub struct TimehistOptions {
+    pub timestamp_offset: TimestampOffset,
+    // scheduling delays longer than this are shown as slices
+    pub delay_threshold: Duration,
+    // connect run slices of a task on CPU tracks
+    pub flow: bool,
+}
+
+// perf sched timehist output
+//            time    cpu  task name                       wait time  sch delay   run time
+//                         [tid/pid]                          (msec)     (msec)     (msec)
+// --------------- ------  ------------------------------  ---------  ---------  ---------
+//  4282978.085809 [0004]  <idle>                              0.000      0.000      0.000
+//  4282978.125710 [0004]  fzf[8489/4922]                      0.000      0.002      0.010
+pub struct TimehistParser {
+    options: TimehistOptions,
+    // tid -> (pid, comm)
+    tasks: BTreeMap<u32, (u32, String)>,
+    flow_tids: HashSet<u32>,
+    lost_event_count: u64,
+    long_delay_count: usize,
+}
+
+impl TimehistParser {
+    pub fn new(options: TimehistOptions) -> TimehistParser {
+        TimehistParser {
+            options,
+            tasks: BTreeMap::new(),
+            flow_tids: HashSet::new(),
+            lost_event_count: 0,
+            long_delay_count: 0,
+        }
+    }
+
+    // tid -> (pid, comm) of tasks which appear in the trace
+    pub fn tasks(&self) -> &BTreeMap<u32, (u32, String)> {
+        &self.tasks
+    }
+
+    pub fn parse_file(
+        &mut self,
+        filepath: &Path,
+        mut write_event: impl FnMut(&chrome::Event) -> Result<()>,
+    ) -> Result<()> {
+        let reader = input::open(filepath)?;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read file {:?}", filepath))?;
+            match self.parse_line(&line) {
+                Ok(events) => {
+                    for event in &events {
+                        write_event(event)?;
+                    }
+                }
+                Err(e) => log::warn!("{:?}:{}: {}", filepath, i + 1, e),
+            }
+        }
+        if self.lost_event_count > 0 {
+            log::warn!("{:?}: lost {} events", filepath, self.lost_event_count);
+        }
+        log::info!(
+            "{} scheduling delays are longer than {:?}",
+            self.long_delay_count,
+            self.options.delay_threshold
+        );
+        Ok(())
+    }
+
+    fn new_event(
+        &self,
+        name: &str,
+        category: &str,
+        timestamp: Duration,
+        duration: Duration,
+        track: (chrome::TrackId, chrome::TrackId),
+    ) -> chrome::Event {
+        chrome::Event {
+            name: name.to_string(),
+            category: category.to_string(),
+            event_type: chrome::EventType::Complete,
+            timestamp,
+            duration,
+            process_id: track.0,
+            thread_id: track.1,
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<Vec<chrome::Event>> {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        // header lines
+        if cols.is_empty()
+            || cols[0] == "time"
+            || cols[0] == "[tid/pid]"
+            || cols[0].bytes().all(|c| c == b'-')
+        {
+            return Ok(vec![]);
+        }
+        let timestamp =
+            parse_seconds(cols[0]).ok_or_else(|| anyhow!("invalid timestamp '{}'", cols[0]))?;
+        let timestamp = self.options.timestamp_offset.apply(timestamp)?;
+
+        // e.g. '1001.610442 lost 78106 events on cpu 0'
+        if cols.get(1) == Some(&"lost") {
+            let lost_events = cols.get(2).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+            let cpu = cols.last().and_then(|s| s.parse::<u32>().ok()).unwrap_or(0);
+            self.lost_event_count += lost_events;
+            let mut event = self.new_event(
+                &format!("lost {} events", lost_events),
+                "lost",
+                timestamp,
+                Duration::from_micros(0),
+                cpu_track(cpu),
+            );
+            event.event_type = chrome::EventType::Instant;
+            event.instant_scope = Some(chrome::InstantScope::Thread);
+            event.set_arg("lost_events", lost_events);
+            return Ok(vec![event]);
+        }
+
+        let cpu = cols
+            .get(1)
+            .and_then(|s| s.strip_prefix('['))
+            .and_then(|s| s.strip_suffix(']'))
+            .and_then(|s| s.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("invalid cpu column '{}'", line))?;
+        // NOTE: task name may have spaces e.g. 'tmux: server[27077]'
+        let (rest, run_time) = line
+            .trim_end()
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        let (rest, sch_delay) = rest
+            .trim_end()
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        let (rest, wait_time) = rest
+            .trim_end()
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        let (run_time, sch_delay, wait_time) = match (
+            parse_msecs(run_time),
+            parse_msecs(sch_delay),
+            parse_msecs(wait_time),
+        ) {
+            (Some(run_time), Some(sch_delay), Some(wait_time)) => (run_time, sch_delay, wait_time),
+            _ => return Err(anyhow!("invalid time columns '{}'", line)),
+        };
+        let task_name = rest
+            .split_once(']')
+            .map(|(_, task_name)| task_name.trim())
+            .ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        // NOTE: idle time is shown as a gap of CPU track
+        if task_name == "<idle>" {
+            return Ok(vec![]);
+        }
+        let (comm, ids) = parse_task_name(task_name)
+            .ok_or_else(|| anyhow!("invalid task name '{}'", task_name))?;
+
+        // NOTE: timestamp is the end of run time
+        let start_timestamp = timestamp.saturating_sub(run_time);
+        let mut events = Vec::new();
+        let (tid, pid) = match ids {
+            Some(ids) => ids,
+            None => {
+                // NOTE: unknown task has only the slice of CPU track
+                let mut cpu_event =
+                    self.new_event(task_name, comm, start_timestamp, run_time, cpu_track(cpu));
+                cpu_event.set_arg("wait_time_ms", wait_time.as_secs_f64() * 1000.0);
+                cpu_event.set_arg("sch_delay_ms", sch_delay.as_secs_f64() * 1000.0);
+                events.push(cpu_event);
+                return Ok(events);
+            }
+        };
+        // NOTE: task name is taken from other lines if comm is unknown
+        if !comm.is_empty() {
+            self.tasks
+                .entry(tid)
+                .or_insert_with(|| (pid, comm.to_string()));
+        }
+        let name = format!("{}({})", comm, tid);
+        let mut cpu_event = self.new_event(&name, comm, start_timestamp, run_time, cpu_track(cpu));
+        cpu_event.set_arg("tid", tid);
+        cpu_event.set_arg("pid", pid);
+        cpu_event.set_arg("wait_time_ms", wait_time.as_secs_f64() * 1000.0);
+        cpu_event.set_arg("sch_delay_ms", sch_delay.as_secs_f64() * 1000.0);
+        events.push(cpu_event);
+        let task_track = (pid.into(), tid.into());
+        events.push(
+            self.new_event(
+                &format!("CPU {}", cpu),
+                comm,
+                start_timestamp,
+                run_time,
+                task_track.clone(),
+            )
+            .with_arg("cpu", cpu),
+        );
+        if sch_delay > self.options.delay_threshold {
+            self.long_delay_count += 1;
+            events.push(self.new_event(
+                "sch_delay",
+                "sch_delay",
+                start_timestamp.saturating_sub(sch_delay),
+                sch_delay,
+                task_track,
+            ));
+        }
+        if self.options.flow {
+            let mut flow_event = self.new_event(
+                &name,
+                "migration",
+                start_timestamp,
+                Duration::from_micros(0),
+                cpu_track(cpu),
+            );
+            flow_event.event_type = if self.flow_tids.insert(tid) {
+                chrome::EventType::FlowStart
+            } else {
+                chrome::EventType::FlowStep
+            };
+            flow_event.id = Some(tid.to_string());
+            events.push(flow_event);
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Vec<chrome::Event> {
+        let mut parser = TimehistParser::new(TimehistOptions {
+            timestamp_offset: TimestampOffset::default(),
+            delay_threshold: Duration::from_millis(1),
+            flow: false,
+        });
+        parser.parse_line(line).unwrap()
+    }
+
+    #[test]
+    fn task_name() {
+        assert_eq!(
+            parse_task_name("tmux: server[27077]"),
+            Some(("tmux: server", Some((27077, 27077))))
+        );
+        assert_eq!(
+            parse_task_name("fzf[8489/4922]"),
+            Some(("fzf", Some((8489, 4922))))
+        );
+        assert_eq!(parse_task_name(":27060"), Some(("", Some((27060, 27060)))));
+        assert_eq!(parse_task_name(":-1[-1]"), Some((":-1", None)));
+        assert_eq!(parse_task_name("fzf"), None);
+    }
+
+    // data/perf-sched-timehist.txt:48
+    #[test]
+    fn unknown_comm() {
+        let events =
+            parse(" 4282978.128009 [0005]  :27060                              0.000      0.002      0.059 ");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "(27060)");
+        assert_eq!(events[0].thread_id, "CPU 5".into());
+        assert_eq!(events[0].duration, Duration::from_micros(59));
+        assert_eq!(events[1].thread_id, 27060.into());
+    }
+
+    // data/perf-sched-timehist.txt:684
+    #[test]
+    fn unknown_task() {
+        let events =
+            parse(" 4282978.235176 [0001]  :-1[-1]                             0.000      0.000      0.238 ");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, ":-1[-1]");
+        assert_eq!(events[0].thread_id, "CPU 1".into());
+        assert_eq!(
+            events[0].timestamp,
+            Duration::from_micros(4282978235176 - 238)
+        );
+    }
+}
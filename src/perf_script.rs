@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::chrome;
+use crate::input;
+use crate::irq::IrqTracker;
+use crate::ltrace::{parse_seconds, return_value};
+use crate::perf_sched::TimestampOffset;
+use crate::sched_stats::SchedStats;
+
+// callchain line e.g. '	    ffffffff81001234 do_syscall_64+0x5b ([kernel.kallsyms])'
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub address: String,
+    pub symbol: String,
+    pub dso: String,
+}
+
+// a record of perf script output
+// e.g. '  myapp  1234/1235 [003] 12345.678901:     probe_myapp:foo: (401136) arg1=1'
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub comm: String,
+    // NOTE: pid is printed only with '-F +pid'
+    pub pid: Option<u32>,
+    pub tid: u32,
+    pub cpu: Option<u32>,
+    pub timestamp: Duration,
+    // sample period of sampling events e.g. '250000 cpu-clock:pppH:'
+    pub period: Option<u64>,
+    pub event: String,
+    pub args: String,
+    // callee first
+    pub callchain: Vec<Frame>,
+}
+
+pub fn parse_frame(line: &str) -> Option<Frame> {
+    let line = line.trim();
+    let (address, rest) = match line.split_once(char::is_whitespace) {
+        Some((address, rest)) => (address, rest.trim()),
+        None => (line, ""),
+    };
+    if address.is_empty() || !address.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    // NOTE: demangled symbol may have spaces e.g. 'foo(int, char)'
+    let (symbol, dso) = match rest.rsplit_once(" (") {
+        Some((symbol, dso)) => (symbol.trim(), dso.trim_end_matches(')')),
+        None => match rest.strip_prefix('(') {
+            Some(dso) => ("", dso.trim_end_matches(')')),
+            None => (rest, ""),
+        },
+    };
+    Some(Frame {
+        address: address.to_string(),
+        symbol: symbol.to_string(),
+        dso: dso.to_string(),
+    })
+}
+
+// 'comm pid/tid [cpu] ts: [period] event: args'
+pub fn parse_header(line: &str) -> Option<Record> {
+    let mut tokens: Vec<(usize, &str)> = Vec::new();
+    let mut offset = 0;
+    for token in line.split_whitespace() {
+        let start = offset + line[offset..].find(token)?;
+        offset = start + token.len();
+        tokens.push((start, token));
+    }
+    let ts_index = tokens.iter().position(|(_, token)| {
+        token
+            .strip_suffix(':')
+            .map(|ts| ts.contains('.') && parse_seconds(ts).is_some())
+            .unwrap_or(false)
+    })?;
+    let timestamp = parse_seconds(tokens[ts_index].1.trim_end_matches(':'))?;
+    let mut index = ts_index;
+    let mut cpu = None;
+    if index > 0 {
+        if let Some(s) = tokens[index - 1]
+            .1
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+        {
+            cpu = Some(s.parse::<u32>().ok()?);
+            index -= 1;
+        }
+    }
+    if index == 0 {
+        return None;
+    }
+    let (ids_start, ids) = tokens[index - 1];
+    let (pid, tid) = match ids.split_once('/') {
+        Some((pid, tid)) => (Some(pid.parse::<u32>().ok()?), tid.parse::<u32>().ok()?),
+        None => (None, ids.parse::<u32>().ok()?),
+    };
+    // NOTE: comm may have spaces
+    let comm = line[..ids_start].trim().to_string();
+
+    let (ts_start, ts) = tokens[ts_index];
+    let mut rest = line[ts_start + ts.len()..].trim_start();
+    let mut period = None;
+    if let Some((first, s)) = rest.split_once(char::is_whitespace) {
+        if let Ok(value) = first.parse::<u64>() {
+            period = Some(value);
+            rest = s.trim_start();
+        }
+    }
+    let (event, args) = match rest.split_once(": ") {
+        Some((event, args)) => (event, args.trim()),
+        None => (rest.trim_end().trim_end_matches(':'), ""),
+    };
+    Some(Record {
+        comm,
+        pid,
+        tid,
+        cpu,
+        timestamp,
+        period,
+        event: event.to_string(),
+        args: args.to_string(),
+        callchain: Vec::new(),
+    })
+}
+
+// records are passed to f one by one with their callchains
+pub fn read_records<F: FnMut(Record)>(
+    filepath: &Path,
+    timestamp_offset: TimestampOffset,
+    mut f: F,
+) -> Result<()> {
+    let reader = input::open(filepath)?;
+    let mut pending: Option<Record> = None;
+    let mut in_callchain = false;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read file {:?}", filepath))?;
+        if line.trim().is_empty() {
+            in_callchain = false;
+            continue;
+        }
+        // NOTE: callchain lines are indented by tab
+        if line.starts_with('\t') || in_callchain {
+            if let (Some(frame), Some(record)) = (parse_frame(&line), pending.as_mut()) {
+                record.callchain.push(frame);
+                in_callchain = true;
+                continue;
+            }
+        }
+        match parse_header(&line) {
+            Some(mut record) => {
+                record.timestamp = timestamp_offset.apply(record.timestamp)?;
+                if let Some(record) = pending.replace(record) {
+                    f(record);
+                }
+            }
+            None => log::warn!("{:?}:{}: skip broken line '{}'", filepath, i + 1, line),
+        }
+    }
+    if let Some(record) = pending {
+        f(record);
+    }
+    Ok(())
+}
+
+// 'prev_comm=foo bar prev_pid=123' -> {"prev_comm": "foo bar", "prev_pid": 123}
+// NOTE: text before the first 'key=value' is kept as 'detail' e.g. '(401136 <- 401200)'
+pub fn parse_event_args(args: &str) -> chrome::Args {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut detail: Vec<&str> = Vec::new();
+    // NOTE: '==>' separates prev and next of sched:sched_switch
+    for token in args.split_whitespace().filter(|token| *token != "==>") {
+        match token.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !key.contains(['(', '"']) => {
+                fields.push((key.to_string(), value.to_string()))
+            }
+            _ => match fields.last_mut() {
+                Some((_, value)) => {
+                    value.push(' ');
+                    value.push_str(token);
+                }
+                None => detail.push(token),
+            },
+        }
+    }
+    let mut event_args = chrome::Args::new();
+    if !detail.is_empty() {
+        event_args.insert(String::from("detail"), detail.join(" ").into());
+    }
+    for (key, value) in fields {
+        event_args.insert(key, return_value(&value));
+    }
+    event_args
+}
+
+// stackFrames of JSON object format
+#[derive(Default)]
+pub struct StackFrameTable {
+    ids: HashMap<(Option<String>, String, String), String>,
+    stack_frames: BTreeMap<String, chrome::StackFrame>,
+}
+
+// 'do_syscall_64+0x5b' -> 'do_syscall_64'
+fn strip_symbol_offset(symbol: &str) -> &str {
+    match symbol.rsplit_once("+0x") {
+        Some((name, offset))
+            if !name.is_empty() && offset.bytes().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            name
+        }
+        _ => symbol,
+    }
+}
+
+impl StackFrameTable {
+    // returns id of the leaf frame
+    // NOTE: offset of symbol is stripped to merge frames of the same function
+    pub fn intern(&mut self, callchain: &[Frame]) -> Option<String> {
+        let mut parent: Option<String> = None;
+        for frame in callchain.iter().rev() {
+            let name = if frame.symbol.is_empty() || frame.symbol == "[unknown]" {
+                format!("0x{}", frame.address)
+            } else {
+                strip_symbol_offset(&frame.symbol).to_string()
+            };
+            let key = (parent.clone(), name, frame.dso.clone());
+            let next_id = self.ids.len().to_string();
+            let id = self
+                .ids
+                .entry(key.clone())
+                .or_insert_with(|| next_id)
+                .clone();
+            self.stack_frames
+                .entry(id.clone())
+                .or_insert_with(|| chrome::StackFrame {
+                    category: key.2,
+                    name: key.1,
+                    parent: key.0,
+                });
+            parent = Some(id);
+        }
+        parent
+    }
+
+    pub fn into_stack_frames(self) -> BTreeMap<String, chrome::StackFrame> {
+        self.stack_frames
+    }
+}
+
+const RETURN_PROBE_SUFFIX: &str = "__return";
+
+// 'sched:sched_switch' -> ('sched', 'sched_switch')
+fn split_event_name(event: &str) -> (&str, &str) {
+    event.split_once(':').unwrap_or(("", event))
+}
+
+// tracepoints -> instants, entry/exit probes -> slices, callchains -> samples, irqs -> per-CPU slices
+// NOTE: sched_switch events are also summarized as context switch statistics
+pub struct PerfScriptConverter {
+    // entry probes whose return probes have appeared e.g. 'probe:foo' of 'probe:foo__return'
+    entry_probes: HashSet<String>,
+    // (tid, event) -> indexes of instants in events which may be entries of return probes
+    // NOTE: an instant is turned into a slice when its return appears
+    open_probes: HashMap<(u32, String), Vec<usize>>,
+    stack_frame_table: StackFrameTable,
+    irq_tracker: IrqTracker,
+    sched_stats: SchedStats,
+    events: Vec<chrome::Event>,
+    last_timestamp: Duration,
+}
+
+impl PerfScriptConverter {
+    // counter_interval is interval of irq and context switch rate counters
+    pub fn new(counter_interval: Duration) -> PerfScriptConverter {
+        PerfScriptConverter {
+            entry_probes: HashSet::new(),
+            open_probes: HashMap::new(),
+            stack_frame_table: StackFrameTable::default(),
+            irq_tracker: IrqTracker::new(counter_interval),
+            sched_stats: SchedStats::new(counter_interval),
+            events: Vec::new(),
+            last_timestamp: Duration::from_micros(0),
+        }
+    }
+
+    fn new_event(record: &Record, name: &str, category: &str) -> chrome::Event {
+        let mut event = chrome::Event {
+            name: name.to_string(),
+            category: category.to_string(),
+            event_type: chrome::EventType::Instant,
+            timestamp: record.timestamp,
+            duration: Duration::from_micros(0),
+            process_id: record.pid.unwrap_or(record.tid).into(),
+            thread_id: record.tid.into(),
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: Some(parse_event_args(&record.args)),
+            stack_frame: None,
+        };
+        if let Some(cpu) = record.cpu {
+            event.set_arg("cpu", cpu);
+        }
+        event
+    }
+
+    pub fn convert(&mut self, record: &Record) {
+        self.last_timestamp = self.last_timestamp.max(record.timestamp);
+        self.irq_tracker.flush(record.timestamp, &mut self.events);
+        self.sched_stats.flush(record.timestamp, &mut self.events);
+        self.sched_stats.update(record);
+        if self.irq_tracker.convert(record, &mut self.events) {
+            return;
+        }
+        let (group, name) = split_event_name(&record.event);
+
+        if let Some(entry_probe) = record.event.strip_suffix(RETURN_PROBE_SUFFIX) {
+            if !self.entry_probes.contains(entry_probe) {
+                self.entry_probes.insert(entry_probe.to_string());
+            }
+            let key = (record.tid, entry_probe.to_string());
+            let entry_index = self.open_probes.get_mut(&key).and_then(|stack| stack.pop());
+            let event = match entry_index {
+                Some(index) => &mut self.events[index],
+                None => {
+                    log::warn!(
+                        "'{}' of tid {} at {:?} without entry",
+                        record.event,
+                        record.tid,
+                        record.timestamp
+                    );
+                    let mut event = Self::new_event(record, name, group);
+                    event.instant_scope = Some(chrome::InstantScope::Thread);
+                    self.events.push(event);
+                    return;
+                }
+            };
+            Self::close_entry(event, record.timestamp);
+            event.set_arg(
+                "return",
+                serde_json::Value::Object(parse_event_args(&record.args)),
+            );
+            return;
+        }
+        // NOTE: period is printed for sampling events (e.g. cpu-clock), but not for tracepoints
+        if !record.callchain.is_empty()
+            && record.period.is_some()
+            && !self.entry_probes.contains(&record.event)
+        {
+            let mut event = Self::new_event(record, &record.event, "sample");
+            event.event_type = chrome::EventType::Sample;
+            self.set_stack_frame(&mut event, record);
+            if let Some(period) = record.period {
+                event.set_arg("period", period);
+            }
+            self.events.push(event);
+            return;
+        }
+        let mut event = Self::new_event(record, name, group);
+        event.instant_scope = Some(chrome::InstantScope::Thread);
+        self.set_stack_frame(&mut event, record);
+        if let Some(period) = record.period {
+            event.set_arg("period", period);
+        }
+        self.open_probes
+            .entry((record.tid, record.event.clone()))
+            .or_default()
+            .push(self.events.len());
+        self.events.push(event);
+    }
+
+    // instant of an entry probe -> slice until end
+    fn close_entry(event: &mut chrome::Event, end: Duration) {
+        event.event_type = chrome::EventType::Complete;
+        event.instant_scope = None;
+        event.duration = end.saturating_sub(event.timestamp);
+    }
+
+    // stack frame of callchain and address and symbol with offset of the leaf frame
+    fn set_stack_frame(&mut self, event: &mut chrome::Event, record: &Record) {
+        let leaf = match record.callchain.first() {
+            Some(leaf) => leaf,
+            None => return,
+        };
+        event.stack_frame = self.stack_frame_table.intern(&record.callchain);
+        event.set_arg("ip", format!("0x{}", leaf.address));
+        if !leaf.symbol.is_empty() && leaf.symbol != "[unknown]" {
+            event.set_arg("symoff", leaf.symbol.as_str());
+        }
+    }
+
+    // entry probes and irqs which do not return last until the end of trace
+    pub fn finish(
+        mut self,
+    ) -> (
+        Vec<chrome::Event>,
+        BTreeMap<String, chrome::StackFrame>,
+        SchedStats,
+    ) {
+        for ((_, event_name), stack) in self.open_probes.drain() {
+            if !self.entry_probes.contains(&event_name) {
+                continue;
+            }
+            for index in stack {
+                let event = &mut self.events[index];
+                Self::close_entry(event, self.last_timestamp);
+                event.set_arg("unfinished", true);
+            }
+        }
+        self.irq_tracker
+            .finish(self.last_timestamp, &mut self.events);
+        self.sched_stats
+            .finish(self.last_timestamp, &mut self.events);
+        (
+            self.events,
+            self.stack_frame_table.into_stack_frames(),
+            self.sched_stats,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(header: &str, callchain: &[&str]) -> Record {
+        let mut record = parse_header(header).unwrap();
+        record.callchain = callchain
+            .iter()
+            .map(|line| parse_frame(line).unwrap())
+            .collect();
+        record
+    }
+
+    #[test]
+    fn symbol_offset() {
+        assert_eq!(strip_symbol_offset("do_syscall_64+0x5b"), "do_syscall_64");
+        assert_eq!(strip_symbol_offset("bar(int, char)+0x6"), "bar(int, char)");
+        assert_eq!(strip_symbol_offset("operator+"), "operator+");
+        assert_eq!(strip_symbol_offset("+0x10"), "+0x10");
+    }
+
+    #[test]
+    fn stack_frames() {
+        let records = vec![
+            record(
+                "myapp  1234/1235 [003] 12345.679100:     250000 cpu-clock:pppH: ",
+                &["\tffffffff81001234 do_syscall_64+0x5b ([kernel.kallsyms])", "\t401136 main+0x16 (/usr/bin/myapp)"],
+            ),
+            record(
+                "myapp  1234/1235 [003] 12345.679200:     250000 cpu-clock:pppH: ",
+                &["\tffffffff81001240 do_syscall_64+0x67 ([kernel.kallsyms])", "\t401140 main+0x20 (/usr/bin/myapp)"],
+            ),
+            record(
+                "myapp  1234/1235 [003] 12345.679300: sched:sched_wakeup: comm=a pid=1 prio=120 target_cpu=001",
+                &["\tffffffff81001240 do_syscall_64+0x67 ([kernel.kallsyms])"],
+            ),
+        ];
+        let mut converter = PerfScriptConverter::new(Duration::from_secs(1));
+        for record in &records {
+            converter.convert(record);
+        }
+        let (events, stack_frames, _) = converter.finish();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].event_type, chrome::EventType::Sample);
+        assert_eq!(events[0].stack_frame, events[1].stack_frame);
+        assert_eq!(events[1].arg("symoff"), Some(&"do_syscall_64+0x67".into()));
+        assert_eq!(events[1].arg("ip"), Some(&"0xffffffff81001240".into()));
+        // NOTE: tracepoint with callchain is not a sample
+        assert_eq!(events[2].event_type, chrome::EventType::Instant);
+        assert!(events[2].stack_frame.is_some());
+        assert_eq!(stack_frames.len(), 3);
+        assert_eq!(
+            stack_frames[events[0].stack_frame.as_ref().unwrap()].name,
+            "do_syscall_64"
+        );
+    }
+
+    #[test]
+    fn return_probes() {
+        let mut converter = PerfScriptConverter::new(Duration::from_secs(1));
+        for header in [
+            "myapp  1235 [000] 1.000000: probe_myapp:foo: (401136) n=2",
+            "myapp  1235 [000] 1.100000: probe_myapp:foo: (401136) n=1",
+            "myapp  1235 [000] 1.200000: probe_myapp:foo__return: (401136 <- 401200) ret=1",
+            "myapp  1235 [000] 1.300000: sched:sched_wakeup: comm=a pid=1",
+            "myapp  1235 [000] 1.500000: probe_myapp:foo: (401136) n=3",
+        ] {
+            converter.convert(&record(header, &[]));
+        }
+        let (events, _, _) = converter.finish();
+        assert_eq!(events.len(), 4);
+        // NOTE: the outer call does not return until the end of trace
+        assert_eq!(events[0].event_type, chrome::EventType::Complete);
+        assert_eq!(events[0].duration, Duration::from_millis(500));
+        assert_eq!(events[0].arg("unfinished"), Some(&true.into()));
+        // NOTE: the inner call is paired with the return which appears first
+        assert_eq!(events[1].event_type, chrome::EventType::Complete);
+        assert_eq!(events[1].instant_scope, None);
+        assert_eq!(events[1].duration, Duration::from_millis(100));
+        assert_eq!(events[1].arg("n"), Some(&1.into()));
+        assert_eq!(
+            events[1].arg("return").and_then(|value| value.get("ret")),
+            Some(&1.into())
+        );
+        assert_eq!(events[2].event_type, chrome::EventType::Instant);
+        assert_eq!(events[3].event_type, chrome::EventType::Complete);
+        assert_eq!(events[3].duration, Duration::from_millis(0));
+    }
+
+    #[test]
+    fn read_callchains() {
+        let filepath = std::env::temp_dir().join(format!("perf-script-{}.txt", std::process::id()));
+        std::fs::write(
+            &filepath,
+            "myapp  1235 [000] 1.000000: 250000 cpu-clock:pppH: \n\
+             \t401136 main+0x16 (/usr/bin/myapp)\n\
+             \t401000 _start+0x2 (/usr/bin/myapp)\n\
+             \n\
+             myapp  1235 [000] 1.100000: sched:sched_wakeup: comm=a pid=1\n\
+             broken line\n\
+             myapp  1235 [000] 1.200000: 250000 cpu-clock:pppH: \n\
+             \tffffffff81001234 do_syscall_64+0x5b ([kernel.kallsyms])\n",
+        )
+        .unwrap();
+        let mut records = Vec::new();
+        let result = read_records(&filepath, TimestampOffset::default(), |record| {
+            records.push(record)
+        });
+        std::fs::remove_file(&filepath).unwrap();
+        result.unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].callchain.len(), 2);
+        assert_eq!(records[0].callchain[1].symbol, "_start+0x2");
+        assert!(records[1].callchain.is_empty());
+        assert_eq!(records[2].callchain[0].dso, "[kernel.kallsyms]");
+    }
+}
@@ -0,0 +1,874 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::chrome;
+use crate::chrome::{EventType, TrackId};
+use crate::hash;
+
+// protobuf field numbers of perfetto/protos/perfetto/trace/*.proto
+mod field {
+    pub const TRACE_PACKET: u32 = 1;
+
+    pub const PACKET_TIMESTAMP: u32 = 8;
+    pub const PACKET_TRUSTED_PACKET_SEQUENCE_ID: u32 = 10;
+    pub const PACKET_TRACK_EVENT: u32 = 11;
+    pub const PACKET_INTERNED_DATA: u32 = 12;
+    pub const PACKET_SEQUENCE_FLAGS: u32 = 13;
+    pub const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+    pub const PACKET_FIRST_PACKET_ON_SEQUENCE: u32 = 87;
+
+    pub const TRACK_UUID: u32 = 1;
+    pub const TRACK_NAME: u32 = 2;
+    pub const TRACK_PROCESS: u32 = 3;
+    pub const TRACK_THREAD: u32 = 4;
+    pub const TRACK_PARENT_UUID: u32 = 5;
+    pub const TRACK_COUNTER: u32 = 8;
+
+    pub const PROCESS_PID: u32 = 1;
+    pub const PROCESS_NAME: u32 = 6;
+
+    pub const THREAD_PID: u32 = 1;
+    pub const THREAD_TID: u32 = 2;
+    pub const THREAD_NAME: u32 = 5;
+
+    pub const EVENT_CATEGORY_IIDS: u32 = 3;
+    pub const EVENT_DEBUG_ANNOTATIONS: u32 = 4;
+    pub const EVENT_TYPE: u32 = 9;
+    pub const EVENT_NAME_IID: u32 = 10;
+    pub const EVENT_TRACK_UUID: u32 = 11;
+    pub const EVENT_COUNTER_VALUE: u32 = 30;
+    pub const EVENT_DOUBLE_COUNTER_VALUE: u32 = 44;
+    pub const EVENT_FLOW_IDS: u32 = 47;
+    pub const EVENT_TERMINATING_FLOW_IDS: u32 = 48;
+
+    pub const ANNOTATION_NAME_IID: u32 = 1;
+    pub const ANNOTATION_BOOL: u32 = 2;
+    pub const ANNOTATION_UINT: u32 = 3;
+    pub const ANNOTATION_INT: u32 = 4;
+    pub const ANNOTATION_DOUBLE: u32 = 5;
+    pub const ANNOTATION_STRING: u32 = 6;
+
+    pub const INTERNED_EVENT_CATEGORIES: u32 = 1;
+    pub const INTERNED_EVENT_NAMES: u32 = 2;
+    pub const INTERNED_DEBUG_ANNOTATION_NAMES: u32 = 3;
+    pub const INTERNED_IID: u32 = 1;
+    pub const INTERNED_NAME: u32 = 2;
+}
+
+// TrackEvent.Type
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+// TracePacket.SequenceFlags
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+const TRUSTED_PACKET_SEQUENCE_ID: u64 = 1;
+
+const WIRE_TYPE_VARINT: u32 = 0;
+const WIRE_TYPE_FIXED64: u32 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u32 = 2;
+
+// minimal protobuf encoder
+#[derive(Default)]
+struct ProtoEncoder {
+    buffer: Vec<u8>,
+}
+
+impl ProtoEncoder {
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buffer.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.push(value as u8);
+    }
+
+    fn write_tag(&mut self, field_number: u32, wire_type: u32) {
+        self.write_varint(((field_number << 3) | wire_type) as u64);
+    }
+
+    fn varint(&mut self, field_number: u32, value: u64) {
+        self.write_tag(field_number, WIRE_TYPE_VARINT);
+        self.write_varint(value);
+    }
+
+    // NOTE: int64 is encoded as two's complement (not zigzag)
+    fn int64(&mut self, field_number: u32, value: i64) {
+        self.varint(field_number, value as u64);
+    }
+
+    fn fixed64(&mut self, field_number: u32, value: u64) {
+        self.write_tag(field_number, WIRE_TYPE_FIXED64);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn double(&mut self, field_number: u32, value: f64) {
+        self.fixed64(field_number, value.to_bits());
+    }
+
+    fn bytes(&mut self, field_number: u32, value: &[u8]) {
+        self.write_tag(field_number, WIRE_TYPE_LENGTH_DELIMITED);
+        self.write_varint(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field_number: u32, value: &str) {
+        self.bytes(field_number, value.as_bytes());
+    }
+
+    fn message(&mut self, field_number: u32, message: &ProtoEncoder) {
+        self.bytes(field_number, &message.buffer);
+    }
+}
+
+#[derive(Default)]
+struct InternTable {
+    iids: HashMap<String, u64>,
+}
+
+impl InternTable {
+    // iid of the string and new entry is added to interned data
+    fn intern(&mut self, name: &str, interned_data: &mut ProtoEncoder, field_number: u32) -> u64 {
+        if let Some(iid) = self.iids.get(name) {
+            return *iid;
+        }
+        // NOTE: iid 0 is invalid
+        let iid = self.iids.len() as u64 + 1;
+        self.iids.insert(name.to_string(), iid);
+        let mut entry = ProtoEncoder::default();
+        entry.varint(field::INTERNED_IID, iid);
+        entry.string(field::INTERNED_NAME, name);
+        interned_data.message(field_number, &entry);
+        iid
+    }
+}
+
+// begin or end of a slice on a thread track
+struct SliceEvent {
+    key: chrome::SliceKey,
+    track_uuid: u64,
+    event_type: u64,
+    event: Option<chrome::Event>,
+}
+
+impl SliceEvent {
+    fn order_key(&self) -> (chrome::SliceKey, u64) {
+        (self.key, self.track_uuid)
+    }
+}
+
+impl PartialEq for SliceEvent {
+    fn eq(&self, other: &SliceEvent) -> bool {
+        self.order_key() == other.order_key()
+    }
+}
+
+impl Eq for SliceEvent {}
+
+impl PartialOrd for SliceEvent {
+    fn partial_cmp(&self, other: &SliceEvent) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SliceEvent {
+    fn cmp(&self, other: &SliceEvent) -> std::cmp::Ordering {
+        self.order_key().cmp(&other.order_key())
+    }
+}
+
+// number of begin and end of slices which are buffered to be sorted
+const SLICE_WINDOW: usize = 1 << 16;
+
+// convert chrome::Event stream into perfetto TracePacket stream
+// NOTE: trace processor sorts packets by timestamp but keeps the order of packets with the same timestamp,
+// so slices are sorted within a bounded window to keep nesting of complete events
+// (e.g. 'X' of iftracer comes at the end of the function, so children come before the parent)
+pub struct PerfettoWriter<W: Write> {
+    writer: W,
+    next_uuid: u64,
+    process_tracks: HashMap<TrackId, u64>,
+    thread_tracks: HashMap<(TrackId, TrackId), u64>,
+    // async, counter and global tracks
+    named_tracks: HashMap<String, u64>,
+    event_names: InternTable,
+    categories: InternTable,
+    annotation_names: InternTable,
+    slice_events: BinaryHeap<Reverse<SliceEvent>>,
+    slice_window: usize,
+    slice_order: usize,
+    // track uuid -> key of the last written begin or end of slice
+    last_slice_keys: HashMap<u64, chrome::SliceKey>,
+    // begin or end of slices which come after later ones on the same track are written
+    late_slice_count: usize,
+    event_count: usize,
+    unsupported_count: usize,
+}
+
+impl<W: Write> PerfettoWriter<W> {
+    pub fn new(writer: W) -> Result<PerfettoWriter<W>> {
+        let mut perfetto_writer = PerfettoWriter {
+            writer,
+            next_uuid: 1,
+            process_tracks: HashMap::new(),
+            thread_tracks: HashMap::new(),
+            named_tracks: HashMap::new(),
+            event_names: InternTable::default(),
+            categories: InternTable::default(),
+            annotation_names: InternTable::default(),
+            slice_events: BinaryHeap::new(),
+            slice_window: SLICE_WINDOW,
+            slice_order: 0,
+            last_slice_keys: HashMap::new(),
+            late_slice_count: 0,
+            event_count: 0,
+            unsupported_count: 0,
+        };
+        let mut packet = ProtoEncoder::default();
+        packet.varint(
+            field::PACKET_TRUSTED_PACKET_SEQUENCE_ID,
+            TRUSTED_PACKET_SEQUENCE_ID,
+        );
+        packet.varint(field::PACKET_SEQUENCE_FLAGS, SEQ_INCREMENTAL_STATE_CLEARED);
+        packet.varint(field::PACKET_FIRST_PACKET_ON_SEQUENCE, 1);
+        perfetto_writer.write_packet(&packet)?;
+        Ok(perfetto_writer)
+    }
+
+    fn write_packet(&mut self, packet: &ProtoEncoder) -> Result<()> {
+        let mut trace = ProtoEncoder::default();
+        trace.message(field::TRACE_PACKET, packet);
+        self.writer.write_all(&trace.buffer)?;
+        Ok(())
+    }
+
+    fn write_track_descriptor(&mut self, track_descriptor: &ProtoEncoder) -> Result<()> {
+        let mut packet = ProtoEncoder::default();
+        packet.varint(
+            field::PACKET_TRUSTED_PACKET_SEQUENCE_ID,
+            TRUSTED_PACKET_SEQUENCE_ID,
+        );
+        packet.message(field::PACKET_TRACK_DESCRIPTOR, track_descriptor);
+        self.write_packet(&packet)
+    }
+
+    fn new_uuid(&mut self) -> u64 {
+        let uuid = self.next_uuid;
+        self.next_uuid += 1;
+        uuid
+    }
+
+    fn process_track(&mut self, pid: &TrackId, name: Option<&str>) -> Result<u64> {
+        let (uuid, is_new) = match self.process_tracks.get(pid) {
+            Some(uuid) => (*uuid, false),
+            None => {
+                let uuid = self.new_uuid();
+                self.process_tracks.insert(pid.clone(), uuid);
+                (uuid, true)
+            }
+        };
+        // NOTE: track descriptor is written again to update the name
+        if is_new || name.is_some() {
+            let mut track_descriptor = ProtoEncoder::default();
+            track_descriptor.varint(field::TRACK_UUID, uuid);
+            match pid {
+                TrackId::Number(pid) => {
+                    let mut process = ProtoEncoder::default();
+                    process.varint(field::PROCESS_PID, *pid as u64);
+                    if let Some(name) = name {
+                        process.string(field::PROCESS_NAME, name);
+                    }
+                    track_descriptor.message(field::TRACK_PROCESS, &process);
+                }
+                TrackId::Name(pid) => {
+                    track_descriptor.string(field::TRACK_NAME, name.unwrap_or(pid));
+                }
+            }
+            self.write_track_descriptor(&track_descriptor)?;
+        }
+        Ok(uuid)
+    }
+
+    fn thread_track(&mut self, pid: &TrackId, tid: &TrackId, name: Option<&str>) -> Result<u64> {
+        let key = (pid.clone(), tid.clone());
+        let (uuid, is_new) = match self.thread_tracks.get(&key) {
+            Some(uuid) => (*uuid, false),
+            None => {
+                let uuid = self.new_uuid();
+                self.thread_tracks.insert(key, uuid);
+                (uuid, true)
+            }
+        };
+        if is_new || name.is_some() {
+            let parent_uuid = self.process_track(pid, None)?;
+            let mut track_descriptor = ProtoEncoder::default();
+            track_descriptor.varint(field::TRACK_UUID, uuid);
+            match (pid, tid) {
+                (TrackId::Number(pid), TrackId::Number(tid)) => {
+                    let mut thread = ProtoEncoder::default();
+                    thread.varint(field::THREAD_PID, *pid as u64);
+                    thread.varint(field::THREAD_TID, *tid as u64);
+                    if let Some(name) = name {
+                        thread.string(field::THREAD_NAME, name);
+                    }
+                    track_descriptor.message(field::TRACK_THREAD, &thread);
+                }
+                // NOTE: string tid e.g. "CPU 3" is a named track in the process
+                _ => {
+                    let tid_name = tid.to_string();
+                    track_descriptor.varint(field::TRACK_PARENT_UUID, parent_uuid);
+                    track_descriptor.string(field::TRACK_NAME, name.unwrap_or(&tid_name));
+                }
+            }
+            self.write_track_descriptor(&track_descriptor)?;
+        }
+        Ok(uuid)
+    }
+
+    fn named_track(
+        &mut self,
+        key: String,
+        name: &str,
+        parent_uuid: Option<u64>,
+        counter: bool,
+    ) -> Result<u64> {
+        if let Some(uuid) = self.named_tracks.get(&key) {
+            return Ok(*uuid);
+        }
+        let uuid = self.new_uuid();
+        self.named_tracks.insert(key, uuid);
+        let mut track_descriptor = ProtoEncoder::default();
+        track_descriptor.varint(field::TRACK_UUID, uuid);
+        track_descriptor.string(field::TRACK_NAME, name);
+        if let Some(parent_uuid) = parent_uuid {
+            track_descriptor.varint(field::TRACK_PARENT_UUID, parent_uuid);
+        }
+        if counter {
+            track_descriptor.message(field::TRACK_COUNTER, &ProtoEncoder::default());
+        }
+        self.write_track_descriptor(&track_descriptor)?;
+        Ok(uuid)
+    }
+
+    fn write_track_event(
+        &mut self,
+        event: &chrome::Event,
+        timestamp_ns: u64,
+        track_uuid: u64,
+        event_type: u64,
+        build: impl FnOnce(&mut ProtoEncoder),
+    ) -> Result<()> {
+        let mut interned_data = ProtoEncoder::default();
+        let mut track_event = ProtoEncoder::default();
+        track_event.varint(field::EVENT_TYPE, event_type);
+        track_event.varint(field::EVENT_TRACK_UUID, track_uuid);
+        if event_type != TYPE_SLICE_END && event_type != TYPE_COUNTER {
+            if !event.category.is_empty() {
+                let iid = self.categories.intern(
+                    &event.category,
+                    &mut interned_data,
+                    field::INTERNED_EVENT_CATEGORIES,
+                );
+                track_event.varint(field::EVENT_CATEGORY_IIDS, iid);
+            }
+            let iid = self.event_names.intern(
+                &event.name,
+                &mut interned_data,
+                field::INTERNED_EVENT_NAMES,
+            );
+            track_event.varint(field::EVENT_NAME_IID, iid);
+            if let Some(args) = &event.args {
+                for (key, value) in args {
+                    let iid = self.annotation_names.intern(
+                        key,
+                        &mut interned_data,
+                        field::INTERNED_DEBUG_ANNOTATION_NAMES,
+                    );
+                    let mut annotation = ProtoEncoder::default();
+                    annotation.varint(field::ANNOTATION_NAME_IID, iid);
+                    encode_annotation_value(&mut annotation, value);
+                    track_event.message(field::EVENT_DEBUG_ANNOTATIONS, &annotation);
+                }
+            }
+        }
+        build(&mut track_event);
+
+        let mut packet = ProtoEncoder::default();
+        packet.varint(field::PACKET_TIMESTAMP, timestamp_ns);
+        packet.varint(
+            field::PACKET_TRUSTED_PACKET_SEQUENCE_ID,
+            TRUSTED_PACKET_SEQUENCE_ID,
+        );
+        packet.varint(field::PACKET_SEQUENCE_FLAGS, SEQ_NEEDS_INCREMENTAL_STATE);
+        if !interned_data.buffer.is_empty() {
+            packet.message(field::PACKET_INTERNED_DATA, &interned_data);
+        }
+        packet.message(field::PACKET_TRACK_EVENT, &track_event);
+        self.write_packet(&packet)
+    }
+
+    fn write_metadata_event(&mut self, event: &chrome::Event) -> Result<()> {
+        let name = event
+            .arg("name")
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_string());
+        match (event.name.as_str(), name) {
+            ("process_name", Some(name)) => {
+                self.process_track(&event.process_id, Some(&name))?;
+            }
+            ("thread_name", Some(name)) => {
+                self.thread_track(&event.process_id, &event.thread_id, Some(&name))?;
+            }
+            // NOTE: sort index and labels are not supported
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn write_event(&mut self, event: &chrome::Event) -> Result<()> {
+        let timestamp_ns = event.timestamp.as_nanos() as u64;
+        match event.event_type {
+            EventType::Metadata => {
+                self.write_metadata_event(event)?;
+            }
+            EventType::DurationBegin | EventType::DurationEnd | EventType::Complete => {
+                let track_uuid = self.thread_track(&event.process_id, &event.thread_id, None)?;
+                self.push_slice_event(event, track_uuid)?;
+            }
+            EventType::Instant => {
+                let track_uuid = match event.instant_scope {
+                    Some(chrome::InstantScope::Global) => {
+                        self.named_track(String::from("global"), "Global", None, false)?
+                    }
+                    Some(chrome::InstantScope::Process) => {
+                        self.process_track(&event.process_id, None)?
+                    }
+                    _ => self.thread_track(&event.process_id, &event.thread_id, None)?,
+                };
+                self.write_track_event(event, timestamp_ns, track_uuid, TYPE_INSTANT, |_| {})?;
+            }
+            EventType::AsyncNestableStart
+            | EventType::AsyncNestableInstant
+            | EventType::AsyncNestableEnd => {
+                // NOTE: one track per async id like chrome://tracing
+                let parent_uuid = self.process_track(&event.process_id, None)?;
+                let key = format!(
+                    "async:{}:{}:{}:{}",
+                    event.process_id,
+                    event.category,
+                    event.scope.as_deref().unwrap_or(""),
+                    event.id.as_deref().unwrap_or("")
+                );
+                let track_uuid = self.named_track(key, &event.name, Some(parent_uuid), false)?;
+                let event_type = match event.event_type {
+                    EventType::AsyncNestableStart => TYPE_SLICE_BEGIN,
+                    EventType::AsyncNestableEnd => TYPE_SLICE_END,
+                    _ => TYPE_INSTANT,
+                };
+                self.write_track_event(event, timestamp_ns, track_uuid, event_type, |_| {})?;
+            }
+            EventType::Counter => {
+                let parent_uuid = self.process_track(&event.process_id, None)?;
+                let empty_args = chrome::Args::new();
+                for (key, value) in event.args.as_ref().unwrap_or(&empty_args) {
+                    let name = format!("{} {}", event.name, key);
+                    let track_key = format!("counter:{}:{}", event.process_id, name);
+                    let track_uuid = self.named_track(track_key, &name, Some(parent_uuid), true)?;
+                    let build = |track_event: &mut ProtoEncoder| {
+                        if let Some(value) = value.as_i64() {
+                            track_event.int64(field::EVENT_COUNTER_VALUE, value);
+                        } else if let Some(value) = value.as_f64() {
+                            track_event.double(field::EVENT_DOUBLE_COUNTER_VALUE, value);
+                        }
+                    };
+                    if value.is_number() {
+                        self.write_track_event(
+                            event,
+                            timestamp_ns,
+                            track_uuid,
+                            TYPE_COUNTER,
+                            build,
+                        )?;
+                    }
+                }
+            }
+            EventType::FlowStart | EventType::FlowStep | EventType::FlowEnd => {
+                // NOTE: flow is attached to an instant event instead of the enclosing slice
+                let track_uuid = self.thread_track(&event.process_id, &event.thread_id, None)?;
+                let flow_id = flow_id(&event.category, event.id.as_deref().unwrap_or(""));
+                let flow_field = if event.event_type == EventType::FlowEnd {
+                    field::EVENT_TERMINATING_FLOW_IDS
+                } else {
+                    field::EVENT_FLOW_IDS
+                };
+                self.write_track_event(
+                    event,
+                    timestamp_ns,
+                    track_uuid,
+                    TYPE_INSTANT,
+                    |track_event| track_event.fixed64(flow_field, flow_id),
+                )?;
+            }
+            _ => {
+                self.unsupported_count += 1;
+                return Ok(());
+            }
+        }
+        self.event_count += 1;
+        Ok(())
+    }
+
+    fn push_slice_event(&mut self, event: &chrome::Event, track_uuid: u64) -> Result<()> {
+        let order = self.slice_order;
+        self.slice_order += 1;
+        let (begin_key, end_key) = match event.event_type {
+            EventType::DurationBegin => (
+                Some(chrome::slice_begin_key(event.timestamp, None, order)),
+                None,
+            ),
+            EventType::DurationEnd => (
+                None,
+                Some(chrome::slice_end_key(None, event.timestamp, order)),
+            ),
+            _ => {
+                let end_timestamp = event.timestamp + event.duration;
+                (
+                    Some(chrome::slice_begin_key(
+                        event.timestamp,
+                        Some(end_timestamp),
+                        order,
+                    )),
+                    Some(chrome::slice_end_key(
+                        Some(event.timestamp),
+                        end_timestamp,
+                        order,
+                    )),
+                )
+            }
+        };
+        let slice_events = begin_key
+            .map(|key| (key, TYPE_SLICE_BEGIN, Some(event.clone())))
+            .into_iter()
+            .chain(end_key.map(|key| (key, TYPE_SLICE_END, None)));
+        for (key, event_type, event) in slice_events {
+            if matches!(self.last_slice_keys.get(&track_uuid), Some(last_key) if key < *last_key) {
+                self.late_slice_count += 1;
+            }
+            self.slice_events.push(Reverse(SliceEvent {
+                key,
+                track_uuid,
+                event_type,
+                event,
+            }));
+        }
+        while self.slice_events.len() > self.slice_window {
+            self.write_slice_event()?;
+        }
+        Ok(())
+    }
+
+    // write the first begin or end of slice in the window
+    fn write_slice_event(&mut self) -> Result<()> {
+        let Reverse(slice_event) = match self.slice_events.pop() {
+            Some(slice_event) => slice_event,
+            None => return Ok(()),
+        };
+        self.last_slice_keys
+            .insert(slice_event.track_uuid, slice_event.key);
+        let empty_event = chrome::Event::default();
+        self.write_track_event(
+            slice_event.event.as_ref().unwrap_or(&empty_event),
+            slice_event.key.0.as_nanos() as u64,
+            slice_event.track_uuid,
+            slice_event.event_type,
+            |_| {},
+        )
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.event_count
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        while !self.slice_events.is_empty() {
+            self.write_slice_event()?;
+        }
+        if self.late_slice_count > 0 {
+            log::warn!(
+                "{} begin or end of slices are out of the sorting window ({} slices), nesting of slices at the same timestamp may be broken",
+                self.late_slice_count,
+                self.slice_window
+            );
+        }
+        if self.unsupported_count > 0 {
+            log::warn!(
+                "{} events are skipped because perfetto format does not support the phase",
+                self.unsupported_count
+            );
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> chrome::EventWriter<W> for PerfettoWriter<W> {
+    fn write_event(&mut self, event: &chrome::Event) -> Result<()> {
+        PerfettoWriter::write_event(self, event)
+    }
+
+    fn event_count(&self) -> usize {
+        PerfettoWriter::event_count(self)
+    }
+
+    fn finish(self: Box<Self>) -> Result<W> {
+        PerfettoWriter::finish(*self)
+    }
+}
+
+fn encode_annotation_value(annotation: &mut ProtoEncoder, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Bool(value) => annotation.varint(field::ANNOTATION_BOOL, *value as u64),
+        serde_json::Value::Number(n) => {
+            if let Some(value) = n.as_u64() {
+                annotation.varint(field::ANNOTATION_UINT, value);
+            } else if let Some(value) = n.as_i64() {
+                annotation.int64(field::ANNOTATION_INT, value);
+            } else if let Some(value) = n.as_f64() {
+                annotation.double(field::ANNOTATION_DOUBLE, value);
+            }
+        }
+        serde_json::Value::String(value) => annotation.string(field::ANNOTATION_STRING, value),
+        // NOTE: nested values are stored as JSON string
+        value => annotation.string(field::ANNOTATION_STRING, &value.to_string()),
+    }
+}
+
+// FNV-1a of category and id
+fn flow_id(category: &str, id: &str) -> u64 {
+    hash::fnv1a(category.bytes().chain([0u8]).chain(id.bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn complete(name: &str, micros: u64, duration_micros: u64) -> chrome::Event {
+        chrome::Event {
+            name: name.to_string(),
+            event_type: EventType::Complete,
+            timestamp: Duration::from_micros(micros),
+            duration: Duration::from_micros(duration_micros),
+            process_id: 1.into(),
+            thread_id: 1.into(),
+            ..Default::default()
+        }
+    }
+
+    enum Value<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn read_varint(buffer: &[u8], offset: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buffer[*offset];
+            *offset += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    // (field number, value) of protobuf message
+    fn decode(buffer: &[u8]) -> Vec<(u32, Value<'_>)> {
+        let mut fields = Vec::new();
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let tag = read_varint(buffer, &mut offset);
+            let value = match tag as u32 & 0x7 {
+                WIRE_TYPE_VARINT => Value::Varint(read_varint(buffer, &mut offset)),
+                WIRE_TYPE_FIXED64 => {
+                    offset += 8;
+                    Value::Bytes(&buffer[offset - 8..offset])
+                }
+                _ => {
+                    let length = read_varint(buffer, &mut offset) as usize;
+                    offset += length;
+                    Value::Bytes(&buffer[offset - length..offset])
+                }
+            };
+            fields.push(((tag >> 3) as u32, value));
+        }
+        fields
+    }
+
+    fn varint(fields: &[(u32, Value)], field_number: u32) -> Option<u64> {
+        fields.iter().find_map(|(number, value)| match value {
+            Value::Varint(value) if *number == field_number => Some(*value),
+            _ => None,
+        })
+    }
+
+    fn messages<'a>(fields: &'a [(u32, Value<'a>)], field_number: u32) -> Vec<&'a [u8]> {
+        fields
+            .iter()
+            .filter_map(|(number, value)| match value {
+                Value::Bytes(bytes) if *number == field_number => Some(*bytes),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // (timestamp, name of begin or "" for end) of slices in written order
+    fn slice_order_with_window(
+        events: &[chrome::Event],
+        slice_window: usize,
+    ) -> Vec<(u64, String)> {
+        let mut perfetto_writer = PerfettoWriter::new(Vec::new()).unwrap();
+        perfetto_writer.slice_window = slice_window;
+        for event in events {
+            perfetto_writer.write_event(event).unwrap();
+            assert!(perfetto_writer.slice_events.len() <= slice_window);
+        }
+        let buffer = perfetto_writer.finish().unwrap();
+        let mut event_names: HashMap<u64, String> = HashMap::new();
+        let mut order = Vec::new();
+        for packet in messages(&decode(&buffer), field::TRACE_PACKET) {
+            let packet = decode(packet);
+            for interned_data in messages(&packet, field::PACKET_INTERNED_DATA) {
+                let interned_data = decode(interned_data);
+                for entry in messages(&interned_data, field::INTERNED_EVENT_NAMES) {
+                    let entry = decode(entry);
+                    let name = messages(&entry, field::INTERNED_NAME)[0];
+                    event_names.insert(
+                        varint(&entry, field::INTERNED_IID).unwrap(),
+                        String::from_utf8(name.to_vec()).unwrap(),
+                    );
+                }
+            }
+            if let Some(track_event) = messages(&packet, field::PACKET_TRACK_EVENT).first() {
+                let track_event = decode(track_event);
+                let name = match varint(&track_event, field::EVENT_NAME_IID) {
+                    Some(iid)
+                        if varint(&track_event, field::EVENT_TYPE) == Some(TYPE_SLICE_BEGIN) =>
+                    {
+                        event_names[&iid].clone()
+                    }
+                    _ => String::new(),
+                };
+                let timestamp_ns = varint(&packet, field::PACKET_TIMESTAMP).unwrap();
+                order.push((timestamp_ns / 1000, name));
+            }
+        }
+        order
+    }
+
+    fn slice_order(events: &[chrome::Event]) -> Vec<(u64, String)> {
+        slice_order_with_window(events, SLICE_WINDOW)
+    }
+
+    #[test]
+    fn complete_child_before_parent() {
+        let order = slice_order(&[
+            complete("child", 10, 5),
+            complete("parent", 10, 20),
+            complete("next", 30, 1),
+        ]);
+        assert_eq!(
+            order,
+            vec![
+                (10, "parent".to_string()),
+                (10, "child".to_string()),
+                (15, String::new()),
+                (30, String::new()),
+                (30, "next".to_string()),
+                (31, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn complete_zero_length() {
+        let order = slice_order(&[
+            complete("a", 10, 0),
+            complete("b", 10, 0),
+            complete("parent", 10, 5),
+        ]);
+        assert_eq!(
+            order,
+            vec![
+                (10, "parent".to_string()),
+                (10, "a".to_string()),
+                (10, String::new()),
+                (10, "b".to_string()),
+                (10, String::new()),
+                (15, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn slices_are_sorted_within_window() {
+        // 'X' of iftracer comes at the end of the function
+        let events = [
+            complete("a", 10, 1),
+            complete("b", 11, 1),
+            complete("c", 12, 1),
+            complete("parent", 10, 5),
+            complete("next", 20, 1),
+        ];
+        let sorted = vec![
+            (10, "parent".to_string()),
+            (10, "a".to_string()),
+            (11, String::new()),
+            (11, "b".to_string()),
+            (12, String::new()),
+            (12, "c".to_string()),
+            (13, String::new()),
+            (15, String::new()),
+            (20, "next".to_string()),
+            (21, String::new()),
+        ];
+        assert_eq!(slice_order_with_window(&events, 6), sorted);
+        // begin of parent comes after the begin of 'a' is written
+        let order = slice_order_with_window(&events, 2);
+        assert_eq!(order[0], (10, "a".to_string()));
+        assert_ne!(order, sorted);
+        let mut perfetto_writer = PerfettoWriter::new(Vec::new()).unwrap();
+        perfetto_writer.slice_window = 2;
+        for event in &events {
+            perfetto_writer.write_event(event).unwrap();
+        }
+        assert!(perfetto_writer.late_slice_count > 0);
+    }
+
+    #[test]
+    fn begin_end_events() {
+        let mut begin = complete("b", 10, 0);
+        begin.event_type = EventType::DurationBegin;
+        let mut end = complete("", 20, 0);
+        end.event_type = EventType::DurationEnd;
+        let order = slice_order(&[begin, complete("x", 10, 10), end]);
+        assert_eq!(
+            order,
+            vec![
+                (10, "b".to_string()),
+                (10, "x".to_string()),
+                (20, String::new()),
+                (20, String::new()),
+            ]
+        );
+    }
+}
@@ -1,42 +1,357 @@
 use anyhow::Context;
 use anyhow::Result;
+use object::{elf, Object, ObjectSegment, SegmentFlags};
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
-use std::path::PathBuf;
-
-pub fn parse_proc_maps(proc_maps_filename: impl AsRef<Path>) -> Result<HashMap<String, u64>> {
-    let input = fs::read_to_string(&proc_maps_filename).with_context(|| {
-        format!(
-            "parse_proc_maps(): Failed to open file {:?}",
-            proc_maps_filename.as_ref()
-        )
-    })?;
-    let maps = rsprocmaps::from_str(&input);
-
-    let mut filename2addr_map: HashMap<String, u64> = HashMap::new();
-
-    for map in maps {
-        if map.is_err() {
-            // NOTE: failed to parse without blank space or memory map name
-            continue;
-        }
-        let map = map?;
-        if map.offset == 0 {
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub shared: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub begin: u64,
+    pub end: u64,
+    pub offset: u64,
+    pub permissions: Permissions,
+    pub pathname: String,
+}
+
+impl Segment {
+    pub fn contains(&self, address: u64) -> bool {
+        self.begin <= address && address < self.end
+    }
+
+    pub fn distance(&self, address: u64) -> u64 {
+        if address < self.begin {
+            self.begin - address
+        } else if address >= self.end {
+            address - self.end + 1
+        } else {
+            0
+        }
+    }
+}
+
+// ELF PT_LOAD program header
+struct LoadSegment {
+    file_offset: u64,
+    file_size: u64,
+    virtual_address: u64,
+    executable: bool,
+}
+
+impl LoadSegment {
+    // max page size of supported architectures
+    const MAX_PAGE_SIZE: u64 = 0x10000;
+
+    // distance from mapped file offset, None if the mapping is not a part of this segment
+    fn distance(&self, mapped_offset: u64) -> Option<u64> {
+        if self.file_offset <= mapped_offset && mapped_offset < self.file_offset + self.file_size {
+            Some(0)
+        } else if mapped_offset < self.file_offset
+            && self.file_offset - mapped_offset < Self::MAX_PAGE_SIZE
+        {
+            // NOTE: the mapped file offset is aligned down to page size
+            Some(self.file_offset - mapped_offset)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleMap {
+    // NOTE: sorted by begin address
+    segments: Vec<Segment>,
+}
+
+impl ModuleMap {
+    pub fn parse(proc_maps_filename: impl AsRef<Path>) -> Result<ModuleMap> {
+        let input = fs::read_to_string(&proc_maps_filename).with_context(|| {
+            format!(
+                "ModuleMap::parse(): Failed to open file {:?}",
+                proc_maps_filename.as_ref()
+            )
+        })?;
+        Ok(ModuleMap::from_maps_str(&input))
+    }
+
+    pub fn from_maps_str(input: &str) -> ModuleMap {
+        let maps = rsprocmaps::from_str(input);
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for map in maps {
+            let map = match map {
+                Ok(map) => map,
+                // NOTE: failed to parse without blank space or memory map name
+                Err(_) => continue,
+            };
             if let rsprocmaps::Pathname::Path(path) = map.pathname {
-                let filepath = PathBuf::from(path);
-                filename2addr_map.insert(
-                    filepath
-                        .file_name()
-                        .with_context(|| format!("failed to parse file name from {:?}", filepath))?
-                        .to_string_lossy()
-                        .to_string(),
-                    map.address_range.begin,
-                );
+                segments.push(Segment {
+                    begin: map.address_range.begin,
+                    end: map.address_range.end,
+                    offset: map.offset,
+                    permissions: Permissions {
+                        readable: map.permissions.readable,
+                        writable: map.permissions.writable,
+                        executable: map.permissions.executable,
+                        shared: map.permissions.shared,
+                    },
+                    pathname: path,
+                });
             }
         }
+        segments.sort_by_key(|segment| segment.begin);
+        ModuleMap { segments }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn module_segments<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Segment> {
+        self.segments
+            .iter()
+            .filter(move |segment| segment.pathname == path)
     }
 
-    Ok(filename2addr_map)
+    // full paths of the modules which have an executable segment
+    pub fn executable_modules(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = Vec::new();
+        for segment in self.segments.iter().filter(|s| s.permissions.executable) {
+            if !paths.contains(&segment.pathname.as_str()) {
+                paths.push(segment.pathname.as_str());
+            }
+        }
+        paths
+    }
+
+    pub fn find_segment(&self, address: u64) -> Option<&Segment> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.begin <= address);
+        if index == 0 {
+            return None;
+        }
+        let segment = &self.segments[index - 1];
+        if segment.contains(address) {
+            Some(segment)
+        } else {
+            None
+        }
+    }
+
+    pub fn find_executable_segment(&self, address: u64) -> Option<&Segment> {
+        self.find_segment(address)
+            .filter(|segment| segment.permissions.executable)
+    }
+
+    pub fn find_nearest_segment(&self, address: u64) -> Option<&Segment> {
+        self.segments
+            .iter()
+            .min_by_key(|segment| segment.distance(address))
+    }
+
+    // load bias = runtime address - link time virtual address
+    // computed by matching the mapped file offset with ELF PT_LOAD program headers of elf_filepath
+    pub fn load_bias(&self, path: &str, elf_filepath: &Path) -> Result<u64> {
+        let data = fs::read(elf_filepath).with_context(|| {
+            format!(
+                "ModuleMap::load_bias(): Failed to open file {:?}",
+                elf_filepath
+            )
+        })?;
+        let elf = object::File::parse(&*data).with_context(|| {
+            format!(
+                "ModuleMap::load_bias(): Failed to parse ELF file {:?}",
+                elf_filepath
+            )
+        })?;
+        let loads: Vec<LoadSegment> = elf
+            .segments()
+            .map(|load| {
+                let (file_offset, file_size) = load.file_range();
+                let executable = match load.flags() {
+                    SegmentFlags::Elf { p_flags } => p_flags & elf::PF_X != 0,
+                    _ => false,
+                };
+                LoadSegment {
+                    file_offset,
+                    file_size,
+                    virtual_address: load.address(),
+                    executable,
+                }
+            })
+            .collect();
+        Ok(self.load_bias_of_loads(path, &loads))
+    }
+
+    fn load_bias_of_loads(&self, path: &str, loads: &[LoadSegment]) -> u64 {
+        // NOTE: prefer executable segments because read only segments may be merged or missing in dumps
+        let mut segments: Vec<&Segment> = self.module_segments(path).collect();
+        segments.sort_by_key(|segment| !segment.permissions.executable);
+        for segment in segments {
+            let load = loads
+                .iter()
+                .filter(|load| load.executable == segment.permissions.executable)
+                .filter_map(|load| load.distance(segment.offset).map(|d| (d, load)))
+                .min_by_key(|(distance, _)| *distance);
+            if let Some((_, load)) = load {
+                // NOTE: virtual address and file offset are congruent modulo page size
+                let link_address = load
+                    .virtual_address
+                    .wrapping_sub(load.file_offset)
+                    .wrapping_add(segment.offset);
+                return segment.begin.wrapping_sub(link_address);
+            }
+        }
+        self.fallback_load_bias(path)
+    }
+
+    // assume that file offset equals to link time virtual address
+    pub fn fallback_load_bias(&self, path: &str) -> u64 {
+        let mut segments: Vec<&Segment> = self.module_segments(path).collect();
+        segments.sort_by_key(|segment| (segment.offset != 0, !segment.permissions.executable));
+        segments
+            .first()
+            .map(|segment| segment.begin.wrapping_sub(segment.offset))
+            .unwrap_or(0)
+    }
+
+    // module path -> load bias for every executable module
+    pub fn load_biases(
+        &self,
+        resolve_filepath: impl Fn(&str) -> Option<std::path::PathBuf>,
+    ) -> BTreeMap<String, u64> {
+        let mut module2bias_map: BTreeMap<String, u64> = BTreeMap::new();
+        for path in self.executable_modules() {
+            let load_bias = match resolve_filepath(path) {
+                Some(elf_filepath) => match self.load_bias(path, &elf_filepath) {
+                    Ok(load_bias) => load_bias,
+                    Err(e) => {
+                        log::warn!("{:?}", e);
+                        self.fallback_load_bias(path)
+                    }
+                },
+                None => self.fallback_load_bias(path),
+            };
+            module2bias_map.insert(path.to_string(), load_bias);
+        }
+        module2bias_map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (file offset, file size, virtual address, executable)
+    fn loads(loads: &[(u64, u64, u64, bool)]) -> Vec<LoadSegment> {
+        loads
+            .iter()
+            .map(
+                |&(file_offset, file_size, virtual_address, executable)| LoadSegment {
+                    file_offset,
+                    file_size,
+                    virtual_address,
+                    executable,
+                },
+            )
+            .collect()
+    }
+
+    #[test]
+    fn load_bias_pie() {
+        let module_map = ModuleMap::from_maps_str(
+            "555555554000-555555555000 r--p 00000000 08:01 123 /usr/bin/app
+555555555000-555555556000 r-xp 00001000 08:01 123 /usr/bin/app
+555555556000-555555557000 r--p 00002000 08:01 123 /usr/bin/app
+555555557000-555555558000 r--p 00002000 08:01 123 /usr/bin/app
+555555558000-555555559000 rw-p 00003000 08:01 123 /usr/bin/app
+7ffff7fc3000-7ffff7fc5000 r-xp 00001000 08:01 456 /usr/lib/ld-linux-x86-64.so.2
+",
+        );
+        let loads = loads(&[
+            (0x0, 0x6a0, 0x0, false),
+            (0x1000, 0x2bd, 0x1000, true),
+            (0x2000, 0x1b4, 0x2000, false),
+            (0x2db8, 0x258, 0x3db8, false),
+        ]);
+        assert_eq!(
+            module_map.executable_modules(),
+            vec!["/usr/bin/app", "/usr/lib/ld-linux-x86-64.so.2"]
+        );
+        assert_eq!(
+            module_map.load_bias_of_loads("/usr/bin/app", &loads),
+            0x555555554000
+        );
+        assert_eq!(
+            module_map.fallback_load_bias("/usr/bin/app"),
+            0x555555554000
+        );
+    }
+
+    #[test]
+    fn load_bias_non_pie() {
+        let module_map = ModuleMap::from_maps_str(
+            "00400000-00401000 r--p 00000000 08:01 123 /usr/bin/app
+00401000-00402000 r-xp 00001000 08:01 123 /usr/bin/app
+00403000-00404000 rw-p 00002000 08:01 123 /usr/bin/app
+",
+        );
+        let loads = loads(&[
+            (0x0, 0x4f0, 0x400000, false),
+            (0x1000, 0x1a5, 0x401000, true),
+            (0x2e10, 0x220, 0x403e10, false),
+        ]);
+        assert_eq!(module_map.load_bias_of_loads("/usr/bin/app", &loads), 0);
+        assert_eq!(module_map.fallback_load_bias("/usr/bin/app"), 0x400000);
+    }
+
+    #[test]
+    fn load_bias_split_segment() {
+        // NOTE: executable PT_LOAD is not page aligned in the file, and its mapping is split by mprotect
+        let module_map = ModuleMap::from_maps_str(
+            "7f0000000000-7f0000001000 r--p 00000000 08:01 123 /usr/lib/libfoo.so
+7f0000001000-7f0000002000 r-xp 00001000 08:01 123 /usr/lib/libfoo.so
+7f0000002000-7f0000003000 r-xp 00002000 08:01 123 /usr/lib/libfoo.so
+",
+        );
+        let loads = loads(&[(0x0, 0xf40, 0x0, false), (0x1040, 0x1f00, 0x2040, true)]);
+        // runtime 7f0000001040 is link time 0x2040
+        assert_eq!(
+            module_map.load_bias_of_loads("/usr/lib/libfoo.so", &loads),
+            0x7f0000001040 - 0x2040
+        );
+        // without matching PT_LOAD
+        let loads = self::loads(&[(0x100000, 0x1000, 0x100000, true)]);
+        assert_eq!(
+            module_map.load_bias_of_loads("/usr/lib/libfoo.so", &loads),
+            0x7f0000000000
+        );
+    }
+
+    #[test]
+    fn load_bias_wrapped() {
+        // NOTE: prelinked library mapped below its link time address has negative bias
+        let module_map = ModuleMap::from_maps_str(
+            "00001000-00002000 r-xp 00001000 08:01 123 /usr/lib/libbar.so
+",
+        );
+        let loads = loads(&[(0x1000, 0x800, 0x40001000, true)]);
+        let load_bias = module_map.load_bias_of_loads("/usr/lib/libbar.so", &loads);
+        assert_eq!(load_bias, 0u64.wrapping_sub(0x40000000));
+        assert_eq!(0x1100u64.wrapping_sub(load_bias), 0x40001100);
+    }
 }
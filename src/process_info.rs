@@ -0,0 +1,429 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chrome;
+
+// process and thread names for metadata events
+#[derive(Debug, Default)]
+pub struct ProcessInfoTable {
+    process_names: HashMap<u32, String>,
+    process_cmdlines: HashMap<u32, String>,
+    thread_names: HashMap<u32, String>,
+    // tid -> pid
+    thread_pids: HashMap<u32, u32>,
+    process_sort_indexes: HashMap<u32, i64>,
+    thread_sort_indexes: HashMap<u32, i64>,
+}
+
+impl ProcessInfoTable {
+    // csv file which has 'pid,comm,cmdline' header e.g. data/pid-comm-cmdline.csv
+    // NOTE: pid column may be tid, so comm is used as thread name too
+    pub fn load_csv(&mut self, filepath: &Path) -> Result<()> {
+        let input = fs::read_to_string(filepath)
+            .with_context(|| format!("load_csv(): Failed to open file {:?}", filepath))?;
+        self.parse_csv(&input, filepath)
+    }
+
+    fn parse_csv(&mut self, input: &str, filepath: &Path) -> Result<()> {
+        let mut lines = input.lines();
+        let header = lines.next().unwrap_or("");
+        if header.trim() != "pid,comm,cmdline" {
+            return Err(anyhow!(
+                "{:?} has invalid header '{}' required header is 'pid,comm,cmdline'",
+                filepath,
+                header
+            ));
+        }
+        for (i, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // NOTE: cmdline may include ','
+            let fields: Vec<&str> = line.splitn(3, ',').collect();
+            let pid = match fields[0].trim().parse::<u32>() {
+                Ok(pid) => pid,
+                Err(_) => {
+                    log::warn!("{:?}:{}: invalid pid '{}'", filepath, i + 2, fields[0]);
+                    continue;
+                }
+            };
+            let comm = fields.get(1).map(|s| unquote(s)).unwrap_or_default();
+            let cmdline = fields.get(2).map(|s| unquote(s)).unwrap_or_default();
+            if !comm.is_empty() {
+                self.process_names.insert(pid, comm.clone());
+                self.thread_names.insert(pid, comm);
+            }
+            if !cmdline.is_empty() {
+                self.process_cmdlines.insert(pid, cmdline);
+            }
+        }
+        Ok(())
+    }
+
+    // snapshot of /proc (or /proc itself) which has $PID/comm, $PID/cmdline and $PID/task/$TID/comm
+    pub fn load_proc_dir(&mut self, proc_dir: &Path) -> Result<()> {
+        let entries = fs::read_dir(proc_dir)
+            .with_context(|| format!("load_proc_dir(): Failed to read directory {:?}", proc_dir))?;
+        for entry in entries {
+            let entry = entry?;
+            let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            let pid_dir = entry.path();
+            if let Some(comm) = read_comm(&pid_dir.join("comm")) {
+                self.process_names.insert(pid, comm);
+            }
+            if let Ok(cmdline) = fs::read(pid_dir.join("cmdline")) {
+                // NOTE: arguments are separated by '\0'
+                let cmdline = cmdline
+                    .split(|c| *c == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if !cmdline.is_empty() {
+                    self.process_cmdlines.insert(pid, cmdline);
+                }
+            }
+            let task_entries = match fs::read_dir(pid_dir.join("task")) {
+                Ok(task_entries) => task_entries,
+                // NOTE: process may exit while taking snapshot
+                Err(_) => continue,
+            };
+            for task_entry in task_entries.flatten() {
+                let tid = match task_entry.file_name().to_string_lossy().parse::<u32>() {
+                    Ok(tid) => tid,
+                    Err(_) => continue,
+                };
+                self.thread_pids.insert(tid, pid);
+                if let Some(comm) = read_comm(&task_entry.path().join("comm")) {
+                    self.thread_names.insert(tid, comm);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_process_name(&mut self, pid: u32, name: &str) {
+        self.process_names.insert(pid, name.to_string());
+    }
+
+    pub fn set_thread_name(&mut self, tid: u32, name: &str) {
+        self.thread_names.insert(tid, name.to_string());
+    }
+
+    pub fn set_process_sort_index(&mut self, pid: u32, sort_index: i64) {
+        self.process_sort_indexes.insert(pid, sort_index);
+    }
+
+    pub fn set_thread_sort_index(&mut self, tid: u32, sort_index: i64) {
+        self.thread_sort_indexes.insert(tid, sort_index);
+    }
+
+    pub fn process_name(&self, pid: u32) -> Option<&str> {
+        self.process_names.get(&pid).map(|name| name.as_str())
+    }
+
+    pub fn process_cmdline(&self, pid: u32) -> Option<&str> {
+        self.process_cmdlines
+            .get(&pid)
+            .map(|cmdline| cmdline.as_str())
+    }
+
+    pub fn thread_name(&self, tid: u32) -> Option<&str> {
+        self.thread_names.get(&tid).map(|name| name.as_str())
+    }
+
+    pub fn thread_pid(&self, tid: u32) -> Option<u32> {
+        self.thread_pids.get(&tid).copied()
+    }
+
+    // metadata events of (pid, tid) pairs which appear in the trace
+    // NOTE: string pid/tid (e.g. "CPU 3") has no metadata event, viewers show the string as its name
+    pub fn metadata_events(
+        &self,
+        threads: &BTreeSet<(chrome::TrackId, chrome::TrackId)>,
+    ) -> Vec<chrome::Event> {
+        let mut events: Vec<chrome::Event> = Vec::new();
+        let threads: BTreeSet<(u32, u32)> = threads
+            .iter()
+            .filter_map(|(pid, tid)| Some((pid.as_number()?, tid.as_number()?)))
+            .collect();
+        let pids: BTreeSet<u32> = threads.iter().map(|(pid, _)| *pid).collect();
+        for &pid in &pids {
+            if let Some(name) = self.process_names.get(&pid) {
+                events.push(metadata_event(
+                    "process_name",
+                    pid,
+                    0,
+                    "name",
+                    name.as_str(),
+                ));
+            }
+            if let Some(cmdline) = self.process_cmdlines.get(&pid) {
+                events.push(metadata_event(
+                    "process_labels",
+                    pid,
+                    0,
+                    "labels",
+                    cmdline.as_str(),
+                ));
+            }
+            if let Some(sort_index) = self.process_sort_indexes.get(&pid) {
+                events.push(metadata_event(
+                    "process_sort_index",
+                    pid,
+                    0,
+                    "sort_index",
+                    *sort_index,
+                ));
+            }
+        }
+        for &(pid, tid) in &threads {
+            if let Some(name) = self.thread_names.get(&tid) {
+                events.push(metadata_event(
+                    "thread_name",
+                    pid,
+                    tid,
+                    "name",
+                    name.as_str(),
+                ));
+            }
+            if let Some(sort_index) = self.thread_sort_indexes.get(&tid) {
+                events.push(metadata_event(
+                    "thread_sort_index",
+                    pid,
+                    tid,
+                    "sort_index",
+                    *sort_index,
+                ));
+            }
+        }
+        events
+    }
+}
+
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+        .replace("\"\"", "\"")
+        .trim()
+        .to_string()
+}
+
+fn read_comm(filepath: &Path) -> Option<String> {
+    fs::read_to_string(filepath)
+        .ok()
+        .map(|comm| comm.trim_end_matches('\n').to_string())
+}
+
+fn metadata_event(
+    name: &str,
+    pid: u32,
+    tid: u32,
+    key: &str,
+    value: impl Into<serde_json::Value>,
+) -> chrome::Event {
+    chrome::Event {
+        name: name.to_string(),
+        category: String::from("__metadata"),
+        event_type: chrome::EventType::Metadata,
+        timestamp: Duration::from_micros(0),
+        duration: Duration::from_micros(0),
+        process_id: pid.into(),
+        thread_id: tid.into(),
+        instant_scope: None,
+        scope: None,
+        id: None,
+        args: None,
+        stack_frame: None,
+    }
+    .with_arg(key, value)
+}
+
+// parse 'ID=VALUE' of command line options e.g. '--thread-name 1234=worker'
+pub fn parse_id_value(s: &str) -> Result<(u32, String)> {
+    let (id, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Failed parse '{}' required format is 'ID=VALUE'", s))?;
+    let id = id
+        .trim()
+        .parse::<u32>()
+        .with_context(|| format!("Failed parse '{}' invalid ID '{}'", s, id))?;
+    Ok((id, value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(events: &[chrome::Event]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| {
+                let value = event.args.as_ref().unwrap().values().next().unwrap();
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                format!(
+                    "{} {}/{} {}",
+                    event.name, event.process_id, event.thread_id, value
+                )
+            })
+            .collect()
+    }
+
+    fn threads(pairs: &[(u32, u32)]) -> BTreeSet<(chrome::TrackId, chrome::TrackId)> {
+        pairs
+            .iter()
+            .map(|&(pid, tid)| (pid.into(), tid.into()))
+            .collect()
+    }
+
+    // directory of /proc snapshot under temp directory (removed on drop)
+    struct ProcDir(std::path::PathBuf);
+
+    impl ProcDir {
+        fn new(name: &str, files: &[(&str, &[u8])]) -> ProcDir {
+            let dir =
+                std::env::temp_dir().join(format!("x2trace-test-{}-{}", name, std::process::id()));
+            for (path, content) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            ProcDir(dir)
+        }
+    }
+
+    impl Drop for ProcDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let mut table = ProcessInfoTable::default();
+        table
+            .parse_csv(
+                "pid,comm,cmdline\n\
+                 1,systemd,/sbin/init splash \n\
+                 2,kthreadd,\n\
+                 x,invalid,\n\
+                 \n\
+                 100,sh,\"sh -c \"\"echo a,b\"\"\"\n",
+                Path::new("test.csv"),
+            )
+            .unwrap();
+        assert_eq!(table.process_name(1), Some("systemd"));
+        assert_eq!(table.process_cmdline(1), Some("/sbin/init splash"));
+        assert_eq!(table.process_cmdline(2), None);
+        // pid column may be tid
+        assert_eq!(table.thread_name(2), Some("kthreadd"));
+        // cmdline may include ',' and quoted '"'
+        assert_eq!(table.process_cmdline(100), Some("sh -c \"echo a,b\""));
+        assert!(table
+            .parse_csv("pid,name\n1,a\n", Path::new("test.csv"))
+            .is_err());
+    }
+
+    #[test]
+    fn proc_snapshot() {
+        let proc_dir = ProcDir::new(
+            "proc-snapshot",
+            &[
+                ("100/comm", b"app\n"),
+                ("100/cmdline", b"./app\0--flag\0"),
+                ("100/task/100/comm", b"app\n"),
+                ("100/task/101/comm", b"worker\n"),
+                ("self/comm", b"self\n"),
+                // process which exited while taking snapshot
+                ("200/comm", b"exited\n"),
+            ],
+        );
+        let mut table = ProcessInfoTable::default();
+        table.load_proc_dir(&proc_dir.0).unwrap();
+        assert_eq!(table.process_cmdline(100), Some("./app --flag"));
+        assert_eq!(table.thread_pid(101), Some(100));
+        assert_eq!(table.thread_pid(200), None);
+        assert_eq!(
+            names(&table.metadata_events(&threads(&[(100, 100), (100, 101), (200, 200)]))),
+            vec![
+                "process_name 100/0 app",
+                "process_labels 100/0 ./app --flag",
+                "process_name 200/0 exited",
+                "thread_name 100/100 app",
+                "thread_name 100/101 worker",
+            ]
+        );
+    }
+
+    // NOTE: same order as load_process_info() of main.rs (CSV < /proc snapshot < command line options)
+    #[test]
+    fn override_precedence() {
+        let proc_dir = ProcDir::new(
+            "override-precedence",
+            &[
+                ("100/comm", b"proc-app\n"),
+                ("100/task/101/comm", b"proc-worker\n"),
+            ],
+        );
+        let mut table = ProcessInfoTable::default();
+        table
+            .parse_csv(
+                "pid,comm,cmdline\n100,csv-app,./app\n101,csv-worker,\n102,csv-other,\n",
+                Path::new("test.csv"),
+            )
+            .unwrap();
+        table.load_proc_dir(&proc_dir.0).unwrap();
+        let (tid, name) = parse_id_value("102=cli-other").unwrap();
+        table.set_thread_name(tid, &name);
+        table.set_thread_sort_index(102, -1);
+        assert_eq!(
+            names(&table.metadata_events(&threads(&[(100, 101), (100, 102)]))),
+            vec![
+                "process_name 100/0 proc-app",
+                "process_labels 100/0 ./app",
+                "thread_name 100/101 proc-worker",
+                "thread_name 100/102 cli-other",
+                "thread_sort_index 100/102 -1",
+            ]
+        );
+    }
+
+    #[test]
+    fn string_track_ids() {
+        let mut table = ProcessInfoTable::default();
+        table.set_process_name(1, "app");
+        let threads: BTreeSet<(chrome::TrackId, chrome::TrackId)> = [
+            (1u32.into(), 1u32.into()),
+            (chrome::TrackId::from("CPU 3"), chrome::TrackId::from("irq")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            names(&table.metadata_events(&threads)),
+            vec!["process_name 1/0 app"]
+        );
+    }
+
+    #[test]
+    fn id_value() {
+        assert_eq!(
+            parse_id_value("1234=worker=1").unwrap(),
+            (1234, String::from("worker=1"))
+        );
+        assert!(parse_id_value("worker").is_err());
+        assert!(parse_id_value("abc=worker").is_err());
+    }
+}
@@ -0,0 +1,656 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::chrome;
+use crate::counter_window::CounterWindow;
+use crate::perf_script::{parse_event_args, Record};
+
+const PERCENTILES: [f64; 3] = [50.0, 95.0, 99.0];
+
+// durations of run time, wait time or sched delay
+#[derive(Default)]
+struct Distribution {
+    // NOTE: sorted by sort() before the summary
+    samples: Vec<Duration>,
+}
+
+impl Distribution {
+    fn add(&mut self, duration: Duration) {
+        self.samples.push(duration);
+    }
+
+    fn sort(&mut self) {
+        self.samples.sort_unstable();
+    }
+
+    fn mean(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+
+    // nearest-rank method
+    fn percentile(&self, percent: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let rank = (percent / 100.0 * self.samples.len() as f64).ceil() as usize;
+        Some(self.samples[rank.clamp(1, self.samples.len()) - 1])
+    }
+
+    fn max(&self) -> Option<Duration> {
+        self.samples.last().copied()
+    }
+
+    // count, mean, percentiles and max (unit is msec)
+    fn summary(&self) -> Vec<String> {
+        let msecs = |duration: Option<Duration>| {
+            duration
+                .map(|duration| format!("{:.3}", duration.as_secs_f64() * 1000.0))
+                .unwrap_or_default()
+        };
+        let mut values = vec![self.samples.len().to_string(), msecs(self.mean())];
+        values.extend(PERCENTILES.iter().map(|&p| msecs(self.percentile(p))));
+        values.push(msecs(self.max()));
+        values
+    }
+
+    fn summary_header(name: &str) -> Vec<String> {
+        let mut header = vec![format!("{}_count", name), format!("{}_mean_ms", name)];
+        header.extend(PERCENTILES.iter().map(|p| format!("{}_p{}_ms", name, p)));
+        header.push(format!("{}_max_ms", name));
+        header
+    }
+}
+
+#[derive(Default)]
+struct SwitchStats {
+    switches: u64,
+    // prev_state is not 'R' (sleep, I/O wait, ...)
+    voluntary: u64,
+    // prev_state is 'R' or 'R+' (preempted)
+    involuntary: u64,
+    // switches to a task of different comm except idle task (perf-sched-timehist.py counts idle too)
+    cache_flush: u64,
+    run_time: Distribution,
+    wait_time: Distribution,
+    sched_delay: Distribution,
+    // counts in the current counter interval
+    window_voluntary: u64,
+    window_involuntary: u64,
+    // counters are written in the previous interval
+    window_active: bool,
+}
+
+impl SwitchStats {
+    fn add_switch(&mut self, involuntary: bool, cache_flush: bool) {
+        self.switches += 1;
+        if involuntary {
+            self.involuntary += 1;
+            self.window_involuntary += 1;
+        } else {
+            self.voluntary += 1;
+            self.window_voluntary += 1;
+        }
+        if cache_flush {
+            self.cache_flush += 1;
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.switches == 0 {
+            return 0.0;
+        }
+        count as f64 / self.switches as f64 * 100.0
+    }
+
+    fn sort(&mut self) {
+        self.run_time.sort();
+        self.wait_time.sort();
+        self.sched_delay.sort();
+    }
+}
+
+#[derive(Default)]
+struct Task {
+    comm: String,
+    pid: Option<u32>,
+    stats: SwitchStats,
+    last_switch_out: Option<Duration>,
+    // woken up or preempted
+    runnable_since: Option<Duration>,
+}
+
+struct Running {
+    tid: u32,
+    timestamp: Duration,
+}
+
+fn arg_u32(args: &chrome::Args, key: &str) -> Option<u32> {
+    args.get(key)
+        .and_then(|value| value.as_u64())
+        .map(|value| value as u32)
+}
+
+fn arg_string(args: &chrome::Args, key: &str) -> String {
+    match args.get(key) {
+        Some(serde_json::Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// context switch statistics per task and per CPU of sched:sched_switch and sched:sched_wakeup
+// run time: switch in -> switch out, wait time: switch out -> switch in,
+// sched delay: wakeup (or preempted) -> switch in
+pub struct SchedStats {
+    counter_window: CounterWindow,
+    // tid -> task (except idle task)
+    tasks: BTreeMap<u32, Task>,
+    cpus: BTreeMap<u32, SwitchStats>,
+    // cpu -> running task
+    running: HashMap<u32, Running>,
+    first_timestamp: Option<Duration>,
+    last_timestamp: Duration,
+    lost_switch_count: usize,
+}
+
+impl SchedStats {
+    pub fn new(counter_interval: Duration) -> SchedStats {
+        SchedStats {
+            counter_window: CounterWindow::new(counter_interval),
+            tasks: BTreeMap::new(),
+            cpus: BTreeMap::new(),
+            running: HashMap::new(),
+            first_timestamp: None,
+            last_timestamp: Duration::from_micros(0),
+            lost_switch_count: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty() && self.cpus.is_empty()
+    }
+
+    fn duration(&self) -> Duration {
+        self.first_timestamp
+            .map(|first_timestamp| self.last_timestamp.saturating_sub(first_timestamp))
+            .unwrap_or_default()
+    }
+
+    // NOTE: the record is still converted as an instant event by the caller
+    pub fn update(&mut self, record: &Record) {
+        self.first_timestamp.get_or_insert(record.timestamp);
+        self.last_timestamp = self.last_timestamp.max(record.timestamp);
+        match record.event.as_str() {
+            "sched:sched_switch" => self.update_switch(record),
+            "sched:sched_wakeup" | "sched:sched_wakeup_new" | "sched:sched_waking" => {
+                let args = parse_event_args(&record.args);
+                let tid = match arg_u32(&args, "pid") {
+                    Some(tid) if tid != 0 => tid,
+                    _ => return,
+                };
+                let task = self.tasks.entry(tid).or_default();
+                if task.comm.is_empty() {
+                    task.comm = arg_string(&args, "comm");
+                }
+                // NOTE: sched_waking is followed by sched_wakeup
+                task.runnable_since.get_or_insert(record.timestamp);
+            }
+            _ => {}
+        }
+    }
+
+    fn update_switch(&mut self, record: &Record) {
+        let args = parse_event_args(&record.args);
+        let (cpu, prev_tid, next_tid) = match (
+            record.cpu,
+            arg_u32(&args, "prev_pid"),
+            arg_u32(&args, "next_pid"),
+        ) {
+            (Some(cpu), Some(prev_tid), Some(next_tid)) => (cpu, prev_tid, next_tid),
+            _ => {
+                log::warn!("broken sched_switch event '{}'", record.args);
+                return;
+            }
+        };
+        let timestamp = record.timestamp;
+        let prev_comm = arg_string(&args, "prev_comm");
+        let next_comm = arg_string(&args, "next_comm");
+        // NOTE: 'R' and 'R+' mean that the task is preempted
+        let involuntary = arg_string(&args, "prev_state").contains('R');
+        // NOTE: switches from or to idle task do not flush cache of other tasks
+        let cache_flush = prev_tid != 0 && next_tid != 0 && prev_comm != next_comm;
+
+        let running = self.running.remove(&cpu);
+        if prev_tid != 0 {
+            let task = self.tasks.entry(prev_tid).or_default();
+            task.comm = prev_comm;
+            if record.tid == prev_tid {
+                task.pid = task.pid.or(record.pid);
+            }
+            task.stats.add_switch(involuntary, cache_flush);
+            task.last_switch_out = Some(timestamp);
+            task.runnable_since = if involuntary { Some(timestamp) } else { None };
+            let cpu_stats = self.cpus.entry(cpu).or_default();
+            cpu_stats.add_switch(involuntary, cache_flush);
+            match running {
+                Some(running) if running.tid == prev_tid => {
+                    let run_time = timestamp.saturating_sub(running.timestamp);
+                    task.stats.run_time.add(run_time);
+                    cpu_stats.run_time.add(run_time);
+                }
+                // the beginning of the trace
+                None => {}
+                Some(_) => self.lost_switch_count += 1,
+            }
+        }
+        if next_tid != 0 {
+            let task = self.tasks.entry(next_tid).or_default();
+            task.comm = next_comm;
+            if let Some(last_switch_out) = task.last_switch_out {
+                task.stats
+                    .wait_time
+                    .add(timestamp.saturating_sub(last_switch_out));
+            }
+            if let Some(runnable_since) = task.runnable_since.take() {
+                let sched_delay = timestamp.saturating_sub(runnable_since);
+                task.stats.sched_delay.add(sched_delay);
+                self.cpus
+                    .entry(cpu)
+                    .or_default()
+                    .sched_delay
+                    .add(sched_delay);
+            }
+        }
+        self.running.insert(
+            cpu,
+            Running {
+                tid: next_tid,
+                timestamp,
+            },
+        );
+    }
+
+    fn counter_event(
+        name: String,
+        process_id: chrome::TrackId,
+        timestamp: Duration,
+        stats: &SwitchStats,
+        window: Duration,
+    ) -> chrome::Event {
+        let rate = |count: u64| count as f64 / window.as_secs_f64();
+        chrome::Event {
+            name,
+            category: String::from("sched"),
+            event_type: chrome::EventType::Counter,
+            timestamp,
+            duration: Duration::from_micros(0),
+            thread_id: process_id.clone(),
+            process_id,
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+        .with_arg("voluntary", rate(stats.window_voluntary))
+        .with_arg("involuntary", rate(stats.window_involuntary))
+    }
+
+    // context switch rates (per sec) of the window which starts at window_start
+    // NOTE: counters of tasks are written only while the task is switched (and once as zero after that)
+    fn write_counters(
+        &mut self,
+        window_start: Duration,
+        window: Duration,
+        events: &mut Vec<chrome::Event>,
+    ) {
+        if window.is_zero() {
+            return;
+        }
+        for (cpu, stats) in self.cpus.iter_mut() {
+            events.push(Self::counter_event(
+                format!("CPU {} context switches", cpu),
+                "CPU".into(),
+                window_start,
+                stats,
+                window,
+            ));
+            stats.window_voluntary = 0;
+            stats.window_involuntary = 0;
+        }
+        for (&tid, task) in self.tasks.iter_mut() {
+            let stats = &mut task.stats;
+            let active = stats.window_voluntary + stats.window_involuntary > 0;
+            if active || stats.window_active {
+                events.push(Self::counter_event(
+                    format!("{}({}) context switches", task.comm, tid),
+                    task.pid.unwrap_or(tid).into(),
+                    window_start,
+                    stats,
+                    window,
+                ));
+            }
+            stats.window_active = active;
+            stats.window_voluntary = 0;
+            stats.window_involuntary = 0;
+        }
+    }
+
+    // write counters of the intervals which end before timestamp
+    pub fn flush(&mut self, timestamp: Duration, events: &mut Vec<chrome::Event>) {
+        for (window_start, window) in self.counter_window.advance(timestamp) {
+            self.write_counters(window_start, window, events);
+        }
+    }
+
+    // run time of tasks which are running at the end of the trace is not counted
+    pub fn finish(&mut self, last_timestamp: Duration, events: &mut Vec<chrome::Event>) {
+        if let Some((window_start, window)) = self.counter_window.last(last_timestamp) {
+            self.write_counters(window_start, window, events);
+        }
+        self.running.clear();
+        for task in self.tasks.values_mut() {
+            task.stats.sort();
+        }
+        for stats in self.cpus.values_mut() {
+            stats.sort();
+        }
+        if self.lost_switch_count > 0 {
+            log::warn!(
+                "{} sched_switch events do not match running tasks (lost events?)",
+                self.lost_switch_count
+            );
+        }
+    }
+
+    fn switches_per_sec(&self, stats: &SwitchStats) -> f64 {
+        let duration = self.duration();
+        if duration.is_zero() {
+            return 0.0;
+        }
+        stats.switches as f64 / duration.as_secs_f64()
+    }
+
+    // human readable tables of top tasks by context switches and all CPUs
+    pub fn write_report(&self, writer: &mut dyn Write, top: usize) -> Result<()> {
+        let mut tasks: Vec<(&u32, &Task)> = self
+            .tasks
+            .iter()
+            .filter(|(_, task)| task.stats.switches > 0)
+            .collect();
+        tasks.sort_by_key(|(_, task)| std::cmp::Reverse(task.stats.switches));
+        writeln!(
+            writer,
+            "context switches of top {} tasks ({} tasks, {:.3} sec)",
+            top.min(tasks.len()),
+            tasks.len(),
+            self.duration().as_secs_f64()
+        )?;
+        let header = format!(
+            "{:>8} {:>8} {:>8} {:>15} {:>10} {:>21} {:>21}",
+            "switches",
+            "vcs(%)",
+            "nvcs(%)",
+            "cache flush(%)",
+            "per sec",
+            "run p50/max (ms)",
+            "sch delay p95/max(ms)"
+        );
+        let row = |stats: &SwitchStats| {
+            let msecs = |duration: Option<Duration>| {
+                duration
+                    .map(|duration| format!("{:.3}", duration.as_secs_f64() * 1000.0))
+                    .unwrap_or_else(|| String::from("-"))
+            };
+            format!(
+                "{:>8} {:>8.1} {:>8.1} {:>15.1} {:>10.1} {:>21} {:>21}",
+                stats.switches,
+                stats.percent(stats.voluntary),
+                stats.percent(stats.involuntary),
+                stats.percent(stats.cache_flush),
+                self.switches_per_sec(stats),
+                format!(
+                    "{}/{}",
+                    msecs(stats.run_time.percentile(50.0)),
+                    msecs(stats.run_time.max())
+                ),
+                format!(
+                    "{}/{}",
+                    msecs(stats.sched_delay.percentile(95.0)),
+                    msecs(stats.sched_delay.max())
+                ),
+            )
+        };
+        writeln!(writer, "{:<32} {}", "name", header)?;
+        for (tid, task) in tasks.iter().take(top) {
+            let name = format!("{}({})", task.comm, tid);
+            writeln!(writer, "{:<32} {}", name, row(&task.stats))?;
+        }
+        writeln!(writer)?;
+        writeln!(writer, "{:<8} {}", "cpu", header)?;
+        for (cpu, stats) in &self.cpus {
+            writeln!(writer, "{:<8} {}", cpu, row(stats))?;
+        }
+        Ok(())
+    }
+
+    // all tasks and CPUs
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut header: Vec<String> = [
+            "type",
+            "id",
+            "pid",
+            "comm",
+            "switches",
+            "voluntary",
+            "involuntary",
+            "voluntary_pct",
+            "involuntary_pct",
+            "cache_flush",
+            "cache_flush_pct",
+            "switches_per_sec",
+        ]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+        header.extend(Distribution::summary_header("run_time"));
+        header.extend(Distribution::summary_header("wait_time"));
+        header.extend(Distribution::summary_header("sched_delay"));
+        writeln!(writer, "{}", header.join(","))?;
+
+        let values = |stats: &SwitchStats| {
+            let mut values = vec![
+                stats.switches.to_string(),
+                stats.voluntary.to_string(),
+                stats.involuntary.to_string(),
+                format!("{:.3}", stats.percent(stats.voluntary)),
+                format!("{:.3}", stats.percent(stats.involuntary)),
+                stats.cache_flush.to_string(),
+                format!("{:.3}", stats.percent(stats.cache_flush)),
+                format!("{:.3}", self.switches_per_sec(stats)),
+            ];
+            values.extend(stats.run_time.summary());
+            values.extend(stats.wait_time.summary());
+            values.extend(stats.sched_delay.summary());
+            values.join(",")
+        };
+        for (tid, task) in &self.tasks {
+            writeln!(
+                writer,
+                "task,{},{},{},{}",
+                tid,
+                task.pid.unwrap_or(*tid),
+                csv_field(&task.comm),
+                values(&task.stats)
+            )?;
+        }
+        for (cpu, stats) in &self.cpus {
+            writeln!(writer, "cpu,{},,,{}", cpu, values(stats))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf_script::parse_header;
+
+    fn msecs(samples: &[u64]) -> Vec<Duration> {
+        samples
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect()
+    }
+
+    fn distribution(samples: &[u64]) -> Distribution {
+        let mut distribution = Distribution {
+            samples: msecs(samples),
+        };
+        distribution.sort();
+        distribution
+    }
+
+    #[test]
+    fn percentiles() {
+        let samples: Vec<u64> = (1..=100).rev().collect();
+        let distribution = distribution(&samples);
+        assert_eq!(distribution.mean(), Some(Duration::from_micros(50500)));
+        assert_eq!(
+            distribution.percentile(50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            distribution.percentile(95.0),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(
+            distribution.percentile(99.0),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(distribution.max(), Some(Duration::from_millis(100)));
+
+        let distribution = self::distribution(&[4, 1, 3, 2]);
+        assert_eq!(
+            distribution.percentile(50.0),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(
+            distribution.percentile(95.0),
+            Some(Duration::from_millis(4))
+        );
+        assert_eq!(distribution.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(
+            distribution.summary(),
+            vec!["4", "2.500", "2.000", "4.000", "4.000", "4.000"]
+        );
+
+        let distribution = self::distribution(&[]);
+        assert_eq!(distribution.percentile(50.0), None);
+        assert_eq!(distribution.summary(), vec!["0", "", "", "", "", ""]);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("bash"), "bash");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    // CPU 0: idle -> a (woken up) -> b -> a (b is preempted) -> b -> idle
+    fn sched_stats() -> (SchedStats, Vec<chrome::Event>) {
+        let lines = [
+            "a,1  10/11 [000] 1.000000: sched:sched_wakeup: comm=a,1 pid=11 prio=120 target_cpu=000",
+            "swapper  0/0 [000] 1.002000: sched:sched_switch: prev_comm=swapper/0 prev_pid=0 prev_prio=120 prev_state=R ==> next_comm=a,1 next_pid=11 next_prio=120",
+            "a,1  10/11 [000] 1.005000: sched:sched_switch: prev_comm=a,1 prev_pid=11 prev_prio=120 prev_state=S ==> next_comm=b next_pid=12 next_prio=120",
+            "b  12/12 [000] 1.006000: sched:sched_switch: prev_comm=b prev_pid=12 prev_prio=120 prev_state=R+ ==> next_comm=a,1 next_pid=11 next_prio=120",
+            "a,1  10/11 [000] 1.010000: sched:sched_switch: prev_comm=a,1 prev_pid=11 prev_prio=120 prev_state=S ==> next_comm=b next_pid=12 next_prio=120",
+            "b  12/12 [000] 1.011000: sched:sched_switch: prev_comm=b prev_pid=12 prev_prio=120 prev_state=S ==> next_comm=swapper/0 next_pid=0 next_prio=120",
+        ];
+        let mut sched_stats = SchedStats::new(Duration::from_secs(1));
+        let mut events = Vec::new();
+        let mut last_timestamp = Duration::from_micros(0);
+        for line in lines {
+            let record = parse_header(line).unwrap();
+            sched_stats.flush(record.timestamp, &mut events);
+            sched_stats.update(&record);
+            last_timestamp = record.timestamp;
+        }
+        sched_stats.finish(last_timestamp, &mut events);
+        (sched_stats, events)
+    }
+
+    #[test]
+    fn switch_attribution() {
+        let (sched_stats, events) = sched_stats();
+        assert_eq!(sched_stats.lost_switch_count, 0);
+        assert!(!sched_stats.tasks.contains_key(&0));
+
+        let a = &sched_stats.tasks[&11];
+        assert_eq!(a.comm, "a,1");
+        assert_eq!(a.pid, Some(10));
+        assert_eq!(
+            (a.stats.switches, a.stats.voluntary, a.stats.involuntary),
+            (2, 2, 0)
+        );
+        assert_eq!(a.stats.cache_flush, 2);
+        assert_eq!(a.stats.run_time.samples, msecs(&[3, 4]));
+        assert_eq!(a.stats.wait_time.samples, msecs(&[1]));
+        assert_eq!(a.stats.sched_delay.samples, msecs(&[2]));
+
+        let b = &sched_stats.tasks[&12];
+        assert_eq!(
+            (b.stats.switches, b.stats.voluntary, b.stats.involuntary),
+            (2, 1, 1)
+        );
+        // NOTE: switch to idle task is not a cache flush
+        assert_eq!(b.stats.cache_flush, 1);
+        assert_eq!(b.stats.run_time.samples, msecs(&[1, 1]));
+        assert_eq!(b.stats.wait_time.samples, msecs(&[4]));
+        // preempted at 1.006 and switched in at 1.010
+        assert_eq!(b.stats.sched_delay.samples, msecs(&[4]));
+
+        let cpu = &sched_stats.cpus[&0];
+        assert_eq!((cpu.switches, cpu.cache_flush), (4, 3));
+        assert_eq!(cpu.run_time.samples, msecs(&[1, 1, 3, 4]));
+        assert_eq!(cpu.sched_delay.samples, msecs(&[2, 4]));
+
+        // the last partial interval of CPU and tasks
+        assert_eq!(events.len(), 3);
+        let rate = events[0].arg("voluntary").unwrap().as_f64().unwrap();
+        assert!((rate - 3.0 / 0.011).abs() < 1e-6);
+    }
+
+    #[test]
+    fn csv_rows() {
+        let (sched_stats, _) = sched_stats();
+        let mut csv = Vec::new();
+        sched_stats.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        let columns = lines[0].split(',').count();
+        assert!(lines[1].starts_with("task,11,10,\"a,1\",2,2,0,100.000,0.000,2,100.000,"));
+        assert!(lines[2].starts_with("task,12,12,b,2,1,1,50.000,50.000,1,50.000,"));
+        assert!(lines[3].starts_with("cpu,0,,,4,3,1,"));
+        // NOTE: quoted comm has a comma
+        assert_eq!(lines[1].split(',').count(), columns + 1);
+        assert_eq!(lines[2].split(',').count(), columns);
+        assert_eq!(lines[3].split(',').count(), columns);
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::chrome;
+use crate::input;
+use crate::ltrace::{
+    begin_end_events, return_value, split_line_header, split_return_value, strip_elapsed_time,
+    OutputMode,
+};
+
+// event which '-k' stack trace lines belong to
+#[derive(Clone, Copy)]
+enum LastCall {
+    Pending(u32),
+    Event(usize),
+}
+
+// strace -ttt -T -f [-tt] [-r] [-y] [-k] output
+pub struct StraceParser {
+    mode: OutputMode,
+    default_tid: u32,
+    // -r: timestamp is relative to the previous line
+    relative: bool,
+    // -ff: tid of the current file from '.PID' suffix of the filename
+    file_tid: Option<u32>,
+    // -r: absolute timestamp of the previous line in the current file
+    previous_timestamp: Duration,
+    // tid -> syscalls which are '<unfinished ...>'
+    pending_calls: HashMap<u32, Vec<chrome::Event>>,
+    events: Vec<chrome::Event>,
+    last_call: Option<LastCall>,
+    last_timestamp: Duration,
+}
+
+impl StraceParser {
+    // default_tid is used for the output without -f (or -ff output files)
+    pub fn new(mode: OutputMode, default_tid: u32, relative: bool) -> StraceParser {
+        StraceParser {
+            mode,
+            default_tid,
+            relative,
+            file_tid: None,
+            previous_timestamp: Duration::from_micros(0),
+            pending_calls: HashMap::new(),
+            events: Vec::new(),
+            last_call: None,
+            last_timestamp: Duration::from_micros(0),
+        }
+    }
+
+    pub fn parse_file(&mut self, filepath: &Path) -> Result<()> {
+        let reader = input::open(filepath)?;
+        self.file_tid = tid_from_filepath(filepath);
+        self.previous_timestamp = Duration::from_micros(0);
+        self.last_call = None;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read file {:?}", filepath))?;
+            if let Err(e) = self.parse_line(&line) {
+                log::warn!("{:?}:{}: {}", filepath, i + 1, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn new_event(tid: u32, timestamp: Duration, name: &str, category: &str) -> chrome::Event {
+        chrome::Event {
+            name: name.to_string(),
+            category: category.to_string(),
+            event_type: chrome::EventType::Complete,
+            timestamp,
+            duration: Duration::from_micros(0),
+            // NOTE: strace -f output has only tid, so tid is used instead
+            process_id: tid.into(),
+            thread_id: tid.into(),
+            instant_scope: None,
+            scope: None,
+            id: None,
+            args: None,
+            stack_frame: None,
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() || line.starts_with("strace: ") {
+            return Ok(());
+        }
+        // -k: ' > /usr/lib/libc.so.6(__write+0x14) [0xf1234]'
+        if let Some(frame) = line.trim_start().strip_prefix("> ") {
+            self.push_stack_frame(frame.trim());
+            return Ok(());
+        }
+        let (tid, timestamp, rest) =
+            split_line_header(line).ok_or_else(|| anyhow!("invalid line '{}'", line))?;
+        let tid = tid.or(self.file_tid).unwrap_or(self.default_tid);
+        let timestamp = if self.relative {
+            self.previous_timestamp + timestamp
+        } else {
+            timestamp
+        };
+        self.previous_timestamp = timestamp;
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let rest = rest.trim_start();
+
+        // '+++ exited with 0 +++', '+++ killed by SIGKILL (core dumped) +++'
+        if let Some(s) = rest.strip_prefix("+++") {
+            let name = s.trim_end_matches("+++").trim();
+            let mut event = Self::new_event(tid, timestamp, name, "process");
+            event.event_type = chrome::EventType::Instant;
+            event.instant_scope = Some(chrome::InstantScope::Thread);
+            if let Some(exit_code) = name.strip_prefix("exited with ") {
+                event.set_arg("exit_code", return_value(exit_code.trim()));
+            } else if let Some(signal) = name.strip_prefix("killed by ") {
+                event.set_arg("signal", signal.split_whitespace().next().unwrap_or(signal));
+            }
+            self.push_event(event);
+            return Ok(());
+        }
+        // '--- SIGCHLD {si_signo=SIGCHLD, si_code=CLD_EXITED, ...} ---', '--- stopped by SIGSTOP ---'
+        if let Some(s) = rest.strip_prefix("---") {
+            let signal = s.trim_end_matches("---").trim();
+            let mut event = match signal.split_once(' ') {
+                Some((name, siginfo)) if name.starts_with("SIG") => {
+                    Self::new_event(tid, timestamp, name, "signal")
+                        .with_arg("siginfo", siginfo.trim())
+                }
+                _ => Self::new_event(tid, timestamp, signal, "signal"),
+            };
+            event.event_type = chrome::EventType::Instant;
+            event.instant_scope = Some(chrome::InstantScope::Thread);
+            self.push_event(event);
+            return Ok(());
+        }
+        // '<... wait4 resumed>[{WIFEXITED(s) && WEXITSTATUS(s) == 0}], 0, NULL) = 1235 <0.010000>'
+        if let Some(s) = rest.strip_prefix("<...") {
+            let (name, s) = s
+                .split_once(" resumed>")
+                .ok_or_else(|| anyhow!("invalid resumed line '{}'", line))?;
+            self.resume_call(tid, timestamp, name.trim(), s);
+            return Ok(());
+        }
+
+        // 'openat(AT_FDCWD, "/etc/ld.so.cache", O_RDONLY|O_CLOEXEC) = 3</etc/ld.so.cache> <0.000020>'
+        let (name, s) = rest
+            .split_once('(')
+            .ok_or_else(|| anyhow!("invalid syscall line '{}'", line))?;
+        let mut event = Self::new_event(tid, timestamp, name.trim(), "syscall");
+        if let Some(arguments) = s.trim_end().strip_suffix("<unfinished ...>") {
+            event.set_arg("arguments", arguments.trim_end());
+            self.pending_calls.entry(tid).or_default().push(event);
+            self.last_call = Some(LastCall::Pending(tid));
+            return Ok(());
+        }
+        let (arguments, retval, elapsed_time) = split_result(s);
+        event.set_arg("arguments", arguments);
+        set_result_args(&mut event, arguments, retval);
+        event.duration = elapsed_time.unwrap_or_default();
+        self.push_event(event);
+        Ok(())
+    }
+
+    fn resume_call(&mut self, tid: u32, timestamp: Duration, name: &str, s: &str) {
+        let (arguments, retval, elapsed_time) = split_result(s);
+        let stack = self.pending_calls.entry(tid).or_default();
+        let pending_call = stack
+            .iter()
+            .rposition(|event| event.name == name)
+            .map(|i| stack.remove(i));
+        let mut event = match pending_call {
+            Some(mut event) => {
+                // NOTE: elapsed time is measured from the call
+                event.duration = match elapsed_time {
+                    Some(elapsed_time) => elapsed_time,
+                    None => timestamp.saturating_sub(event.timestamp),
+                };
+                let arguments = format!(
+                    "{} {}",
+                    event
+                        .arg("arguments")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or(""),
+                    arguments
+                );
+                event.set_arg("arguments", arguments.trim());
+                event
+            }
+            None => {
+                log::warn!(
+                    "'{}' is resumed without unfinished call on tid {}",
+                    name,
+                    tid
+                );
+                let elapsed_time = elapsed_time.unwrap_or_default();
+                let mut event =
+                    Self::new_event(tid, timestamp.saturating_sub(elapsed_time), name, "syscall");
+                event.duration = elapsed_time;
+                event.set_arg("arguments", arguments);
+                event
+            }
+        };
+        let arguments = event
+            .arg("arguments")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        set_result_args(&mut event, &arguments, retval);
+        self.push_event(event);
+    }
+
+    fn push_event(&mut self, event: chrome::Event) {
+        self.last_call = Some(LastCall::Event(self.events.len()));
+        self.events.push(event);
+    }
+
+    fn push_stack_frame(&mut self, frame: &str) {
+        let event = match self.last_call {
+            Some(LastCall::Pending(tid)) => self
+                .pending_calls
+                .get_mut(&tid)
+                .and_then(|stack| stack.last_mut()),
+            Some(LastCall::Event(i)) => self.events.get_mut(i),
+            None => None,
+        };
+        let event = match event {
+            Some(event) => event,
+            None => {
+                log::warn!("stack trace '{}' without syscall", frame);
+                return;
+            }
+        };
+        let mut stack = match event.arg("stack") {
+            Some(serde_json::Value::Array(stack)) => stack.clone(),
+            _ => Vec::new(),
+        };
+        stack.push(frame.into());
+        event.set_arg("stack", stack);
+    }
+
+    // syscalls which are not resumed last until the end of trace
+    pub fn finish(mut self) -> Vec<chrome::Event> {
+        let last_timestamp = self.last_timestamp;
+        let pending_calls: Vec<chrome::Event> = self
+            .pending_calls
+            .drain()
+            .flat_map(|(_, stack)| stack)
+            .collect();
+        for mut event in pending_calls {
+            event.duration = last_timestamp.saturating_sub(event.timestamp);
+            event.set_arg("unfinished", true);
+            self.events.push(event);
+        }
+        match self.mode {
+            OutputMode::Complete => self.events,
+            OutputMode::BeginEnd => begin_end_events(self.events),
+        }
+    }
+}
+
+// 'strace.log.1234' or 'strace.log.1234.gz' (strace -ff -o strace.log)
+fn tid_from_filepath(filepath: &Path) -> Option<u32> {
+    let filename = filepath.file_name()?.to_str()?;
+    let filename = filename
+        .strip_suffix(".gz")
+        .or_else(|| filename.strip_suffix(".zst"))
+        .unwrap_or(filename);
+    let (_, suffix) = filename.rsplit_once('.')?;
+    if suffix.is_empty() || !suffix.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    suffix.parse::<u32>().ok()
+}
+
+// split 'ARGS) = RETVAL <ELAPSED_TIME>'
+fn split_result(s: &str) -> (&str, Option<&str>, Option<Duration>) {
+    let (s, elapsed_time) = strip_elapsed_time(s);
+    let (arguments, retval) = split_return_value(s);
+    (arguments.trim(), retval, elapsed_time)
+}
+
+// '-1 ENOENT (No such file or directory)', '3</etc/passwd>', '0 (Timeout)', '?'
+fn set_result_args(event: &mut chrome::Event, arguments: &str, retval: Option<&str>) {
+    let fd_paths = find_fd_paths(arguments);
+    if !fd_paths.is_empty() {
+        event.set_arg("fd_paths", fd_paths);
+    }
+    let retval = match retval {
+        Some(retval) => retval,
+        None => return,
+    };
+    let (value, rest) = match retval.split_once(' ') {
+        Some((value, rest)) => (value, rest.trim()),
+        None => (retval, ""),
+    };
+    // -y: '3</etc/passwd>'
+    let value = match value.split_once('<') {
+        Some((fd, path)) if path.ends_with('>') => {
+            event.set_arg("return_path", &path[..path.len() - 1]);
+            fd
+        }
+        _ => value,
+    };
+    event.set_arg("return_value", return_value(value));
+    if rest.is_empty() {
+        return;
+    }
+    if rest.starts_with('E') {
+        let (errno, message) = match rest.split_once(' ') {
+            Some((errno, message)) => (errno, Some(message)),
+            None => (rest, None),
+        };
+        event.set_arg("errno", errno);
+        if let Some(message) = message {
+            event.set_arg(
+                "error",
+                message.trim().trim_start_matches('(').trim_end_matches(')'),
+            );
+        }
+    } else {
+        event.set_arg("return_detail", rest);
+    }
+}
+
+// -y: '3</etc/passwd>' or '-yy': '3<TCP:[127.0.0.1:80->127.0.0.1:5000]>' in arguments
+fn find_fd_paths(arguments: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut fd_paths = serde_json::Map::new();
+    let bytes = arguments.as_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_string {
+            if c == b'\\' {
+                i += 1;
+            } else if c == b'"' {
+                in_string = false;
+            }
+            i += 1;
+            continue;
+        }
+        if c == b'"' {
+            in_string = true;
+            i += 1;
+            continue;
+        }
+        let boundary = i == 0 || matches!(bytes[i - 1], b' ' | b'[' | b'(' | b'{' | b'=');
+        if !(boundary && c.is_ascii_digit()) {
+            i += 1;
+            continue;
+        }
+        let fd_start = i;
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        if i >= bytes.len() || bytes[i] != b'<' {
+            continue;
+        }
+        let path_start = i + 1;
+        let mut depth = 0;
+        let mut path_end = None;
+        for (j, &c) in bytes.iter().enumerate().skip(path_start) {
+            match c {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                b'>' if depth == 0 => {
+                    path_end = Some(j);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let path_end = match path_end {
+            Some(path_end) => path_end,
+            None => break,
+        };
+        fd_paths.insert(
+            arguments[fd_start..path_start - 1].to_string(),
+            arguments[path_start..path_end].into(),
+        );
+        i = path_end + 1;
+    }
+    fd_paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: &mut StraceParser, lines: &[&str]) {
+        for line in lines {
+            parser.parse_line(line).unwrap();
+        }
+    }
+
+    fn arg(event: &chrome::Event, key: &str) -> String {
+        match event.arg(key) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => String::new(),
+        }
+    }
+
+    #[test]
+    fn resumed_across_threads() {
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, false);
+        parse(
+            &mut parser,
+            &[
+                "1234 1624633549.000010 wait4(-1,  <unfinished ...>",
+                "1235 1624633549.000011 write(1, \"a\\n\", 2) = 2 <0.000002>",
+                "[pid  1235] 1624633549.000012 read(0,  <unfinished ...>",
+                "1234 1624633549.000020 <... wait4 resumed>[{WIFEXITED(s) && WEXITSTATUS(s) == 0}], 0, NULL) = 1235 <0.000010>",
+                "1235 1624633549.000030 <... read resumed>\"x\", 1) = 1 <0.000018>",
+                "1236 1624633549.000040 futex(0x7f0, FUTEX_WAIT, 0, NULL <unfinished ...>",
+            ],
+        );
+        let events = parser.finish();
+        let calls: Vec<(String, String, String, String, u128)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.thread_id.to_string(),
+                    event.name.clone(),
+                    arg(event, "arguments"),
+                    arg(event, "return_value"),
+                    event.duration.as_micros(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                (
+                    "1235".into(),
+                    "write".into(),
+                    "1, \"a\\n\", 2".into(),
+                    "2".into(),
+                    2
+                ),
+                (
+                    "1234".into(),
+                    "wait4".into(),
+                    "-1, [{WIFEXITED(s) && WEXITSTATUS(s) == 0}], 0, NULL".into(),
+                    "1235".into(),
+                    10
+                ),
+                (
+                    "1235".into(),
+                    "read".into(),
+                    "0, \"x\", 1".into(),
+                    "1".into(),
+                    18
+                ),
+                // not resumed until the end of trace
+                (
+                    "1236".into(),
+                    "futex".into(),
+                    "0x7f0, FUTEX_WAIT, 0, NULL".into(),
+                    "".into(),
+                    0
+                ),
+            ]
+        );
+        assert_eq!(arg(&events[3], "unfinished"), "true");
+    }
+
+    #[test]
+    fn relative_timestamp() {
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, true);
+        parse(
+            &mut parser,
+            &[
+                "     0.000000 execve(\"./a.out\", [\"./a.out\"], 0x7ffd /* 10 vars */) = 0 <0.000100>",
+                "     0.000200 brk(NULL)         = 0x55d0c8e2a000 <0.000010>",
+                "     0.000050 --- SIGCHLD {si_signo=SIGCHLD, si_code=CLD_EXITED} ---",
+                "     0.001000 +++ exited with 0 +++",
+            ],
+        );
+        let timestamps: Vec<u128> = parser
+            .finish()
+            .iter()
+            .map(|event| event.timestamp.as_micros())
+            .collect();
+        assert_eq!(timestamps, vec![0, 200, 250, 1250]);
+    }
+
+    #[test]
+    fn ff_files() {
+        assert_eq!(
+            tid_from_filepath(Path::new("/tmp/strace.log.1234")),
+            Some(1234)
+        );
+        assert_eq!(
+            tid_from_filepath(Path::new("strace.log.1234.gz")),
+            Some(1234)
+        );
+        assert_eq!(
+            tid_from_filepath(Path::new("strace.log.1234.zst")),
+            Some(1234)
+        );
+        assert_eq!(tid_from_filepath(Path::new("strace.log")), None);
+        assert_eq!(tid_from_filepath(Path::new("strace.1a")), None);
+
+        // -ff -r: relative time starts from 0 in each file
+        let dir =
+            std::env::temp_dir().join(format!("x2trace-test-strace-ff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("strace.log.100"),
+            "0.000000 getpid() = 100 <0.000001>\n0.000010 getpid() = 100 <0.000001>\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("strace.log.101"),
+            "0.000005 gettid() = 101 <0.000001>\n",
+        )
+        .unwrap();
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, true);
+        parser.parse_file(&dir.join("strace.log.100")).unwrap();
+        parser.parse_file(&dir.join("strace.log.101")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let calls: Vec<(String, String, u128)> = parser
+            .finish()
+            .iter()
+            .map(|event| {
+                (
+                    event.thread_id.to_string(),
+                    event.name.clone(),
+                    event.timestamp.as_micros(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("100".into(), "getpid".into(), 0),
+                ("100".into(), "getpid".into(), 10),
+                ("101".into(), "gettid".into(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn signals_and_exits() {
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, false);
+        parse(
+            &mut parser,
+            &[
+                "1234 12:34:56.000010 --- SIGCHLD {si_signo=SIGCHLD, si_code=CLD_EXITED, si_pid=1235} ---",
+                "1234 12:34:56.000020 --- stopped by SIGSTOP ---",
+                "1235 12:34:56.000030 +++ exited with 1 +++",
+                "1236 12:34:56.000040 +++ killed by SIGKILL (core dumped) +++",
+            ],
+        );
+        let events = parser.finish();
+        let instants: Vec<(String, String, String)> = events
+            .iter()
+            .map(|event| {
+                assert_eq!(event.event_type, chrome::EventType::Instant);
+                let detail = ["siginfo", "exit_code", "signal"]
+                    .iter()
+                    .map(|key| arg(event, key))
+                    .collect::<Vec<_>>()
+                    .concat();
+                (event.category.clone(), event.name.clone(), detail)
+            })
+            .collect();
+        assert_eq!(
+            instants,
+            vec![
+                (
+                    "signal".into(),
+                    "SIGCHLD".into(),
+                    "{si_signo=SIGCHLD, si_code=CLD_EXITED, si_pid=1235}".into()
+                ),
+                ("signal".into(), "stopped by SIGSTOP".into(), "".into()),
+                ("process".into(), "exited with 1".into(), "1".into()),
+                (
+                    "process".into(),
+                    "killed by SIGKILL (core dumped)".into(),
+                    "SIGKILL".into()
+                ),
+            ]
+        );
+        assert_eq!(
+            events[0].timestamp.as_micros(),
+            (12 * 3600 + 34 * 60 + 56) * 1_000_000 + 10
+        );
+    }
+
+    #[test]
+    fn fd_paths_and_errors() {
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, false);
+        parse(
+            &mut parser,
+            &[
+                "1624633549.000010 openat(AT_FDCWD, \"/etc/passwd\", O_RDONLY) = 3</etc/passwd> <0.000020>",
+                "1624633549.000040 read(3</etc/passwd>, \"a<1>\", 4096) = 4 <0.000005>",
+                "1624633549.000050 openat(AT_FDCWD, \"/nonexistent\", O_RDONLY) = -1 ENOENT (No such file or directory) <0.000010>",
+                "1624633549.000070 poll([{fd=4<socket:[123]>, events=POLLIN}], 1, 0) = 0 (Timeout) <0.000003>",
+                "1624633549.000080 connect(5<TCP:[127.0.0.1:5000->127.0.0.1:80]>, {sa_family=AF_INET}, 16) = 0 <0.000004>",
+            ],
+        );
+        let events = parser.finish();
+        let results: Vec<Vec<String>> = events
+            .iter()
+            .map(|event| {
+                [
+                    "fd_paths",
+                    "return_value",
+                    "return_path",
+                    "errno",
+                    "error",
+                    "return_detail",
+                ]
+                .iter()
+                .map(|key| arg(event, key))
+                .collect()
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                vec!["", "3", "/etc/passwd", "", "", ""],
+                vec!["{\"3\":\"/etc/passwd\"}", "4", "", "", "", ""],
+                vec!["", "-1", "", "ENOENT", "No such file or directory", ""],
+                vec!["{\"4\":\"socket:[123]\"}", "0", "", "", "", "(Timeout)"],
+                vec![
+                    "{\"5\":\"TCP:[127.0.0.1:5000->127.0.0.1:80]\"}",
+                    "0",
+                    "",
+                    "",
+                    "",
+                    ""
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn stack_traces() {
+        let mut parser = StraceParser::new(OutputMode::Complete, 1, false);
+        parse(
+            &mut parser,
+            &[
+                " > /usr/lib/libc.so.6(_start+0x10) [0x1000]",
+                "1234 1624633549.000010 write(1, \"a\", 1) = 1 <0.000002>",
+                " > /usr/lib/libc.so.6(__write+0x14) [0xf1234]",
+                " > /tmp/a.out(main+0x20) [0x1140]",
+                "1234 1624633549.000020 read(0,  <unfinished ...>",
+                " > /usr/lib/libc.so.6(read+0x10) [0xf2000]",
+                "1234 1624633549.000030 <... read resumed>\"\", 1) = 0 <0.000010>",
+            ],
+        );
+        let stacks: Vec<String> = parser
+            .finish()
+            .iter()
+            .map(|event| arg(event, "stack"))
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                "[\"/usr/lib/libc.so.6(__write+0x14) [0xf1234]\",\"/tmp/a.out(main+0x20) [0x1140]\"]",
+                "[\"/usr/lib/libc.so.6(read+0x10) [0xf2000]\"]",
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::hash;
+use crate::symbolizer;
+use crate::symbolizer::SymbolTable;
+
+const CACHE_FILE_EXTENSION: &str = "symtab.json";
+
+// $X2TRACE_CACHE_DIR, $XDG_CACHE_HOME/x2trace or $HOME/.cache/x2trace
+pub fn default_cache_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("X2TRACE_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Ok(dir) = env::var("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir).join("x2trace"));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|dir| PathBuf::from(dir).join(".cache").join("x2trace"))
+}
+
+// build-id of the binary or content hash if build-id does not exist
+pub fn cache_key(filepath: &Path) -> Result<String> {
+    let data = fs::read(filepath)
+        .with_context(|| format!("cache_key(): Failed to open file {:?}", filepath))?;
+    let build_id = object::File::parse(&*data)
+        .ok()
+        .and_then(|elf| symbolizer::read_build_id(&elf));
+    Ok(match build_id {
+        Some(build_id) => build_id,
+        None => format!(
+            "fnv1a-{:016x}-{:x}",
+            hash::fnv1a(data.iter().copied()),
+            data.len()
+        ),
+    })
+}
+
+pub fn cache_filepath(cache_dir: &Path, key: &str) -> PathBuf {
+    cache_dir.join(format!("{}.{}", key, CACHE_FILE_EXTENSION))
+}
+
+pub fn load(cache_dir: &Path, key: &str) -> Option<SymbolTable> {
+    let filepath = cache_filepath(cache_dir, key);
+    let file = File::open(&filepath).ok()?;
+    match serde_json::from_reader::<_, SymbolTable>(BufReader::new(file)) {
+        Ok(symbol_table) if symbol_table.version == SymbolTable::VERSION => Some(symbol_table),
+        Ok(_) => {
+            log::warn!("symbol cache {:?} is old version, ignored", filepath);
+            None
+        }
+        Err(e) => {
+            log::warn!("failed to load symbol cache {:?}: {}", filepath, e);
+            None
+        }
+    }
+}
+
+pub fn store(cache_dir: &Path, key: &str, symbol_table: &SymbolTable) -> Result<PathBuf> {
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("store(): Failed to create directory {:?}", cache_dir))?;
+    let filepath = cache_filepath(cache_dir, key);
+    // NOTE: write to temporary file and rename it for concurrent conversions
+    let tmp_filepath = filepath.with_extension(format!("tmp.{}", std::process::id()));
+    let file = File::create(&tmp_filepath)
+        .with_context(|| format!("store(): Failed to create file {:?}", tmp_filepath))?;
+    serde_json::to_writer(BufWriter::new(file), symbol_table)?;
+    fs::rename(&tmp_filepath, &filepath)
+        .with_context(|| format!("store(): Failed to rename file {:?}", tmp_filepath))?;
+    Ok(filepath)
+}
+
+// remove all symbol cache files and return the number of removed files
+pub fn purge(cache_dir: &Path) -> Result<usize> {
+    if !cache_dir.is_dir() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for entry in fs::read_dir(cache_dir)
+        .with_context(|| format!("purge(): Failed to read directory {:?}", cache_dir))?
+    {
+        let path = entry?.path();
+        let is_cache_file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().ends_with(CACHE_FILE_EXTENSION))
+            .unwrap_or(false);
+        if is_cache_file {
+            fs::remove_file(&path)
+                .with_context(|| format!("purge(): Failed to remove file {:?}", path))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
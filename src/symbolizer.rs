@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use addr2line::gimli::{EndianRcSlice, RunTimeEndian};
+use anyhow::{Context, Result};
+use object::{Architecture, Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use crate::objdump::{AddressInformation, InlinedFrame};
+use crate::symbol_cache;
+
+type DwarfContext = addr2line::Context<EndianRcSlice<RunTimeEndian>>;
+
+pub const DEFAULT_DEBUG_DIR: &str = "/usr/lib/debug";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElfSymbol {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+// file location and inlined frames of resolved addresses (for symbol cache)
+// NOTE: only addresses resolved by DWARF are cached, so cached and uncached results are same
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LineTable {
+    strings: Vec<String>,
+    // (function name, file location) string indexes, innermost inlined function first
+    frames: Vec<Vec<(u32, u32)>>,
+    // (address, frames index) sorted by address
+    addresses: Vec<(u64, u32)>,
+}
+
+impl LineTable {
+    fn find(&self, address: u64) -> Option<u32> {
+        self.addresses
+            .binary_search_by_key(&address, |(address, _)| *address)
+            .ok()
+            .map(|index| self.addresses[index].1)
+    }
+
+    fn frames(&self, address: u64) -> Vec<InlinedFrame> {
+        let frames_index = match self.find(address) {
+            Some(frames_index) => frames_index,
+            None => return Vec::new(),
+        };
+        self.frames[frames_index as usize]
+            .iter()
+            .map(|(function_name, file_location)| InlinedFrame {
+                function_name: self.strings[*function_name as usize].clone(),
+                file_location: self.strings[*file_location as usize].clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SymbolTable {
+    pub version: u32,
+    pub symbols: Vec<ElfSymbol>,
+    // (begin address, end address) of PT_LOAD segments
+    pub segments: Vec<(u64, u64)>,
+    pub line_table: Option<LineTable>,
+}
+
+impl SymbolTable {
+    pub const VERSION: u32 = 3;
+}
+
+#[derive(Default)]
+pub struct Symbolizer {
+    symbols: Vec<ElfSymbol>,
+    segments: Vec<(u64, u64)>,
+    dwarf: Option<DwarfContext>,
+    line_table: Option<LineTable>,
+}
+
+impl Symbolizer {
+    pub fn load(filepath: &Path, debug_dirs: &[PathBuf]) -> Result<Symbolizer> {
+        let data = fs::read(filepath)
+            .with_context(|| format!("Symbolizer::load(): Failed to open file {:?}", filepath))?;
+        let elf = object::File::parse(&*data).with_context(|| {
+            format!(
+                "Symbolizer::load(): Failed to parse ELF file {:?}",
+                filepath
+            )
+        })?;
+        let mut symbols = load_elf_symbols(&elf);
+        let segments = elf
+            .segments()
+            .map(|segment| (segment.address(), segment.address() + segment.size()))
+            .filter(|(begin, end)| begin < end)
+            .collect();
+        let mut dwarf = load_dwarf(&elf, filepath);
+
+        if dwarf.is_none() || symbols.is_empty() {
+            if let Some(debug_filepath) = find_debug_file(&elf, filepath, debug_dirs) {
+                log::info!("use separate debug info {:?}", debug_filepath);
+                let debug_data = fs::read(&debug_filepath).with_context(|| {
+                    format!(
+                        "Symbolizer::load(): Failed to open file {:?}",
+                        debug_filepath
+                    )
+                })?;
+                let debug_elf = object::File::parse(&*debug_data).with_context(|| {
+                    format!(
+                        "Symbolizer::load(): Failed to parse ELF file {:?}",
+                        debug_filepath
+                    )
+                })?;
+                symbols.append(&mut load_elf_symbols(&debug_elf));
+                if dwarf.is_none() {
+                    dwarf = load_dwarf(&debug_elf, &debug_filepath);
+                }
+            }
+        }
+
+        // NOTE: .symtab and .dynsym have same symbols, so keep the sized one at each address
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then(b.size.cmp(&a.size)));
+        symbols.dedup_by(|b, a| a.address == b.address);
+        Ok(Symbolizer {
+            symbols,
+            segments,
+            dwarf,
+            line_table: None,
+        })
+    }
+
+    // load from symbol cache if it has all addresses (link time address),
+    // otherwise load from ELF file and store the addresses to symbol cache
+    pub fn load_cached(
+        filepath: &Path,
+        debug_dirs: &[PathBuf],
+        cache_dir: Option<&Path>,
+        addresses: &[u64],
+    ) -> Result<Symbolizer> {
+        let cache_dir = match cache_dir {
+            Some(cache_dir) => cache_dir,
+            None => return Symbolizer::load(filepath, debug_dirs),
+        };
+        let key = symbol_cache::cache_key(filepath)?;
+        let mut cached_addresses: Vec<u64> = Vec::new();
+        if let Some(symbol_table) = symbol_cache::load(cache_dir, &key) {
+            let line_table = symbol_table.line_table.as_ref();
+            let missing_count = line_table
+                .map(|line_table| {
+                    addresses
+                        .iter()
+                        .filter(|&&address| line_table.find(address).is_none())
+                        .count()
+                })
+                .unwrap_or(addresses.len());
+            if missing_count == 0 {
+                log::info!(
+                    "use symbol cache {:?} for {:?}",
+                    symbol_cache::cache_filepath(cache_dir, &key),
+                    filepath
+                );
+                return Ok(Symbolizer::from_symbol_table(symbol_table));
+            }
+            log::info!(
+                "{} addresses are not in symbol cache of {:?}",
+                missing_count,
+                filepath
+            );
+            if let Some(line_table) = line_table {
+                cached_addresses = line_table
+                    .addresses
+                    .iter()
+                    .map(|(address, _)| *address)
+                    .collect();
+            }
+        }
+        let symbolizer = Symbolizer::load(filepath, debug_dirs)?;
+        // NOTE: symbol table without DWARF is cheap to load, so cache only with DWARF
+        if symbolizer.has_dwarf() {
+            cached_addresses.extend_from_slice(addresses);
+            let symbol_table = symbolizer.to_symbol_table(&cached_addresses);
+            if let Err(e) = symbol_cache::store(cache_dir, &key, &symbol_table) {
+                log::warn!("failed to store symbol cache of {:?}: {:?}", filepath, e);
+            }
+        }
+        Ok(symbolizer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // the nearest sized symbol which contains the address, or zero size symbol at the address
+    // NOTE: zero size symbols (e.g. labels in assembly) inside a function do not shadow the function
+    pub fn lookup(&self, address: u64) -> Option<&ElfSymbol> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        let mut exact_symbol = None;
+        for symbol in self.symbols[..index].iter().rev() {
+            if symbol.size == 0 {
+                if symbol.address == address {
+                    exact_symbol = Some(symbol);
+                }
+                continue;
+            }
+            if address < symbol.address + symbol.size {
+                return Some(symbol);
+            }
+            break;
+        }
+        exact_symbol
+    }
+
+    // link time address is in a PT_LOAD segment (true if the ELF has no segments e.g. relocatable object)
+    pub fn contains(&self, address: u64) -> bool {
+        self.segments.is_empty()
+            || self
+                .segments
+                .iter()
+                .any(|(begin, end)| *begin <= address && address < *end)
+    }
+
+    pub fn has_dwarf(&self) -> bool {
+        self.dwarf.is_some() || self.line_table.is_some()
+    }
+
+    fn dwarf_frames(dwarf: &DwarfContext, address: u64) -> Vec<InlinedFrame> {
+        let mut inlined_frames: Vec<InlinedFrame> = Vec::new();
+        let mut frames = match dwarf.find_frames(address).skip_all_loads() {
+            Ok(frames) => frames,
+            Err(_) => return inlined_frames,
+        };
+        while let Ok(Some(frame)) = frames.next() {
+            let function_name = match &frame.function {
+                Some(function) => function
+                    .raw_name()
+                    .map(|name| name.to_string())
+                    .unwrap_or_default(),
+                None => String::new(),
+            };
+            let file_location = match &frame.location {
+                Some(addr2line::Location {
+                    file: Some(file),
+                    line: Some(line),
+                    ..
+                }) => format!("{}:{}", file, line),
+                Some(addr2line::Location {
+                    file: Some(file), ..
+                }) => file.to_string(),
+                _ => String::new(),
+            };
+            inlined_frames.push(InlinedFrame {
+                function_name,
+                file_location,
+            });
+        }
+        inlined_frames
+    }
+
+    // return (function name, file location, inlined frames) of the function which contains address
+    fn find_frames(&self, address: u64) -> Option<(String, String, Vec<InlinedFrame>)> {
+        let mut inlined_frames = if let Some(dwarf) = &self.dwarf {
+            Symbolizer::dwarf_frames(dwarf, address)
+        } else {
+            self.line_table.as_ref()?.frames(address)
+        };
+        // NOTE: the last frame is the function which is not inlined
+        let frame = inlined_frames.pop()?;
+        Some((frame.function_name, frame.file_location, inlined_frames))
+    }
+
+    // addresses of function symbols (for prebuilding symbol cache)
+    pub fn symbol_addresses(&self) -> Vec<u64> {
+        self.symbols.iter().map(|symbol| symbol.address).collect()
+    }
+
+    // NOTE: frames of addresses are resolved by DWARF (same as uncached resolve)
+    pub fn to_symbol_table(&self, addresses: &[u64]) -> SymbolTable {
+        let line_table = self.dwarf.as_ref().map(|dwarf| {
+            let mut strings: Vec<String> = Vec::new();
+            let mut frames_list: Vec<Vec<(u32, u32)>> = Vec::new();
+            let mut string2index_map: HashMap<String, u32> = HashMap::new();
+            let mut frames2index_map: HashMap<Vec<(u32, u32)>, u32> = HashMap::new();
+            let mut intern = |string: String| -> u32 {
+                *string2index_map.entry(string.clone()).or_insert_with(|| {
+                    strings.push(string);
+                    (strings.len() - 1) as u32
+                })
+            };
+            let mut addresses = addresses.to_vec();
+            addresses.sort_unstable();
+            addresses.dedup();
+            let addresses = addresses
+                .into_iter()
+                .map(|address| {
+                    let frames = Symbolizer::dwarf_frames(dwarf, address)
+                        .into_iter()
+                        .map(|frame| (intern(frame.function_name), intern(frame.file_location)))
+                        .collect::<Vec<_>>();
+                    let index = *frames2index_map.entry(frames.clone()).or_insert_with(|| {
+                        frames_list.push(frames);
+                        (frames_list.len() - 1) as u32
+                    });
+                    (address, index)
+                })
+                .collect();
+            LineTable {
+                strings,
+                frames: frames_list,
+                addresses,
+            }
+        });
+        SymbolTable {
+            version: SymbolTable::VERSION,
+            symbols: self.symbols.clone(),
+            segments: self.segments.clone(),
+            line_table,
+        }
+    }
+
+    pub fn from_symbol_table(symbol_table: SymbolTable) -> Symbolizer {
+        Symbolizer {
+            symbols: symbol_table.symbols,
+            segments: symbol_table.segments,
+            dwarf: None,
+            line_table: symbol_table.line_table,
+        }
+    }
+
+    pub fn resolve(&self, address: u64) -> Option<AddressInformation> {
+        let symbol = self.lookup(address);
+        let (dwarf_function_name, file_location, inlined_frames) =
+            self.find_frames(address).unwrap_or_default();
+        let function_name = match symbol {
+            Some(symbol) => symbol.name.clone(),
+            None if !dwarf_function_name.is_empty() => dwarf_function_name,
+            None => return None,
+        };
+        Some(AddressInformation {
+            address,
+            file_location,
+            function_name,
+            inlined_frames,
+        })
+    }
+}
+
+fn load_elf_symbols(elf: &object::File) -> Vec<ElfSymbol> {
+    // NOTE: ARM thumb function symbols have the lowest bit set
+    let address_mask = if elf.architecture() == Architecture::Arm {
+        !1u64
+    } else {
+        !0u64
+    };
+
+    let mut symbols: Vec<ElfSymbol> = Vec::new();
+    for symbol in elf.symbols().chain(elf.dynamic_symbols()) {
+        if symbol.kind() != SymbolKind::Text || !symbol.is_definition() {
+            continue;
+        }
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        // NOTE: skip ARM/AArch64 mapping symbols e.g. '$a', '$t', '$x'
+        if name.is_empty() || name.starts_with('$') {
+            continue;
+        }
+        symbols.push(ElfSymbol {
+            address: symbol.address() & address_mask,
+            size: symbol.size(),
+            name: name.to_string(),
+        });
+    }
+    symbols
+}
+
+fn load_dwarf(elf: &object::File, filepath: &Path) -> Option<DwarfContext> {
+    match addr2line::Context::new(elf) {
+        Ok(_) if elf.section_by_name(".debug_info").is_none() => None,
+        Ok(context) => Some(context),
+        Err(e) => {
+            log::warn!("failed to load DWARF of {:?}: {}", filepath, e);
+            None
+        }
+    }
+}
+
+fn to_hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn read_build_id(elf: &object::File) -> Option<String> {
+    match elf.build_id() {
+        Ok(Some(build_id)) if !build_id.is_empty() => Some(to_hex_string(build_id)),
+        _ => None,
+    }
+}
+
+pub fn read_file_build_id(filepath: &Path) -> Result<Option<String>> {
+    let data = fs::read(filepath)
+        .with_context(|| format!("read_file_build_id(): Failed to open file {:?}", filepath))?;
+    let elf = object::File::parse(&*data).with_context(|| {
+        format!(
+            "read_file_build_id(): Failed to parse ELF file {:?}",
+            filepath
+        )
+    })?;
+    Ok(read_build_id(&elf))
+}
+
+// search order is same as gdb
+// 1. DEBUG_DIR/.build-id/xx/yyyy.debug
+// 2. BIN_DIR/DEBUGLINK, BIN_DIR/.debug/DEBUGLINK, DEBUG_DIR/BIN_DIR/DEBUGLINK
+fn find_debug_file(elf: &object::File, filepath: &Path, debug_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let build_id = read_build_id(elf);
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    if let Some(build_id) = &build_id {
+        if build_id.len() > 2 {
+            for debug_dir in debug_dirs {
+                candidates.push(
+                    debug_dir
+                        .join(".build-id")
+                        .join(&build_id[..2])
+                        .join(format!("{}.debug", &build_id[2..])),
+                );
+            }
+        }
+    }
+    if let Ok(Some((debuglink, _crc))) = elf.gnu_debuglink() {
+        let debuglink = String::from_utf8_lossy(debuglink).to_string();
+        let bin_dir = fs::canonicalize(filepath)
+            .ok()
+            .and_then(|path| path.parent().map(|dir| dir.to_path_buf()))
+            .unwrap_or_default();
+        candidates.push(bin_dir.join(&debuglink));
+        candidates.push(bin_dir.join(".debug").join(&debuglink));
+        for debug_dir in debug_dirs {
+            candidates.push(
+                debug_dir
+                    .join(bin_dir.strip_prefix("/").unwrap_or(&bin_dir))
+                    .join(&debuglink),
+            );
+            candidates.push(debug_dir.join(&debuglink));
+        }
+    }
+
+    for candidate in candidates {
+        if !candidate.is_file()
+            || fs::canonicalize(&candidate).ok() == fs::canonicalize(filepath).ok()
+        {
+            continue;
+        }
+        let debug_build_id = fs::read(&candidate).ok().and_then(|data| {
+            object::File::parse(&*data)
+                .ok()
+                .and_then(|debug_elf| read_build_id(&debug_elf))
+        });
+        if let (Some(build_id), Some(debug_build_id)) = (&build_id, &debug_build_id) {
+            if build_id != debug_build_id {
+                log::warn!(
+                    "build-id mismatch: {:?} is {} but debug file {:?} is {}, skipped",
+                    filepath,
+                    build_id,
+                    candidate,
+                    debug_build_id
+                );
+                continue;
+            }
+        }
+        return Some(candidate);
+    }
+    None
+}
+
+pub fn get_addr2info_map(
+    filepath: &Path,
+    debug_dirs: &[PathBuf],
+    cache_dir: Option<&Path>,
+    base_address: u64,
+    address_list: &[u64],
+) -> Result<HashMap<u64, AddressInformation>> {
+    // NOTE: load bias may be "negative" (wrapped) when the mapping is below p_vaddr
+    let addresses: Vec<u64> = address_list
+        .iter()
+        .map(|runtime_address| runtime_address.wrapping_sub(base_address))
+        .collect();
+    let symbolizer = Symbolizer::load_cached(filepath, debug_dirs, cache_dir, &addresses)?;
+    if symbolizer.is_empty() {
+        log::warn!(
+            "no function symbols found in {:?}, maybe stripped",
+            filepath
+        );
+    }
+    if !symbolizer.has_dwarf() {
+        log::warn!("no DWARF debug info found in {:?}", filepath);
+    }
+    let mut addr2info_map: HashMap<u64, AddressInformation> = HashMap::new();
+    for (runtime_address, &address) in address_list.iter().zip(&addresses) {
+        if !symbolizer.contains(address) {
+            continue;
+        }
+        if let Some(mut address_information) = symbolizer.resolve(address) {
+            address_information.address = *runtime_address;
+            addr2info_map.insert(*runtime_address, address_information);
+        }
+    }
+    Ok(addr2info_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbolizer(symbols: &[(u64, u64, &str)]) -> Symbolizer {
+        Symbolizer {
+            symbols: symbols
+                .iter()
+                .map(|&(address, size, name)| ElfSymbol {
+                    address,
+                    size,
+                    name: name.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn lookup_name(symbolizer: &Symbolizer, address: u64) -> Option<&str> {
+        symbolizer
+            .lookup(address)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    #[test]
+    fn lookup_sized_symbols() {
+        let symbolizer = symbolizer(&[(0x1000, 0x10, "foo"), (0x1020, 0x20, "bar")]);
+        assert_eq!(lookup_name(&symbolizer, 0xfff), None);
+        assert_eq!(lookup_name(&symbolizer, 0x1000), Some("foo"));
+        assert_eq!(lookup_name(&symbolizer, 0x100f), Some("foo"));
+        // gap between functions
+        assert_eq!(lookup_name(&symbolizer, 0x1010), None);
+        assert_eq!(lookup_name(&symbolizer, 0x103f), Some("bar"));
+        assert_eq!(lookup_name(&symbolizer, 0x1040), None);
+    }
+
+    #[test]
+    fn lookup_zero_size_symbol_in_function() {
+        let symbolizer = symbolizer(&[
+            (0x1000, 0x40, "foo"),
+            (0x1010, 0, "foo_label"),
+            (0x1020, 0, "foo_label2"),
+            (0x1080, 0, "_start"),
+        ]);
+        assert_eq!(lookup_name(&symbolizer, 0x1018), Some("foo"));
+        assert_eq!(lookup_name(&symbolizer, 0x1024), Some("foo"));
+        // NOTE: sized function is preferred to zero size symbol at the same address
+        assert_eq!(lookup_name(&symbolizer, 0x1010), Some("foo"));
+        // zero size symbol matches only its address out of functions
+        assert_eq!(lookup_name(&symbolizer, 0x1080), Some("_start"));
+        assert_eq!(lookup_name(&symbolizer, 0x1084), None);
+        assert_eq!(lookup_name(&symbolizer, 0x1040), None);
+    }
+}
@@ -3,7 +3,6 @@ import json
 import re
 import sys
 import argparse
-from operator import itemgetter
 
 parser = argparse.ArgumentParser(
     formatter_class=argparse.ArgumentDefaultsHelpFormatter)
@@ -34,17 +33,26 @@ args, extra_args = parser.parse_known_args()
 def main():
     json_file = open(args.input, 'r')
     json_root = json.load(json_file)
+    # NOTE: JSON object format (default of x2trace) has events in 'traceEvents'
+    trace_events = json_root['traceEvents'] if isinstance(
+        json_root, dict) else json_root
 
     func_map = {}
+    metadata_index_list = []
     index = 0
-    base_timestamp = 0
-    for v in json_root:
+    base_timestamp = None
+    for v in trace_events:
+        # metadata events (e.g. process_name) are always kept
+        if v['ph'] == 'M':
+            metadata_index_list.append(index)
+            index += 1
+            continue
         name = v['name']
         if name not in func_map:
             func_map[name] = {'stack': [], 'list': []}
         ph = v['ph']
         ts = v['ts']
-        if index == 0:
+        if base_timestamp is None:
             base_timestamp = ts
         stack = func_map[name]['stack']
         if ph == 'B':
@@ -68,13 +76,14 @@ def main():
             return 1
         index += 1
 
+    base_timestamp = base_timestamp or 0
     interest_begin_ts = args.begin_timestamp * 1000.0 + base_timestamp
     interest_end_ts = args.end_timestamp * 1000.0 + base_timestamp
 
     include_pattern = re.compile(args.include)
     exclude_pattern = re.compile(args.exclude)
 
-    valid_index_list = []
+    valid_index_list = list(metadata_index_list)
     for func_name in func_map.keys():
         result = include_pattern.match(func_name)
         if result is None:
@@ -91,11 +100,12 @@ def main():
 
     valid_index_list.sort()
 
-    filtered_json_data = []
-    if len(valid_index_list) != 0:
-        filtered_json_data = itemgetter(*valid_index_list)(json_root)
-
-    print(json.dumps(list(filtered_json_data)))
+    filtered_json_data = [trace_events[i] for i in valid_index_list]
+    if isinstance(json_root, dict):
+        json_root['traceEvents'] = filtered_json_data
+        print(json.dumps(json_root))
+    else:
+        print(json.dumps(filtered_json_data))
 
 
 if __name__ == "__main__":
@@ -43,12 +43,17 @@ def find_outliers(data, m=100.):
 def main():
     json_file = open(args.input, 'r')
     json_root = json.load(json_file)
+    # NOTE: JSON object format (default of x2trace) has events in 'traceEvents'
+    trace_events = json_root['traceEvents'] if isinstance(
+        json_root, dict) else json_root
 
     th = args.th
     call_count_th = args.call
 
     func_map = {}
-    for v in json_root:
+    for v in trace_events:
+        if v['ph'] == 'M':
+            continue
         name = v['name']
         if name not in func_map:
             func_map[name] = {'stack': [], 'durations': []}